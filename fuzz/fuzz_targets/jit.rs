@@ -0,0 +1,25 @@
+// Checks that the code generated by the JIT behaves like the interpreter on
+// the optimized instructions.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use zombie::{compile, generate, interpret, optimize, parser};
+use zombie::options::Options;
+
+fuzz_target!(|data: &[u8]| {
+    let gen_opts = generate::GenOptions {
+        reads: true,
+        ..generate::GenOptions::default()
+    };
+    let code = generate::program(&mut generate::ByteChoices::new(data), &gen_opts);
+
+    let opts = Options::default();
+    let insts = optimize::lin_optimize(parser::parse(&code, &opts).unwrap(), &opts);
+
+    let mut output = Vec::new();
+    interpret::run(&insts, &opts, &mut &data[..], &mut output);
+    let mut jit_output = Vec::new();
+    compile::compile_and_run(&insts, &opts, &mut &data[..], &mut jit_output).unwrap();
+
+    assert_eq!(output, jit_output, "output differs for {}", code);
+});
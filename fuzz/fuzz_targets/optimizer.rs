@@ -0,0 +1,34 @@
+// Checks that linearizing loops does not change what a program does: the
+// parsed and the optimized instructions have to produce the same output and
+// leave the same tape behind in the interpreter.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use zombie::{generate, interpret, optimize, parser};
+use zombie::options::{CellSize, Options};
+
+fuzz_target!(|data: &[u8]| {
+    let gen_opts = generate::GenOptions {
+        reads: true,
+        ..generate::GenOptions::default()
+    };
+    let code = generate::program(&mut generate::ByteChoices::new(data), &gen_opts);
+
+    for cell_size in [CellSize::Bits(8), CellSize::Bits(16), CellSize::Modular(251)].iter() {
+        let opts = Options {
+            cell_size: cell_size.clone(),
+            memory_size: 64,
+            ..Options::default()
+        };
+        let insts = parser::parse(&code, &opts).unwrap();
+        let mut output = Vec::new();
+        let tape = interpret::run_to_tape(&insts, &opts, &mut &data[..], &mut output);
+
+        let opt_insts = optimize::lin_optimize(insts, &opts);
+        let mut opt_output = Vec::new();
+        let opt_tape = interpret::run_to_tape(&opt_insts, &opts, &mut &data[..], &mut opt_output);
+
+        assert_eq!(output, opt_output, "output differs for {} ({:?})", code, cell_size);
+        assert_eq!(tape, opt_tape, "tape differs for {} ({:?})", code, cell_size);
+    }
+});
@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use super::ir::{self, Instruction, ConstVisitor};
+use super::options::{CellLayout, CellSize, Options};
+
+// the values of the cells relative to the pointer, `None` if unknown
+#[derive(Clone)]
+struct KnownTape {
+    cells: BTreeMap<i64, Option<i128>>,
+    // the value of the cells not in `cells`
+    rest: Option<i128>,
+}
+
+impl KnownTape {
+    fn unknown() -> Self {
+        KnownTape { cells: BTreeMap::new(), rest: None }
+    }
+
+    fn get(&self, offset: i64) -> Option<i128> {
+        self.cells.get(&offset).copied().unwrap_or(self.rest)
+    }
+
+    fn set(&mut self, offset: i64, value: Option<i128>) {
+        self.cells.insert(offset, value);
+    }
+
+    fn move_ptr(&mut self, offset: i64) {
+        self.cells = self.cells.iter().map(|(&off, &value)| (off - offset, value)).collect();
+    }
+}
+
+/// The values of the cells each instruction accesses, where they are known
+/// before it runs. The tape starts out zeroed; after a loop only its cell is
+/// known to be 0 and in a loop body nothing is known.
+pub struct KnownCells {
+    cell_size: CellSize,
+    tape: KnownTape,
+    // offsets and values, for the instructions with known cells
+    known: HashMap<*const Instruction, Vec<(i64, i128)>>,
+}
+
+impl KnownCells {
+    pub fn analyze(instrs: &[Instruction], opts: &Options) -> Self {
+        let mut analysis = KnownCells {
+            cell_size: opts.cell_size.clone(),
+            tape: KnownTape { cells: BTreeMap::new(), rest: Some(0) },
+            known: HashMap::new(),
+        };
+        analysis.visit_instructions(instrs);
+        analysis
+    }
+
+    /// The known cells an instruction accesses, with their offsets
+    pub fn get(&self, inst: &Instruction) -> &[(i64, i128)] {
+        self.known.get(&(inst as *const _)).map_or(&[], Vec::as_slice)
+    }
+
+    /// Brings a value into the range of the cells
+    fn reduce(&self, value: i128) -> i128 {
+        match self.cell_size {
+            CellSize::Bits(n) if n < 64 => value.rem_euclid(1 << n),
+            CellSize::Modular(n) if n > 0 => value.rem_euclid(n as i128),
+            CellSize::Bits(_) | CellSize::Modular(_) | CellSize::Int => value as i64 as i128,
+        }
+    }
+
+    fn add(&mut self, offset: i64, value: Option<i128>) {
+        let sum = match (self.tape.get(offset), value) {
+            (Some(cell), Some(value)) => Some(self.reduce(cell + value)),
+            (cell, Some(0)) => cell,
+            _ => None,
+        };
+        self.tape.set(offset, sum);
+    }
+}
+
+impl ir::ConstVisitor for KnownCells {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        let known: Vec<(i64, i128)> = inst.accessed_offsets().into_iter()
+            .filter_map(|offset| self.tape.get(offset).map(|value| (offset, value)))
+            .collect();
+        if !known.is_empty() {
+            self.known.insert(inst as *const _, known);
+        }
+    }
+
+    fn visit_add(&mut self, add: &Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            self.add(*offset, Some(*value as i128));
+        }
+    }
+
+    fn visit_set(&mut self, set: &Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            let value = self.reduce(*value as i128);
+            self.tape.set(*offset, Some(value));
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset, factors, .. } = l {
+            let source = self.tape.get(*offset);
+            for (&off, &factor) in factors {
+                if off != 0 {
+                    self.add(offset + off, source.map(|source| source * factor as i128));
+                }
+            }
+            self.tape.set(*offset, Some(0));
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            self.tape.move_ptr(*offset);
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(body, _) = l {
+            // a loop on a zero cell is skipped
+            if self.tape.get(0) == Some(0) {
+                return;
+            }
+            self.tape = KnownTape::unknown();
+            self.visit_instructions(body);
+            self.tape = KnownTape::unknown();
+            self.tape.set(0, Some(0));
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            self.tape.set(*offset, None);
+        }
+    }
+}
+
+/// The known values as comments, e.g. `// @0 = 3, @2 = 0`
+impl ir::Annotations for KnownCells {
+    fn comment(&self, inst: &Instruction) -> Option<String> {
+        let known = self.get(inst);
+        if known.is_empty() {
+            return None;
+        }
+        let cells: Vec<String> = known.iter().map(|(offset, value)| format!("@{} = {}", offset, value)).collect();
+        Some(cells.join(", "))
+    }
+}
+
+/// A range of cells relative to the starting cell, both ends included. An end
+/// is `None` where there is no bound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl Bounds {
+    pub fn at(cell: i64) -> Self {
+        Bounds { min: Some(cell), max: Some(cell) }
+    }
+
+    /// The bounds moved by `offset` cells
+    pub fn shift(self, offset: i64) -> Self {
+        Bounds {
+            min: self.min.and_then(|min| min.checked_add(offset)),
+            max: self.max.and_then(|max| max.checked_add(offset)),
+        }
+    }
+
+    /// The smallest bounds containing both
+    pub fn join(self, other: Bounds) -> Self {
+        Bounds {
+            min: self.min.and_then(|min| other.min.map(|other| min.min(other))),
+            max: self.max.and_then(|max| other.max.map(|other| max.max(other))),
+        }
+    }
+
+    /// The cells in both
+    pub fn intersect(self, other: Bounds) -> Self {
+        let bound = |a: Option<i64>, b: Option<i64>, pick: fn(i64, i64) -> i64| match (a, b) {
+            (Some(a), Some(b)) => Some(pick(a, b)),
+            (a, b) => a.or(b),
+        };
+        Bounds { min: bound(self.min, other.min, i64::max), max: bound(self.max, other.max, i64::min) }
+    }
+
+    pub fn contains(&self, other: &Bounds) -> bool {
+        let above = match (self.min, other.min) {
+            (None, _) => true,
+            (Some(min), Some(other)) => min <= other,
+            (Some(_), None) => false,
+        };
+        let below = match (self.max, other.max) {
+            (None, _) => true,
+            (Some(max), Some(other)) => max >= other,
+            (Some(_), None) => false,
+        };
+        above && below
+    }
+
+    /// The number of cells, `None` if unbounded
+    pub fn width(&self) -> Option<usize> {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => Some((max as i128 - min as i128 + 1) as usize),
+            _ => None,
+        }
+    }
+
+    /// Where a loop entered with the pointer in these bounds can be at its
+    /// head, if one iteration moves the pointer by `step`. The pointer can
+    /// go on in each direction the loop moves it.
+    fn loop_head(self, step: Bounds) -> Self {
+        Bounds {
+            min: if step.min.is_none_or(|min| min < 0) { None } else { self.min },
+            max: if step.max.is_none_or(|max| max > 0) { None } else { self.max },
+        }
+    }
+}
+
+/// Written like a rust range, e.g. `-2..=5` or `0..` without an upper bound
+impl fmt::Display for Bounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(min) = self.min {
+            write!(f, "{}", min)?;
+        }
+        match self.max {
+            Some(max) => write!(f, "..={}", max),
+            None => write!(f, ".."),
+        }
+    }
+}
+
+/// How far the pointer can end up from where it was before the instructions
+fn movement(instrs: &[Instruction]) -> Bounds {
+    instrs.iter().fold(Bounds::at(0), |ptr, inst| match inst {
+        Instruction::MovePtr(offset) => ptr.shift(*offset),
+        Instruction::Loop(body, _) => ptr.loop_head(movement(body)),
+        _ => ptr,
+    })
+}
+
+/// Bounds on the pointer before each instruction and on the cells the program
+/// may touch, found by abstract interpretation of the instructions. Loops
+/// whose body can end with the pointer elsewhere than it started are
+/// unbounded and let the pointer go on in that direction.
+pub struct PointerRanges {
+    // the bounds of the pointer before each instruction
+    pointer: HashMap<*const Instruction, Bounds>,
+    // loops with a non-zero net movement of the pointer
+    unbounded: HashSet<*const Instruction>,
+    // the pointer positions and the cells accessed so far
+    cells: Bounds,
+    ptr: Bounds,
+}
+
+impl PointerRanges {
+    pub fn analyze(instrs: &[Instruction]) -> Self {
+        let mut analysis = PointerRanges {
+            pointer: HashMap::new(),
+            unbounded: HashSet::new(),
+            cells: Bounds::at(0),
+            ptr: Bounds::at(0),
+        };
+        analysis.visit_instructions(instrs);
+        analysis.cells = analysis.cells.join(analysis.ptr);
+        analysis
+    }
+
+    /// The cells the program may access or move the pointer to
+    pub fn cells(&self) -> Bounds {
+        self.cells
+    }
+
+    /// The bounds of the pointer before an instruction
+    pub fn pointer(&self, inst: &Instruction) -> Option<Bounds> {
+        self.pointer.get(&(inst as *const _)).copied()
+    }
+
+    pub fn is_unbounded(&self, inst: &Instruction) -> bool {
+        self.unbounded.contains(&(inst as *const _))
+    }
+
+    /// The number of cells the tape needs so the program never leaves it,
+    /// `None` if the cells are unbounded. The pointer of the trusting layout
+    /// starts in the middle of the tape, the other layouts start at cell 0
+    /// and wrap negative indices to the end.
+    pub fn memory_size(&self, layout: &CellLayout) -> Option<usize> {
+        let (min, max) = (self.cells.min?, self.cells.max?);
+        match layout {
+            CellLayout::Trusting => Some(2 * (-min).max(max) as usize + 1),
+            CellLayout::Wrapping | CellLayout::Unbounded => self.cells.width(),
+        }
+    }
+}
+
+impl ir::ConstVisitor for PointerRanges {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        self.pointer.insert(inst as *const _, self.ptr);
+        self.cells = self.cells.join(self.ptr);
+        for offset in inst.accessed_offsets() {
+            self.cells = self.cells.join(self.ptr.shift(offset));
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            self.ptr = self.ptr.shift(*offset);
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(body, _) = l {
+            let step = movement(body);
+            if step != Bounds::at(0) {
+                self.unbounded.insert(l as *const _);
+            }
+            // the loop is left at its head
+            let head = self.ptr.loop_head(step);
+            self.ptr = head;
+            self.visit_instructions(body);
+            self.ptr = head;
+        }
+    }
+}
+
+/// The bounds of the pointer at loops, e.g. `// ptr 0..` for a loop moving
+/// it to the right
+impl ir::Annotations for PointerRanges {
+    fn comment(&self, inst: &Instruction) -> Option<String> {
+        match inst {
+            Instruction::Loop(_, _) if self.is_unbounded(inst) => Some(format!("ptr {}, unbounded", self.pointer(inst)?)),
+            Instruction::Loop(_, _) | Instruction::LinearLoop{ .. } => Some(format!("ptr {}", self.pointer(inst)?)),
+            _ => None,
+        }
+    }
+}
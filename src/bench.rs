@@ -0,0 +1,172 @@
+use super::golden::Backend;
+use super::ir::Instruction;
+use super::options::Options;
+use super::{compile, interpret, optimize, parser};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Summary of the times measured for one phase, in milliseconds
+pub struct Stats {
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub stddev: f64,
+}
+
+impl Stats {
+    fn from_times(times: &[Duration]) -> Self {
+        let ms: Vec<f64> = times.iter().map(|t| t.as_secs_f64() * 1000.0).collect();
+        let n = ms.len().max(1) as f64;
+        let mean = ms.iter().sum::<f64>() / n;
+        let variance = ms.iter().map(|t| (t - mean) * (t - mean)).sum::<f64>() / n;
+        Stats {
+            mean,
+            min: ms.iter().copied().fold(f64::INFINITY, f64::min),
+            max: ms.iter().copied().fold(0.0, f64::max),
+            stddev: variance.sqrt(),
+        }
+    }
+}
+
+/// The optimizer passes run before the program is compiled or interpreted.
+/// These are not the levels of `-O`, which pick how the C code is generated.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Passes {
+    // the instructions as parsed
+    None,
+    // with linearized loops, as the program is usually run
+    Linear,
+}
+
+impl Passes {
+    pub fn name(self) -> &'static str {
+        match self {
+            Passes::None => "none",
+            Passes::Linear => "linear",
+        }
+    }
+}
+
+/// Results of benchmarking one program with one backend and optimizer passes
+pub struct Measurement {
+    pub program: String,
+    pub backend: Backend,
+    pub passes: Passes,
+    pub runs: usize,
+    pub parse: Stats,
+    pub optimize: Stats,
+    // always 0 for the interpreter
+    pub codegen: Stats,
+    pub execute: Stats,
+    pub total: Stats,
+}
+
+fn backend_name(backend: Backend) -> &'static str {
+    match backend {
+        Backend::Interpreter => "interpret",
+        Backend::Jit => "jit",
+    }
+}
+
+/// Parses, optimizes, compiles and runs the program `runs` times, discarding
+/// its output
+pub fn measure(name: &str, code: &str, input: &[u8], opts: &Options,
+               backend: Backend, passes: Passes, runs: usize) -> Result<Measurement, String> {
+    let mut times: [Vec<Duration>; 5] = Default::default();
+    for _ in 0..runs {
+        let start = Instant::now();
+        let insts = parser::parse(code, opts).map_err(|msg| format!("error parsing {}: {}", name, msg))?;
+        let parsed = Instant::now();
+
+        let insts = optimize(insts, opts, passes);
+        let optimized = Instant::now();
+
+        let executed;
+        let generated;
+        match backend {
+            Backend::Interpreter => {
+                generated = optimized;
+                interpret::run(&insts, opts, &mut &input[..], &mut io::sink());
+                executed = Instant::now();
+            },
+            Backend::Jit => {
+                compile::supports(opts).map_err(|err| format!("cannot compile {}: {}", name, err))?;
+                let program = compile::compile(&insts, opts);
+                generated = Instant::now();
+                program.execute(opts, &mut &input[..], &mut io::sink()).map_err(|err| format!("{}: {}", name, err))?;
+                executed = Instant::now();
+            },
+        }
+
+        times[0].push(parsed - start);
+        times[1].push(optimized - parsed);
+        times[2].push(generated - optimized);
+        times[3].push(executed - generated);
+        times[4].push(executed - start);
+    }
+
+    let [parse, optimize, codegen, execute, total] = times;
+    Ok(Measurement {
+        program: name.to_string(),
+        backend,
+        passes,
+        runs,
+        parse: Stats::from_times(&parse),
+        optimize: Stats::from_times(&optimize),
+        codegen: Stats::from_times(&codegen),
+        execute: Stats::from_times(&execute),
+        total: Stats::from_times(&total),
+    })
+}
+
+fn optimize(insts: Vec<Instruction>, opts: &Options, passes: Passes) -> Vec<Instruction> {
+    match passes {
+        Passes::None => insts,
+        Passes::Linear => optimize::lin_optimize(insts, opts),
+    }
+}
+
+fn phases(m: &Measurement) -> [(&'static str, &Stats); 5] {
+    [("parse", &m.parse), ("optimize", &m.optimize), ("codegen", &m.codegen),
+     ("execute", &m.execute), ("total", &m.total)]
+}
+
+/// Prints one table per measurement, times in milliseconds
+pub fn print_report(measurements: &[Measurement]) {
+    for m in measurements {
+        println!("{} ({}, passes {}, {} runs)", m.program, backend_name(m.backend), m.passes.name(), m.runs);
+        println!("    {:<10} {:>10} {:>10} {:>10} {:>10}", "[ms]", "mean", "min", "max", "stddev");
+        for (phase, stats) in phases(m).iter() {
+            println!("    {:<10} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+                     phase, stats.mean, stats.min, stats.max, stats.stddev);
+        }
+        println!();
+    }
+}
+
+/// The measurements as a JSON array, times in milliseconds
+pub fn to_json(measurements: &[Measurement]) -> String {
+    let entries: Vec<String> = measurements.iter().map(|m| {
+        let phases: Vec<String> = phases(m).iter().map(|(phase, stats)| {
+            format!("\"{}\":{{\"mean\":{:.6},\"min\":{:.6},\"max\":{:.6},\"stddev\":{:.6}}}",
+                    phase, stats.mean, stats.min, stats.max, stats.stddev)
+        }).collect();
+        format!("{{\"program\":{},\"backend\":\"{}\",\"passes\":\"{}\",\"runs\":{},{}}}",
+                json_string(&m.program), backend_name(m.backend), m.passes.name(), m.runs, phases.join(","))
+    }).collect();
+    format!("[{}]", entries.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
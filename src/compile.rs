@@ -1,9 +1,15 @@
-use std::{i64, i32};
 use super::{ir, optimize};
+use std::collections::HashMap;
+use std::fmt;
 use std::io::{Write, Read};
 use std::mem;
+use std::time::Instant;
 use super::ir::{ConstVisitor, Instruction};
-use super::options::{Options, CellLayout, CellSize};
+use super::options::{Options, CellLayout, CellSize, EofMode};
+use super::interpret;
+use super::trans::BackendError;
+use super::analysis::{Bounds, PointerRanges};
+use super::profile::{self, Profile};
 //use mmap::{MemoryMap, MapOption};
 
 /*#[cfg(target_os = "windows")]
@@ -18,70 +24,311 @@ pub enum Storation {
     Stack(i64),
 }
 
-pub fn compile_cfg<'a>(cfg: Vec<optimize::DfInstr<'a>>) -> Box<fn (*mut u8) -> ()> {
+pub fn compile_cfg<'a>(_cfg: Vec<optimize::DfInstr<'a>>) -> Box<fn (*mut u8) -> ()> {
     Box::new(|_| {})
 }
 
 
-pub fn compile_and_run<'a>(instrs: &Vec<ir::Instruction>, opts: &'a Options) -> Vec<u8> {
-    let mut cg = CodeGenerator::<'a>::create(opts);
+// cells allocated beyond each end of the tape at most. The pointer is kept on
+// the tape, the cells at an offset from it can reach into them without checks.
+const MAX_PADDING: usize = 1 << 16;
+
+// execution and iteration counts of the profiled instructions, boxed so the
+// generated code can refer to them by address
+type Counters = HashMap<*const Instruction, Box<[u64; 2]>>;
+
+/// The generated code stopped the program because its pointer left the tape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfBounds;
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the pointer left the tape")
+    }
+}
+
+impl std::error::Error for OutOfBounds {}
+
+/// How the generated code keeps the pointer of a program on the trusting tape,
+/// chosen by the pointer range analysis
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TapeChecks {
+    // the program provably stays on the tape, it runs without checks
+    Trusting,
+    // the pointer is checked where the program may leave the tape
+    Checked,
+}
+
+/// The checks a program needs to stay on the tape of the options
+pub fn tape_checks(instrs: &[ir::Instruction], opts: &Options) -> TapeChecks {
+    checks_for(&PointerRanges::analyze(instrs), opts)
+}
+
+fn checks_for(ranges: &PointerRanges, opts: &Options) -> TapeChecks {
+    match ranges.cells().width() {
+        Some(width) if width <= opts.memory_size => TapeChecks::Trusting,
+        _ => TapeChecks::Checked,
+    }
+}
+
+/// Checks whether the JIT can run programs with the options. It generates
+/// code for 8 bit cells only, and only keeps the pointer on a trusting tape,
+/// it stops a program leaving it rather than wrapping it around or growing
+/// the tape.
+pub fn supports(opts: &Options) -> Result<(), BackendError> {
+    if opts.cell_size != CellSize::Bits(8) {
+        return Err(BackendError::UnsupportedCellSize(opts.cell_size.clone()));
+    }
+    if opts.cell_layout != CellLayout::Trusting {
+        return Err(BackendError::UnsupportedCellLayout(opts.cell_layout.clone()));
+    }
+    // the offsets are at most the memory size, the code addresses cells with
+    // 32 bit displacements
+    if opts.memory_size == 0 || opts.memory_size > i32::MAX as usize {
+        return Err(BackendError::UnsupportedMemorySize(opts.memory_size));
+    }
+    Ok(())
+}
+
+pub fn compile_and_run(instrs: &[ir::Instruction], opts: &Options, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), OutOfBounds> {
+    compile(instrs, opts).execute(opts, input, output)
+}
+
+/// Runs the program with counters compiled in for all loops and writes
+pub fn compile_and_profile(instrs: &[ir::Instruction], opts: &Options, input: &mut dyn Read, output: &mut dyn Write) -> Profile {
+    let mut cg = CodeGenerator::create(opts, instrs);
+    cg.counters = Some(HashMap::new());
+    let start = Instant::now();
+    let program = generate(cg, instrs);
+    // the counts up to where the program was stopped are still useful
+    if let Err(err) = program.execute(opts, input, output) {
+        eprintln!("{}", err);
+    }
+    let counters = program.counters.unwrap_or_default();
+    let total_time = start.elapsed();
+
+    let counters = counters.into_iter()
+        .map(|(inst, counts)| (inst, profile::Counter {
+            executions: counts[0],
+            iterations: counts[1],
+            ..profile::Counter::default()
+        }))
+        .collect();
+    Profile {
+        counters,
+        total_time,
+        timed: false,
+    }
+}
+
+/// Machine code generated for a program
+pub struct CompiledProgram {
+    buffer: dynasmrt::ExecutableBuffer,
+    entry: dynasmrt::AssemblyOffset,
+    counters: Option<Counters>,
+    // index of the starting cell in memory
+    origin: usize,
+    padding: usize,
+}
+
+/// Generates machine code for the program without running it, the options are
+/// checked with `supports`
+pub fn compile(instrs: &[ir::Instruction], opts: &Options) -> CompiledProgram {
+    generate(CodeGenerator::create(opts, instrs), instrs)
+}
+
+fn generate(mut cg: CodeGenerator, instrs: &[ir::Instruction]) -> CompiledProgram {
     cg.initialize();
 
     let entry = cg.buffer.offset();
 
     cg.visit_instructions(instrs);
-    let _committed = cg.buffer.commit();
+    // the labels of the bounds checks are defined at the end
     cg.finalize();
-    let buf = cg.buffer.finalize().unwrap();
+    let _committed = cg.buffer.commit();
+    let buffer = cg.buffer.finalize().unwrap();
 
     //let ret = buf.to_vec();
     //println!("{:02x?}", ret);
 
-    let function: extern "C" fn(memory: *mut u8) -> bool = unsafe {
-        //mem::transmute(cg.get_callable())
-        mem::transmute(buf.ptr(entry))
-    };
+    CompiledProgram {
+        buffer,
+        entry,
+        counters: cg.counters,
+        origin: cg.origin,
+        padding: cg.padding,
+    }
+}
 
-    unsafe {
-        let layout = match opts.cell_layout {
-            CellLayout::Trusting => std::alloc::Layout::array::<u8>(opts.memory_size).unwrap(),
-            CellLayout::Wrapping => std::alloc::Layout::array::<u8>(opts.memory_size).unwrap(),
-            CellLayout::Unbounded => std::alloc::Layout::array::<u8>(opts.memory_size).unwrap(),
+impl CompiledProgram {
+    /// Runs the generated code on a fresh tape
+    pub fn execute(&self, opts: &Options, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), OutOfBounds> {
+        let function: extern "C" fn(memory: *mut u8, rt: *mut Runtime) -> bool = unsafe {
+            //mem::transmute(cg.get_callable())
+            mem::transmute(self.buffer.ptr(self.entry))
         };
-        let mem = std::alloc::alloc_zeroed(layout);
-        match opts.cell_layout {
-            CellLayout::Trusting => { function(mem.offset(0x8000)); },
-            _ => { function(mem); }
+
+        // the starting cell is not on an empty tape, and allocating no
+        // memory is undefined
+        if opts.memory_size == 0 {
+            return Err(OutOfBounds);
+        }
+        unsafe {
+            let layout = std::alloc::Layout::array::<u8>(opts.memory_size + 2 * self.padding).unwrap();
+            let allocated = std::alloc::alloc_zeroed(layout);
+            let mem = allocated.add(self.padding);
+            let origin = self.origin;
+            let mut rt = Runtime {
+                start: mem,
+                end: mem.add(opts.memory_size),
+                memory: mem,
+                memory_size: opts.memory_size,
+                origin,
+                input,
+                output,
+                eof_mode: opts.eof_mode,
+            };
+            let in_bounds = function(mem.add(origin), &mut rt);
+            std::alloc::dealloc(allocated, layout);
+            if in_bounds { Ok(()) } else { Err(OutOfBounds) }
         }
-        std::alloc::dealloc(mem, layout);
     }
+}
 
-    //cg.into_vec()
-    //ret
-    vec![]
+/// Where the starting cell goes in memory. The analysis puts the cells the
+/// program can reach on the tape where it can, the pointer starts in the
+/// middle otherwise.
+fn origin(opts: &Options, cells: Bounds) -> usize {
+    let size = opts.memory_size as i64;
+    let origin = match (cells.min, cells.max) {
+        (Some(min), _) if -min < size => -min,
+        (None, Some(max)) if max < size => size - 1 - max,
+        _ => size / 2,
+    };
+    origin as usize
+}
+
+/// The largest distance of an accessed cell from the pointer
+fn max_offset(instrs: &[Instruction]) -> u64 {
+    instrs.iter().map(|inst| {
+        let offset = inst.accessed_offsets().iter().map(|offset| offset.unsigned_abs()).max().unwrap_or(0);
+        match inst {
+            Instruction::Loop(body, _) => offset.max(max_offset(body)),
+            _ => offset,
+        }
+    }).max().unwrap_or(0)
+}
+
+/// State the generated code passes to its callbacks (kept in rsi)
+#[repr(C)]
+pub struct Runtime<'a> {
+    // the bounds of the tape, the generated code checks the pointer against
+    // them at the offsets 0 and 8
+    start: *const u8,
+    end: *const u8,
+    memory: *mut u8,
+    memory_size: usize,
+    // index of the starting cell in memory
+    origin: usize,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    eof_mode: EofMode,
 }
 
 pub struct CodeGenerator<'a> {
     pub buffer: dynasmrt::x64::Assembler,
-    opts: &'a Options
+    #[allow(dead_code)]
+    opts: &'a Options,
+    counters: Option<Counters>,
+    // bounds checks are only generated for checked programs, where the
+    // pointer ranges do not keep the accessed cells on the tape
+    checks: TapeChecks,
+    ranges: PointerRanges,
+    origin: usize,
+    // the cells on the tape, relative to the starting cell
+    tape: Bounds,
+    // the largest offset of the program if the memory on both sides of the
+    // tape can cover it, the pointer itself always stays on the tape
+    padding: usize,
+    // returns false from the generated code
+    out_of_bounds: dynasmrt::DynamicLabel,
 }
 
 impl<'a> CodeGenerator<'a> {
-    pub fn create(opts: &'a Options) -> Self {
+    pub fn create(opts: &'a Options, instrs: &[Instruction]) -> Self {
+        let ranges = PointerRanges::analyze(instrs);
+        let checks = checks_for(&ranges, opts);
+        let origin = origin(opts, ranges.cells());
+        let padding = match max_offset(instrs) as usize {
+            offset if offset <= MAX_PADDING => offset,
+            _ => 0,
+        };
+        let mut buffer = dynasmrt::x64::Assembler::new().unwrap();
+        let out_of_bounds = buffer.new_dynamic_label();
         CodeGenerator {
-            buffer: dynasmrt::x64::Assembler::new().unwrap(),
-            opts: opts
+            buffer,
+            opts,
+            counters: None,
+            checks,
+            ranges,
+            origin,
+            tape: Bounds { min: Some(-(origin as i64)), max: Some((opts.memory_size - origin) as i64 - 1) },
+            padding,
+            out_of_bounds,
+        }
+    }
+
+    /// Stops the program unless the cells from `low` to `high` relative to
+    /// the pointer are on the tape, for a pointer within `ptr`. The check is
+    /// left out at the ends where the cells are always in `allowed`.
+    fn check_bounds(&mut self, ptr: Bounds, low: i64, high: i64, allowed: Bounds) {
+        if self.checks == TapeChecks::Trusting {
+            return;
+        }
+        let cells = ptr.shift(low).join(ptr.shift(high));
+        let (above_start, below_end) = (Bounds{ max: None, ..allowed }, Bounds{ min: None, ..allowed });
+        if !above_start.contains(&cells) {
+            dynasm!(self.buffer
+                ; lea rax, [rdi + low as i32]
+                ; cmp rax, [rsi]
+                ; jb => self.out_of_bounds
+            );
+        }
+        if !below_end.contains(&cells) {
+            dynasm!(self.buffer
+                ; lea rax, [rdi + high as i32]
+                ; cmp rax, [rsi + 8]
+                ; jae => self.out_of_bounds
+            );
+        }
+    }
+
+    /// Address of the execution counter of an instruction when profiling, the
+    /// iteration counter follows right after it
+    fn counter(&mut self, inst: &Instruction) -> Option<i64> {
+        let counters = self.counters.as_mut()?;
+        let counter = counters.entry(inst as *const _).or_insert_with(|| Box::new([0; 2]));
+        Some(counter.as_ptr() as i64)
+    }
+
+    fn count_execution(&mut self, inst: &Instruction) {
+        if let Some(counter) = self.counter(inst) {
+            dynasm!(self.buffer
+                ; mov rax, QWORD counter
+                ; inc QWORD [rax]
+            );
         }
     }
 
     pub fn initialize(&mut self) {
-        dynasm!(self.buffer
-            ; xor rsi, rsi
-        );
     }
 
     pub fn finalize(&mut self) {
         dynasm!(self.buffer
+            ; mov eax, 1
+            ; ret
+            ; => self.out_of_bounds
+            ; xor eax, eax
             ; ret
         );
     }
@@ -120,6 +367,23 @@ impl<'a> CodeGenerator<'a> {
 impl<'a> ir::ConstVisitor for CodeGenerator<'a> {
     type Ret = ();
 
+    fn before(&mut self, inst: &Instruction) {
+        let offsets = inst.accessed_offsets();
+        let (low, high) = match (offsets.iter().min(), offsets.iter().max()) {
+            (Some(&low), Some(&high)) => (low, high),
+            _ => return,
+        };
+        // the pointer is checked where it moves, so it is on the tape
+        if low == 0 && high == 0 {
+            return;
+        }
+        if let Some(ptr) = self.ranges.pointer(inst) {
+            let padding = self.padding as i64;
+            let allowed = Bounds { min: self.tape.min.map(|min| min - padding), max: self.tape.max.map(|max| max + padding) };
+            self.check_bounds(ptr.intersect(self.tape), low, high, allowed);
+        }
+    }
+
     fn visit_nop(&mut self, _nop: &Instruction) {
     }
 
@@ -140,8 +404,17 @@ impl<'a> ir::ConstVisitor for CodeGenerator<'a> {
     }
 
     fn visit_linear_loop(&mut self, l: &Instruction) {
-        if let Instruction::LinearLoop{ offset: glob_offset, factors } = l {
-            if factors.len() > 0 {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            if let Some(counter) = self.counter(l) {
+                // the loop would have run as many times as the counter cell says
+                dynasm!(self.buffer
+                    ; mov rax, QWORD counter
+                    ; inc QWORD [rax]
+                    ; movzx edx, BYTE [rdi + *glob_offset as i32]
+                    ; add QWORD [rax + 8], rdx
+                );
+            }
+            if !factors.is_empty() {
                 dynasm!(self.buffer
                     ; movzx ecx, BYTE [rdi + *glob_offset as i32]
                 );
@@ -242,18 +515,28 @@ impl<'a> ir::ConstVisitor for CodeGenerator<'a> {
             dynasm!(self.buffer
                 ; lea rdi, [rdi + *offset as i32]
             );
+            if let Some(ptr) = self.ranges.pointer(mp) {
+                self.check_bounds(ptr.shift(*offset), 0, 0, self.tape);
+            }
         }
     }
 
     fn visit_loop(&mut self, l: &Instruction) {
-        if let Instruction::Loop(insts) = l {
+        if let Instruction::Loop(insts, _) = l {
             let begin = self.buffer.new_dynamic_label();
             let end = self.buffer.new_dynamic_label();
+            self.count_execution(l);
             dynasm!(self.buffer
                 ; cmp BYTE [rdi], 0
                 ; jz => end
                 ; => begin
             );
+            if let Some(counter) = self.counter(l) {
+                dynasm!(self.buffer
+                    ; mov rax, QWORD counter
+                    ; inc QWORD [rax + 8]
+                );
+            }
             self.visit_instructions(insts);
             dynasm!(self.buffer
                 ; cmp BYTE [rdi], 0
@@ -269,7 +552,10 @@ impl<'a> ir::ConstVisitor for CodeGenerator<'a> {
                 ; push rdi
                 ; push rsi
                 ; sub rsp, 24
-                ; mov rax, QWORD readbyte as _
+                ; movzx eax, BYTE [rdi + *offset as i32]
+                ; mov rdi, rsi
+                ; mov esi, eax
+                ; mov rax, QWORD readbyte as *const () as _
                 ; call rax
                 ; add rsp, 24
                 ; pop rsi
@@ -281,13 +567,15 @@ impl<'a> ir::ConstVisitor for CodeGenerator<'a> {
 
     fn visit_write(&mut self, w: &Instruction) {
         if let Instruction::Write(offset) = w {
+            self.count_execution(w);
             dynasm!(self.buffer
                 ; push rdi
                 ; push rsi
                 ; sub rsp, 24
-                ; xor rdx, rdx
-                ; mov dil, BYTE [rdi + *offset as i32]
-                ; mov rax, QWORD putbyte as _
+                ; movzx edx, BYTE [rdi + *offset as i32]
+                ; mov rdi, rsi
+                ; mov esi, edx
+                ; mov rax, QWORD putbyte as *const () as _
                 ; call rax
                 ; add rsp, 24
                 ; pop rsi
@@ -295,17 +583,51 @@ impl<'a> ir::ConstVisitor for CodeGenerator<'a> {
             );
         }
     }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        dynasm!(self.buffer
+            ; push rdi
+            ; push rsi
+            ; sub rsp, 24
+            ; mov rdx, rdi
+            ; mov rdi, rsi
+            ; mov rsi, rdx
+            ; mov rax, QWORD debug_dump as *const () as _
+            ; call rax
+            ; add rsp, 24
+            ; pop rsi
+            ; pop rdi
+        );
+    }
 }
 
-extern "C" fn putbyte(chr: u8) {
+extern "C" fn putbyte(rt: *mut Runtime, chr: u8) {
     //println!("{:?}", chr);
-    std::io::stdout().write(&[chr]).unwrap();
-    std::io::stdout().flush().unwrap();
+    let rt = unsafe { &mut *rt };
+    rt.output.write_all(&[chr]).unwrap();
+    rt.output.flush().unwrap();
+}
+
+extern "C" fn debug_dump(rt: *mut Runtime, cell: *const u8) {
+    let rt = unsafe { &*rt };
+    let index = cell as usize - rt.memory as usize;
+    let ptr = index as i64 - rt.origin as i64;
+    interpret::print_tape_window(ptr, |i| {
+        let i = (i + rt.origin as i64).rem_euclid(rt.memory_size as i64);
+        unsafe { *rt.memory.add(i as usize) as u64 }
+    });
 }
 
-extern "C" fn readbyte() -> u8 {
-    //let mut byte: u8 = 0;
-    //std::io::stdin().read(&mut [byte]).unwrap();
-    std::io::stdin().bytes().next().unwrap_or(Ok(0)).unwrap_or(0)
+extern "C" fn readbyte(rt: *mut Runtime, current: u8) -> u8 {
+    let rt = unsafe { &mut *rt };
+    let mut byte: [u8; 1] = [0];
+    match rt.input.read(&mut byte) {
+        Ok(1) => byte[0],
+        _ => match rt.eof_mode {
+            EofMode::Zero => 0,
+            EofMode::MinusOne => 0xFF,
+            EofMode::Unchanged => current,
+        }
+    }
 }
 
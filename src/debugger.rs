@@ -0,0 +1,242 @@
+use super::ir::{self, Instruction, Span};
+use super::interpret::{self, Monitor, Tape};
+use super::options::Options;
+use super::parser;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead, Read, Write};
+use std::process::exit;
+use std::str::FromStr;
+
+const HELP: &str = "\
+commands:
+    s, step [n]         execute n instructions (default 1), entering loops
+    n, next             execute the next instruction, stepping over loops
+    c, continue         run until a breakpoint or watch triggers
+    u, until <cell>     run until the given cell changes
+    b, break <pos>      set a breakpoint at a source position (line:col or offset)
+    d, delete <n>       delete breakpoint n
+    w, watch <cell>     stop whenever the given cell changes
+    x, unwatch <cell>   remove a watch
+    p, print [cell]     print a cell (default: the current one)
+    t, tape             show the cells around the pointer
+    l, list             show the current source location
+    i, info             list breakpoints and watches
+    q, quit             exit the debugger
+    h, help             show this message";
+
+enum Mode {
+    // stop after the given number of instructions
+    Step(usize),
+    // stop at the next instruction not nested deeper than the given depth
+    Next(usize),
+    // stop once the cell does not hold the value anymore
+    Until(i64, u64),
+    Continue,
+}
+
+pub struct Debugger<'a> {
+    code: &'a str,
+    ranges: HashMap<*const Instruction, Span>,
+    // instructions starting a run of instructions sharing a source range
+    run_starts: HashSet<*const Instruction>,
+    breakpoints: Vec<usize>,
+    watches: BTreeMap<i64, u64>,
+    mode: Mode,
+    depth: usize,
+}
+
+/// Runs the program in the interpreter under control of an interactive debugger
+pub fn run(code: &str, instrs: &Vec<Instruction>, opts: &Options, input: &mut dyn Read) {
+    let mut debugger = Debugger::new(code, instrs);
+    eprintln!("zombie debugger, type 'h' for help");
+    interpret::run_with_monitor(instrs, opts, input, &mut io::stdout(), &mut debugger);
+    eprintln!("program finished");
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(code: &'a str, instrs: &[Instruction]) -> Self {
+        let mut run_starts = HashSet::new();
+        let ranges = ir::source_ranges(instrs, Span{ start: 0, end: code.len() });
+        collect_run_starts(instrs, &ranges, &mut run_starts);
+        Debugger {
+            code,
+            ranges,
+            run_starts,
+            breakpoints: Vec::new(),
+            watches: BTreeMap::new(),
+            mode: Mode::Step(1),
+            depth: 0,
+        }
+    }
+
+    fn range_of(&self, inst: &Instruction) -> Span {
+        self.ranges.get(&(inst as *const _)).copied().unwrap_or(Span{ start: 0, end: 0 })
+    }
+
+    fn hits_breakpoint(&self, inst: &Instruction, pos: usize) -> bool {
+        match inst {
+            Instruction::Loop(_, Some(span)) => pos == span.start,
+            Instruction::LinearLoop{ span: Some(span), .. } => span.start <= pos && pos < span.end,
+            _ => {
+                let range = self.range_of(inst);
+                self.run_starts.contains(&(inst as *const _)) && range.start <= pos && pos < range.end
+            }
+        }
+    }
+
+    fn show_location(&self, inst: &Instruction) {
+        let (start, snippet) = parser::commands_in(self.code, self.range_of(inst), 40);
+        let (line, col) = parser::line_col(self.code, start);
+        // loops without their bodies, the snippet shows them
+        let description = match inst {
+            Instruction::Loop(_, _) => "Loop".to_string(),
+            Instruction::LinearLoop{ offset, .. } => format!("LinearLoop(@{})", offset),
+            _ => inst.to_string(),
+        };
+        eprintln!("at {}:{} {}    {}", line, col, description, snippet);
+    }
+
+    fn prompt(&mut self, inst: &Instruction, tape: &dyn Tape) {
+        self.show_location(inst);
+        let stdin = io::stdin();
+        loop {
+            eprint!("(zdb) ");
+            io::stderr().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                exit(0);
+            }
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("s");
+            let arg = words.next();
+            let cell_arg = || arg.and_then(|a| i64::from_str(a).ok());
+
+            match command {
+                "s" | "step" => {
+                    let n = arg.and_then(|a| usize::from_str(a).ok()).unwrap_or(1);
+                    self.mode = Mode::Step(n.max(1));
+                    return;
+                },
+                "n" | "next" => {
+                    self.mode = Mode::Next(self.depth);
+                    return;
+                },
+                "c" | "continue" => {
+                    self.mode = Mode::Continue;
+                    return;
+                },
+                "u" | "until" => match cell_arg() {
+                    Some(cell) => {
+                        self.mode = Mode::Until(cell, tape.cell(cell));
+                        return;
+                    },
+                    None => eprintln!("usage: until <cell>"),
+                },
+                "b" | "break" => match arg.and_then(|a| parser::parse_position(self.code, a)) {
+                    Some(pos) => {
+                        let (line, col) = parser::line_col(self.code, pos);
+                        self.breakpoints.push(pos);
+                        eprintln!("breakpoint {} at {}:{}", self.breakpoints.len(), line, col);
+                    },
+                    None => eprintln!("usage: break <line:col | offset>"),
+                },
+                "d" | "delete" => match arg.and_then(|a| usize::from_str(a).ok()) {
+                    Some(n) if n >= 1 && n <= self.breakpoints.len() => {
+                        self.breakpoints.remove(n - 1);
+                    },
+                    _ => eprintln!("no such breakpoint"),
+                },
+                "w" | "watch" => match cell_arg() {
+                    Some(cell) => {
+                        self.watches.insert(cell, tape.cell(cell));
+                    },
+                    None => eprintln!("usage: watch <cell>"),
+                },
+                "x" | "unwatch" => match cell_arg() {
+                    Some(cell) => {
+                        self.watches.remove(&cell);
+                    },
+                    None => eprintln!("usage: unwatch <cell>"),
+                },
+                "p" | "print" => {
+                    let cell = cell_arg().unwrap_or_else(|| tape.ptr());
+                    eprintln!("@{} = {}", cell, tape.cell(cell));
+                },
+                "t" | "tape" => {
+                    interpret::print_tape_window(tape.ptr(), |i| tape.cell(i));
+                },
+                "l" | "list" => self.show_location(inst),
+                "i" | "info" => {
+                    for (i, &pos) in self.breakpoints.iter().enumerate() {
+                        let (line, col) = parser::line_col(self.code, pos);
+                        eprintln!("breakpoint {} at {}:{}", i + 1, line, col);
+                    }
+                    for (cell, value) in &self.watches {
+                        eprintln!("watch @{} = {}", cell, value);
+                    }
+                },
+                "q" | "quit" => exit(0),
+                "h" | "help" => eprintln!("{}", HELP),
+                _ => eprintln!("unknown command '{}', type 'h' for help", command),
+            }
+        }
+    }
+}
+
+impl<'a> Monitor for Debugger<'a> {
+    fn before(&mut self, inst: &Instruction, tape: &dyn Tape) {
+        let mut stop = match self.mode {
+            Mode::Step(ref mut n) => {
+                *n -= 1;
+                *n == 0
+            },
+            Mode::Next(depth) => self.depth <= depth,
+            Mode::Until(cell, value) => tape.cell(cell) != value,
+            Mode::Continue => false,
+        };
+
+        for (cell, value) in self.watches.iter_mut() {
+            let new_value = tape.cell(*cell);
+            if new_value != *value {
+                eprintln!("@{} changed: {} -> {}", cell, value, new_value);
+                *value = new_value;
+                stop = true;
+            }
+        }
+
+        for (i, &pos) in self.breakpoints.iter().enumerate() {
+            if self.hits_breakpoint(inst, pos) {
+                eprintln!("breakpoint {}", i + 1);
+                stop = true;
+            }
+        }
+
+        if stop {
+            self.prompt(inst, tape);
+        }
+
+        if let Instruction::Loop(_, _) = inst {
+            self.depth += 1;
+        }
+    }
+
+    fn after(&mut self, inst: &Instruction, _tape: &dyn Tape) {
+        if let Instruction::Loop(_, _) = inst {
+            self.depth -= 1;
+        }
+    }
+}
+
+fn collect_run_starts(instrs: &[Instruction], ranges: &HashMap<*const Instruction, Span>, starts: &mut HashSet<*const Instruction>) {
+    let mut previous = None;
+    for inst in instrs {
+        let range = ranges.get(&(inst as *const _));
+        if inst.span().is_none() && range != previous {
+            starts.insert(inst as *const _);
+        }
+        previous = range;
+        if let Instruction::Loop(body, _) = inst {
+            collect_run_starts(body, ranges, starts);
+        }
+    }
+}
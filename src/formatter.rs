@@ -3,31 +3,51 @@
 
 pub struct Formatter {
     indent: String,
-    code: String
+    code: String,
+    // lines added with `add_line`, annotations are not counted
+    lines: usize,
 }
 
 
 
+impl Default for Formatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Formatter {
     pub fn new() -> Self {
         Formatter {
             indent: String::new(),
-            code: String::new()
+            code: String::new(),
+            lines: 0,
         }
     }
 
     pub fn add_line(&mut self, line: &str) {
+        self.add_annotation(line);
+        self.lines += 1;
+    }
+
+    /// Adds a line that is not code, like the marks of a source map
+    pub fn add_annotation(&mut self, line: &str) {
         self.code += &self.indent;
         self.code += line;
         self.code += "\n"
     }
 
+    /// The lines of code added so far
+    pub fn line_count(&self) -> usize {
+        self.lines
+    }
+
     pub fn indent(&mut self) {
         self.indent += "    ";
     }
     pub fn unindent(&mut self) {
         if self.indent.len() >= 4 {
-            self.indent = (&self.indent[..(self.indent.len() - 4)]).to_string();
+            self.indent = self.indent[..(self.indent.len() - 4)].to_string();
         }
     }
 
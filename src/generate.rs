@@ -0,0 +1,203 @@
+use std::ops::Range;
+
+/// Supplies the random decisions of the program generator
+pub trait Choices {
+    /// A number in `0..bound`
+    fn choose(&mut self, bound: u64) -> u64;
+}
+
+/// Seeded xorshift generator, so the same seed always gives the same program
+pub struct Xorshift(u64);
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        Xorshift(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+}
+
+impl Choices for Xorshift {
+    fn choose(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// Takes the decisions from a byte string (e.g. fuzzer input), always choosing
+/// 0 once it is used up
+pub struct ByteChoices<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteChoices<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        ByteChoices{ bytes }
+    }
+}
+
+impl<'a> Choices for ByteChoices<'a> {
+    fn choose(&mut self, bound: u64) -> u64 {
+        match self.bytes.split_first() {
+            Some((&byte, rest)) => {
+                self.bytes = rest;
+                byte as u64 % bound
+            },
+            None => 0,
+        }
+    }
+}
+
+pub struct GenOptions {
+    // the pointer always stays in the cells 0..tape_range
+    pub tape_range: i64,
+    // maximum nesting depth of loops
+    pub max_depth: usize,
+    // maximum number of items in a block
+    pub max_block_length: u64,
+    // whether the program may contain ','
+    pub reads: bool,
+}
+
+impl Default for GenOptions {
+    fn default() -> Self {
+        GenOptions {
+            tape_range: 12,
+            max_depth: 3,
+            max_block_length: 8,
+            reads: false,
+        }
+    }
+}
+
+/// Generates a random program that terminates on any input and for any cell
+/// size, and never moves the pointer outside of `0..opts.tape_range`
+pub fn program(choices: &mut dyn Choices, opts: &GenOptions) -> String {
+    let mut generator = Generator {
+        choices,
+        opts,
+        ptr: 0,
+        counters: Vec::new(),
+        code: String::new(),
+    };
+    generator.block(0);
+    generator.code
+}
+
+struct Generator<'a> {
+    choices: &'a mut dyn Choices,
+    opts: &'a GenOptions,
+    ptr: i64,
+    // cells counting down the enclosing loops, these are not touched by anything
+    // inside the loops
+    counters: Vec<i64>,
+    code: String,
+}
+
+impl<'a> Generator<'a> {
+    fn choose_in(&mut self, range: Range<u64>) -> u64 {
+        range.start + self.choices.choose(range.end - range.start)
+    }
+
+    fn is_free(&self, cell: i64) -> bool {
+        !self.counters.contains(&cell)
+    }
+
+    fn block(&mut self, depth: usize) {
+        let length = self.choose_in(1..self.opts.max_block_length + 1);
+        for _ in 0..length {
+            match self.choices.choose(8) {
+                0 | 1 => if self.is_free(self.ptr) {
+                    self.add();
+                },
+                2 | 3 => {
+                    let target = self.choices.choose(self.opts.tape_range as u64) as i64;
+                    self.move_to(target);
+                },
+                4 => self.code.push('.'),
+                5 => if self.opts.reads && self.is_free(self.ptr) {
+                    self.code.push(',');
+                },
+                6 => if self.is_free(self.ptr) {
+                    self.linear_loop();
+                },
+                _ => if depth < self.opts.max_depth && self.is_free(self.ptr) {
+                    self.counted_loop(depth);
+                },
+            }
+        }
+    }
+
+    fn add(&mut self) {
+        let c = if self.choices.choose(2) == 0 { "+" } else { "-" };
+        let n = self.choose_in(1..21) as usize;
+        self.code.push_str(&c.repeat(n));
+    }
+
+    fn move_to(&mut self, target: i64) {
+        let c = if target > self.ptr { ">" } else { "<" };
+        self.code.push_str(&c.repeat((target - self.ptr).unsigned_abs() as usize));
+        self.ptr = target;
+    }
+
+    // sets the cell at the pointer up to count a loop down to zero in the
+    // given number of iterations, and returns what the loop changes it by
+    // each time, e.g. [-]++++ for --
+    fn counter(&mut self, iterations: usize) -> String {
+        let (up, down) = if self.choices.choose(2) == 0 { ("+", "-") } else { ("-", "+") };
+        let step = self.choose_in(1..3) as usize;
+        self.code.push_str("[-]");
+        self.code.push_str(&up.repeat(iterations * step));
+        down.repeat(step)
+    }
+
+    // a loop the optimizer can linearize, e.g. [->+++>-<<], [>+<-] or
+    // [-]++++[-->+<]
+    fn linear_loop(&mut self) {
+        let counter = self.ptr;
+        // decrementing by one reaches zero from any value of a wrapping cell,
+        // other steps need a counter that is set first
+        let step = match self.choices.choose(2) {
+            0 => "-".to_string(),
+            _ => {
+                let iterations = self.choose_in(1..6) as usize;
+                self.counter(iterations)
+            },
+        };
+        let step_first = self.choices.choose(2) == 0;
+        self.code.push('[');
+        if step_first {
+            self.code.push_str(&step);
+        }
+        for _ in 0..self.choose_in(1..4) {
+            let target = self.choices.choose(self.opts.tape_range as u64) as i64;
+            if target != counter && self.is_free(target) {
+                self.move_to(target);
+                match self.choices.choose(2) {
+                    0 => self.add(),
+                    _ => self.code.push(if self.choices.choose(2) == 0 { '+' } else { '-' }),
+                }
+            }
+        }
+        self.move_to(counter);
+        if !step_first {
+            self.code.push_str(&step);
+        }
+        self.code.push(']');
+    }
+
+    // a loop running a fixed number of times, e.g. [-]+++[ ... -] or
+    // [-]------[ ... ++]
+    fn counted_loop(&mut self, depth: usize) {
+        let counter = self.ptr;
+        let iterations = self.choose_in(1..6) as usize;
+        let step = self.counter(iterations);
+        self.code.push('[');
+        self.counters.push(counter);
+        self.block(depth + 1);
+        self.counters.pop();
+        self.move_to(counter);
+        self.code.push_str(&step);
+        self.code.push(']');
+    }
+}
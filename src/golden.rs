@@ -0,0 +1,119 @@
+use super::options::Options;
+use super::{compile, interpret, optimize, parser};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// number of bytes shown around the first difference
+const CONTEXT: usize = 24;
+
+#[derive(Clone, Copy)]
+pub enum Backend {
+    Interpreter,
+    Jit,
+}
+
+/// A program with the output it is expected to produce
+pub struct GoldenTest {
+    pub program: PathBuf,
+    // missing means empty input
+    pub input: Option<PathBuf>,
+    pub output: PathBuf,
+}
+
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// Finds all `<name>.bf` files in `dir` with a matching `<name>.out`, and the
+/// `<name>.in` files with their input
+pub fn discover(dir: &Path) -> io::Result<Vec<GoldenTest>> {
+    let mut tests = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let program = entry?.path();
+        if program.extension().is_some_and(|ext| ext == "bf") {
+            let output = program.with_extension("out");
+            if output.is_file() {
+                let input = Some(program.with_extension("in")).filter(|input| input.is_file());
+                tests.push(GoldenTest{ program, input, output });
+            }
+        }
+    }
+    tests.sort_by(|a, b| a.program.cmp(&b.program));
+    Ok(tests)
+}
+
+/// Runs all tests in `dir`, printing one line per test and a description of
+/// the first difference for failing ones
+pub fn run(dir: &Path, backend: Backend, opts: &Options) -> io::Result<Summary> {
+    let mut summary = Summary{ passed: 0, failed: 0 };
+    for test in discover(dir)? {
+        let name = test.program.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        match check(&test, backend, opts)? {
+            None => {
+                println!("test {} ... ok", name);
+                summary.passed += 1;
+            },
+            Some(failure) => {
+                println!("test {} ... FAILED", name);
+                println!("    {}", failure);
+                summary.failed += 1;
+            },
+        }
+    }
+    println!();
+    println!("{} passed, {} failed", summary.passed, summary.failed);
+    Ok(summary)
+}
+
+/// Runs a single test, returning the reason if it fails
+pub fn check(test: &GoldenTest, backend: Backend, opts: &Options) -> io::Result<Option<String>> {
+    let source = fs::read_to_string(&test.program)?;
+    let expected = fs::read(&test.output)?;
+    let (code, inline_input) = parser::split_inline_input(&source, opts);
+    let input = match (&test.input, inline_input) {
+        (Some(file), _) => fs::read(file)?,
+        (None, Some(text)) => text.as_bytes().to_vec(),
+        (None, None) => Vec::new(),
+    };
+
+    let insts = match parser::parse(code, opts) {
+        Ok(insts) => insts,
+        Err(msg) => return Ok(Some(format!("error parsing: {}", msg))),
+    };
+    let insts = optimize::lin_optimize(insts, opts);
+
+    let mut output = Vec::new();
+    match backend {
+        Backend::Interpreter => interpret::run(&insts, opts, &mut &input[..], &mut output),
+        Backend::Jit => {
+            if let Err(err) = compile::supports(opts) {
+                return Ok(Some(format!("cannot compile: {}", err)));
+            }
+            if let Err(err) = compile::compile_and_run(&insts, opts, &mut &input[..], &mut output) {
+                return Ok(Some(err.to_string()));
+            }
+        },
+    }
+    Ok(describe_mismatch(&expected, &output))
+}
+
+fn describe_mismatch(expected: &[u8], actual: &[u8]) -> Option<String> {
+    let pos = match expected.iter().zip(actual).position(|(e, a)| e != a) {
+        Some(pos) => pos,
+        None if expected.len() == actual.len() => return None,
+        None => expected.len().min(actual.len()),
+    };
+    let line = expected[..pos].iter().filter(|&&b| b == b'\n').count() + 1;
+    let start = pos.saturating_sub(CONTEXT / 2);
+    let snippet = |bytes: &[u8]| {
+        let end = bytes.len().min(pos + CONTEXT / 2);
+        let shown = if start < end { &bytes[start..end] } else { &[][..] };
+        format!("\"{}\"", shown.escape_ascii())
+    };
+    Some(format!("output differs at byte {} (line {}, expected {} bytes, got {})\n    \
+                  expected: {}\n    \
+                  actual:   {}",
+                 pos, line, expected.len(), actual.len(), snippet(expected), snippet(actual)))
+}
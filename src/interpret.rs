@@ -1,174 +1,258 @@
-use super::ir::Instruction;
+use super::ir::{Instruction, DEBUG_WINDOW};
 use super::options;
-use super::options::Options;
+use super::options::{Options, EofMode};
 use std::io::Read;
 use std::io::Write;
-use std::io;
-use std::ops::{Add, Mul, Sub};
 use std::num::Wrapping;
 
 
 trait FromNum {
     fn from(n: i64) -> Self;
 }
+trait ToNum {
+    fn to_num(&self) -> u64;
+}
 trait CellWrite {
     fn write<S: Write>(&self, s: &mut S);
 }
-trait CellRead {
-    fn read<R: Read>(&mut self, r: &mut R);
-}
 
 impl FromNum for Wrapping<u8> {
     fn from(n: i64) -> Self { Wrapping(n as u8) }
 }
+impl ToNum for Wrapping<u8> {
+    fn to_num(&self) -> u64 { self.0 as _ }
+}
 impl CellWrite for Wrapping<u8> {
     fn write<S: Write>(&self, s: &mut S) {
-        s.write(&[self.0]).unwrap();
+        s.write_all(&[self.0]).unwrap();
         s.flush().unwrap();
     }
 }
-impl CellRead for Wrapping<u8> {
-    fn read<R: Read>(&mut self, r: &mut R) {
-        let mut bytes: [u8; 1] = [0];
-        if r.read(&mut bytes).is_ok() {
-            *self = Wrapping(bytes[0]);
-        }
-        else {
-            *self = Wrapping(0);
-        }
-    }
-}
 
 
 impl FromNum for Wrapping<u16> {
     fn from(n: i64) -> Self { Wrapping(n as u16) }
 }
+impl ToNum for Wrapping<u16> {
+    fn to_num(&self) -> u64 { self.0 as _ }
+}
 impl CellWrite for Wrapping<u16> {
     fn write<S: Write>(&self, s: &mut S) {
-        s.write(&[self.0 as _]).unwrap();
+        s.write_all(&[self.0 as _]).unwrap();
         s.flush().unwrap();
     }
 }
-impl CellRead for Wrapping<u16> {
-    fn read<R: Read>(&mut self, r: &mut R) {
-        let mut bytes: [u8; 1] = [0];
-        if r.read(&mut bytes).is_ok() {
-            *self = Wrapping(bytes[0] as _);
-        }
-        else {
-            *self = Wrapping(0);
-        }
-    }
-}
 
 impl FromNum for i64 {
     fn from(n: i64) -> Self { n as _ }
 }
+impl ToNum for i64 {
+    fn to_num(&self) -> u64 { *self as _ }
+}
 impl CellWrite for i64 {
     fn write<S: Write>(&self, s: &mut S) {
-        s.write(&[*self as u8]).unwrap();
+        s.write_all(&[*self as u8]).unwrap();
         s.flush().unwrap();
     }
 }
-impl CellRead for i64 {
-    fn read<R: Read>(&mut self, r: &mut R) {
-        let mut bytes: [u8; 1] = [0];
-        if r.read(&mut bytes).is_ok() {
-            *self = bytes[0] as _;
+
+
+
+
+
+
+
+/// Prints the cells around `ptr` to stderr, the current one in brackets
+/// (the output of the `#` instruction, shared with the JIT)
+pub fn print_tape_window<F: Fn(i64) -> u64>(ptr: i64, cell: F) {
+    let mut line = format!("#{}:", ptr);
+    for i in (ptr - DEBUG_WINDOW)..=(ptr + DEBUG_WINDOW) {
+        if i == ptr {
+            line += &format!(" [{}]", cell(i));
         }
         else {
-            *self = 0;
+            line += &format!(" {}", cell(i));
         }
     }
+    eprintln!("{}", line);
+}
+
+
+/// Observes the execution of a program in the interpreter
+pub trait Monitor {
+    /// Called before an instruction is executed
+    fn before(&mut self, _inst: &Instruction, _tape: &dyn Tape) {}
+
+    /// Called after an instruction (for loops, after the last iteration)
+    fn after(&mut self, _inst: &Instruction, _tape: &dyn Tape) {}
+
+    /// Called before each iteration of a loop
+    fn iteration(&mut self, _inst: &Instruction, _tape: &dyn Tape) {}
 }
 
+/// Read access to the state of the interpreter for monitors
+pub trait Tape {
+    /// The pointer, relative to the starting cell
+    fn ptr(&self) -> i64;
 
+    /// The value of a cell, indexed relative to the starting cell
+    fn cell(&self, index: i64) -> u64;
+}
 
+/// The index of a cell relative to the starting cell, the tape wraps around
+/// at both ends
+fn wrap(cell: i64, len: usize) -> usize {
+    cell.rem_euclid(len as i64) as usize
+}
 
+struct NoMonitor;
 
+impl Monitor for NoMonitor {}
 
 
-struct Data<T> {
+struct Data<'a, T> {
     memory: Vec<T>,
     ptr: i64,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    eof_mode: EofMode,
+}
+
+impl<'a, T: ToNum> Data<'a, T> {
+    fn tape(&self) -> Vec<u64> {
+        self.memory.iter().map(ToNum::to_num).collect()
+    }
 }
 
-pub fn run(instructions: &Vec<Instruction>, opts: &Options) {
+impl<'a, T: ToNum> Tape for Data<'a, T> {
+    fn ptr(&self) -> i64 {
+        self.ptr
+    }
+
+    fn cell(&self, index: i64) -> u64 {
+        self.memory[wrap(index, self.memory.len())].to_num()
+    }
+}
+
+pub fn run(instructions: &Vec<Instruction>, opts: &Options, input: &mut dyn Read, output: &mut dyn Write) {
+    run_with_monitor(instructions, opts, input, output, &mut NoMonitor);
+}
+
+pub fn run_with_monitor<M: Monitor>(instructions: &Vec<Instruction>, opts: &Options,
+                                    input: &mut dyn Read, output: &mut dyn Write, monitor: &mut M) {
+    execute(instructions, opts, input, output, monitor);
+}
+
+/// Runs the program and returns the contents of the tape afterwards
+pub fn run_to_tape(instructions: &Vec<Instruction>, opts: &Options,
+                   input: &mut dyn Read, output: &mut dyn Write) -> Vec<u64> {
+    execute(instructions, opts, input, output, &mut NoMonitor)
+}
+
+fn execute<M: Monitor>(instructions: &Vec<Instruction>, opts: &Options,
+                       input: &mut dyn Read, output: &mut dyn Write, monitor: &mut M) -> Vec<u64> {
     if opts.cell_size == options::CellSize::Bits(8) {
         let mut data = Data::<Wrapping<u8>> {
-            memory: vec![Wrapping(0); opts.memory_size as usize],
+            memory: vec![Wrapping(0); opts.memory_size],
             ptr: 0,
+            input,
+            output,
+            eof_mode: opts.eof_mode,
         };
-        run_with_funcs(instructions, &mut data, &|a, b| a + b, &|a, b| a * b);
+        run_with_funcs(instructions, &mut data, &|a, b| a + b, &|a, b| a * b, monitor);
+        data.tape()
     }
     else if opts.cell_size == options::CellSize::Bits(16) {
         let mut data = Data::<Wrapping<u16>> {
-            memory: vec![Wrapping(0); opts.memory_size as usize],
+            memory: vec![Wrapping(0); opts.memory_size],
+            ptr: 0,
+            input,
+            output,
+            eof_mode: opts.eof_mode,
+        };
+        run_with_funcs(instructions, &mut data, &|a, b| a + b, &|a, b| a * b, monitor);
+        data.tape()
+    }
+    else if let options::CellSize::Modular(n) = opts.cell_size {
+        let n = n as i64;
+        let mut data = Data::<i64> {
+            memory: vec![0; opts.memory_size],
             ptr: 0,
+            input,
+            output,
+            eof_mode: opts.eof_mode,
         };
-        run_with_funcs(instructions, &mut data, &|a, b| a + b, &|a, b| a * b);
+        run_with_funcs(instructions, &mut data, &|a, b| (a + b).rem_euclid(n), &|a, b| (a * b).rem_euclid(n), monitor);
+        data.tape()
     }
     else {
-        match opts.cell_size {
-            options::CellSize::Modular(n) => {
-                let n = n as i64;
-                let mut data = Data::<i64> {
-                    memory: vec![0; opts.memory_size as usize],
-                    ptr: 0,
-                };
-                run_with_funcs(instructions, &mut data, &|a, b| (a + b) % n, &|a, b| (a * b) % n);
-            },
-            _ => {}
-        }
+        Vec::new()
     }
 }
 
 
-fn run_with_funcs<T>(instructions: &Vec<Instruction>,
+fn run_with_funcs<T, M>(instructions: &Vec<Instruction>,
                  data: &mut Data<T>,
                  add: &dyn Fn(T, T) -> T,
-                 mul: &dyn Fn(T, T) -> T)
+                 mul: &dyn Fn(T, T) -> T,
+                 monitor: &mut M)
 where
-T: Copy + Eq + CellWrite + CellRead + FromNum
+T: Copy + Eq + CellWrite + FromNum + ToNum,
+M: Monitor
 {
     let len = data.memory.len();
     for inst in instructions {
+        monitor.before(inst, data);
         match inst {
             Instruction::Nop => {},
             Instruction::Add{ offset, value } => {
-                let cell = &mut data.memory[(data.ptr + offset) as usize % len];
+                let cell = &mut data.memory[wrap(data.ptr + offset, len)];
                 *cell = add(*cell, T::from(*value));
             },
             Instruction::Set{ offset, value } => {
-                let cell = &mut data.memory[(data.ptr + offset) as usize % len];
+                let cell = &mut data.memory[wrap(data.ptr + offset, len)];
                 *cell = T::from(*value);
             },
             Instruction::MovePtr(offset) => {
                 data.ptr = data.ptr.wrapping_add(*offset);
             },
-            Instruction::Loop(instrs) => {
-                while data.memory[data.ptr as usize % len] != T::from(0) {
-                    run_with_funcs(instrs, data, add, mul);
+            Instruction::Loop(instrs, _) => {
+                while data.memory[wrap(data.ptr, len)] != T::from(0) {
+                    monitor.iteration(inst, data);
+                    run_with_funcs(instrs, data, add, mul, monitor);
                 }
             },
             Instruction::Read(offset) => {
-                let cell = &mut data.memory[(data.ptr + offset) as usize % len];
-                cell.read(&mut io::stdin());
+                let cell = &mut data.memory[wrap(data.ptr + offset, len)];
+                let mut byte: [u8; 1] = [0];
+                match data.input.read(&mut byte) {
+                    // adding 0 brings the byte into the range of the cell
+                    Ok(1) => *cell = add(T::from(byte[0] as i64), T::from(0)),
+                    _ => match data.eof_mode {
+                        EofMode::Zero => *cell = T::from(0),
+                        EofMode::MinusOne => *cell = add(T::from(-1), T::from(0)),
+                        EofMode::Unchanged => {},
+                    }
+                }
             },
             Instruction::Write(offset) => {
-                let cell = data.memory[(data.ptr + offset) as usize % len];
-                cell.write(&mut io::stdout());
+                let cell = data.memory[wrap(data.ptr + offset, len)];
+                cell.write(&mut data.output);
             },
-            Instruction::LinearLoop{ offset: glob_offset, factors } => {
+            Instruction::LinearLoop{ offset: glob_offset, factors, .. } => {
                 //assert_eq!(factors.get(&0), Some(&-1));
-                let multiplicator = data.memory[((data.ptr + glob_offset) as usize) % len];
+                let multiplicator = data.memory[wrap(data.ptr + glob_offset, len)];
                 for (offset, value) in factors {
-                    let cell = &mut data.memory[(data.ptr + offset + glob_offset) as usize % len];
+                    let cell = &mut data.memory[wrap(data.ptr + offset + glob_offset, len)];
                     *cell = add(*cell, mul(multiplicator, T::from(*value)));
                 }
-                data.memory[((data.ptr + glob_offset) as usize) % len] = T::from(0);
+                data.memory[wrap(data.ptr + glob_offset, len)] = T::from(0);
+            },
+            Instruction::Debug => {
+                print_tape_window(data.ptr, |i| data.memory[wrap(i, len)].to_num());
             },
         }
+        monitor.after(inst, data);
     }
 }
 
@@ -1,6 +1,16 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use super::formatter::Formatter;
+use super::options::{CellLayout, CellSize, Options};
 
-#[derive(Debug)]
+/// A range of byte positions in the source code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Instruction {
     // No instruction
     Nop,
@@ -10,54 +20,304 @@ pub enum Instruction {
     Set{ offset: i64, value: i64 },
     // Add the value at offset to all cells specified by factors
     // multiplied (factors indices are relative to offset)
-    LinearLoop{ offset: i64, factors: BTreeMap<i64, i64> },
+    LinearLoop{ offset: i64, factors: BTreeMap<i64, i64>, span: Option<Span> },
     // Move the current cell pointer
     MovePtr(i64),
     // A loop that is executed until the current cell is 0
-    Loop(Vec<Instruction>),
+    Loop(Vec<Instruction>, Option<Span>),
     // Read one input symbol into the current cell
     Read(i64),
     // Print the current cell
-    Write(i64)
+    Write(i64),
+    // Dump the cells around the current cell to stderr
+    Debug
 }
 
+/// Number of cells shown on each side of the pointer by `Debug`
+pub const DEBUG_WINDOW: i64 = 8;
+
 impl Instruction {
-    pub fn to_string(&self) -> String {
-        use self::Instruction::*;
+    /// The source span, if this instruction stands for a loop in the code
+    pub fn span(&self) -> Option<Span> {
         match self {
+            Instruction::Loop(_, span) => *span,
+            Instruction::LinearLoop{ span, .. } => *span,
+            _ => None
+        }
+    }
+
+    /// The offsets of the cells the instruction reads or writes, loops read
+    /// the current cell (their bodies are not included)
+    pub fn accessed_offsets(&self) -> Vec<i64> {
+        match self {
+            Instruction::Add{ offset, .. } | Instruction::Set{ offset, .. } |
+            Instruction::Read(offset) | Instruction::Write(offset) => vec![*offset],
+            Instruction::LinearLoop{ offset, factors, .. } => {
+                std::iter::once(*offset).chain(factors.keys().map(|off| offset + off)).collect()
+            },
+            Instruction::Loop(_, _) => vec![0],
+            Instruction::Nop | Instruction::MovePtr(_) | Instruction::Debug => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// The instruction in the textual IR, see `print`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(print(std::slice::from_ref(self), true, &()).trim_end())
+    }
+}
+
+/// Adds to what `print` writes for each instruction
+#[allow(unused_variables)]
+pub trait Annotations {
+    /// A comment after the instruction, e.g. what an analysis knows about it
+    fn comment(&self, inst: &Instruction) -> Option<String> {
+        None
+    }
+
+    /// A line before the instruction
+    fn before(&self, inst: &Instruction) -> Option<String> {
+        None
+    }
+
+    /// A line after the instruction, and after the body of loops
+    fn after(&self, inst: &Instruction) -> Option<String> {
+        None
+    }
+}
+
+impl Annotations for () {}
+
+/// The annotations of both, comments are separated by `; `
+impl<A: Annotations, B: Annotations> Annotations for (A, B) {
+    fn comment(&self, inst: &Instruction) -> Option<String> {
+        match (self.0.comment(inst), self.1.comment(inst)) {
+            (Some(first), Some(second)) => Some(format!("{}; {}", first, second)),
+            (first, second) => first.or(second),
+        }
+    }
+
+    fn before(&self, inst: &Instruction) -> Option<String> {
+        self.0.before(inst).or_else(|| self.1.before(inst))
+    }
+
+    fn after(&self, inst: &Instruction) -> Option<String> {
+        self.0.after(inst).or_else(|| self.1.after(inst))
+    }
+}
+
+/// The instructions in the textual IR described in `trans::zombie_ir`, one per
+/// line with the bodies of loops indented. `spans` adds the source ranges of
+/// the loops.
+pub fn print(instrs: &[Instruction], spans: bool, annotations: &dyn Annotations) -> String {
+    let mut formatter = Formatter::new();
+    print_instructions(&mut formatter, instrs, spans, annotations);
+    formatter.get_code()
+}
+
+fn print_instructions(formatter: &mut Formatter, instrs: &[Instruction], spans: bool, annotations: &dyn Annotations) {
+    use self::Instruction::*;
+    for inst in instrs {
+        if let Some(line) = annotations.before(inst) {
+            formatter.add_line(&line);
+        }
+        let span = match inst.span() {
+            Some(span) if spans => format!(" {}", span),
+            _ => String::new(),
+        };
+        let line = match inst {
             Nop => "Nop".to_string(),
+            Add{ offset, value } => format!("@{} += {}", offset, value),
+            Set{ offset, value } => format!("@{} = {}", offset, value),
+            LinearLoop{ offset, .. } => format!("LinearLoop(@{}){} {{", offset, span),
+            MovePtr(offset) => format!("ptr += {}", offset),
+            Loop(_, _) => format!("Loop{} {{", span),
+            Read(offset) => format!("Read(@{})", offset),
+            Write(offset) => format!("Write(@{})", offset),
+            Debug => "Debug".to_string(),
+        };
+        match annotations.comment(inst) {
+            Some(comment) => formatter.add_line(&format!("{}  // {}", line, comment)),
+            None => formatter.add_line(&line),
+        }
+
+        match inst {
+            LinearLoop{ offset, factors, .. } => {
+                formatter.indent();
+                for (off, factor) in factors {
+                    formatter.add_line(&format!("@{} += {} * @{}", offset + off, factor, offset));
+                }
+                formatter.unindent();
+                formatter.add_line("}");
+            },
+            Loop(body, _) => {
+                formatter.indent();
+                print_instructions(formatter, body, spans, annotations);
+                formatter.unindent();
+                formatter.add_line("}");
+            },
+            _ => {},
+        }
+        if let Some(line) = annotations.after(inst) {
+            formatter.add_line(&line);
+        }
+    }
+}
+
+/// Maps every instruction (by address) to the source range it was generated from.
+///
+/// Loops have their own span. The other instructions are attributed to all the
+/// code between the neighbouring loops, since the parser and the optimizer
+/// merge them freely.
+pub fn source_ranges(instrs: &[Instruction], code: Span) -> HashMap<*const Instruction, Span> {
+    let mut ranges = HashMap::new();
+    collect_source_ranges(instrs, code, &mut ranges);
+    ranges
+}
+
+fn collect_source_ranges(instrs: &[Instruction], outer: Span, ranges: &mut HashMap<*const Instruction, Span>) {
+    let mut start = outer.start;
+    for (i, inst) in instrs.iter().enumerate() {
+        let range = match inst.span() {
+            Some(span) => span,
+            None => {
+                let end = instrs[i..].iter()
+                    .filter_map(Instruction::span)
+                    .map(|span| span.start)
+                    .next()
+                    .unwrap_or(outer.end);
+                Span{ start, end }
+            }
+        };
+        ranges.insert(inst as *const _, range);
+
+        if let Instruction::Loop(body, span) = inst {
+            let inner = match span {
+                Some(span) => Span{ start: span.start + 1, end: span.end - 1 },
+                None => range
+            };
+            collect_source_ranges(body, inner, ranges);
+        }
+        if inst.span().is_some() {
+            start = range.end;
+        }
+    }
+}
+
+/// What the passes guarantee about the instructions they produce, see `verify`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Invariant {
+    // `Nop`s are removed
+    NoNop,
+    // the loop cell is not among the factors of a linear loop, it is cleared
+    NoSelfFactor,
+    NonZeroAdd,
+    // added values are in the range `normalize` brings them into
+    NormalizedAdd,
+    // loops have a body, the ones doing nothing like `[]` move the pointer by 0
+    NonEmptyLoop,
+    // offsets and pointer moves of programs trusted to stay on the tape are at
+    // most the memory size, the other layouts wrap them around or grow it
+    OffsetInRange,
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Invariant::NoNop => "Nop left over",
+            Invariant::NoSelfFactor => "factor for the loop cell",
+            Invariant::NonZeroAdd => "adds 0",
+            Invariant::NormalizedAdd => "added value not normalized to the cell size",
+            Invariant::NonEmptyLoop => "loop without a body",
+            Invariant::OffsetInRange => "offset out of range",
+        };
+        f.write_str(text)
+    }
+}
+
+/// An instruction breaking an invariant
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub invariant: Invariant,
+    // the first line the instruction prints as
+    pub instruction: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} in `{}`", self.invariant, self.instruction)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Brings a value added to a cell into the range `[-m/2, m/2)` for a cell
+/// modulo `m`, values of 64 bit and int cells and of cells without a
+/// modulus are left as they are
+pub fn normalize(value: i64, cell_size: &CellSize) -> i64 {
+    let modulus: i128 = match cell_size {
+        CellSize::Bits(n) if *n < 64 => 1 << n,
+        CellSize::Modular(n) if *n > 0 => *n as i128,
+        CellSize::Bits(_) | CellSize::Modular(_) | CellSize::Int => return value,
+    };
+    let half = modulus / 2;
+    ((value as i128 + half).rem_euclid(modulus) - half) as i64
+}
+
+/// Checks the invariants of the instructions, returning the first violation
+pub fn verify(instrs: &[Instruction], opts: &Options) -> Result<(), Violation> {
+    use self::Instruction::*;
+    for inst in instrs {
+        let violation = |invariant| Violation {
+            invariant,
+            instruction: inst.to_string().lines().next().unwrap_or("").trim_end_matches(" {").to_string(),
+        };
+        let offsets: Vec<i64> = match inst {
+            Nop => return Err(violation(Invariant::NoNop)),
             Add{ offset, value } => {
-                if *offset == 0 {
-                    if *value == 1 {
-                        "Inc".to_string()
-                    }
-                    else if *value == -1 {
-                        "Dec".to_string()
-                    }
-                    else {
-                        format!("Add(@{}, {})", offset, value)
-                    }
-                } 
-                else {
-                    format!("Add(@{}, {})", offset, value)
+                if *value == 0 {
+                    return Err(violation(Invariant::NonZeroAdd));
                 }
+                if normalize(*value, &opts.cell_size) != *value {
+                    return Err(violation(Invariant::NormalizedAdd));
+                }
+                vec![*offset]
             },
-            Set{ offset, value } => format!("Set(@{}, {})", offset, value),
-            LinearLoop{ offset: _offset, factors: _factors } => {
-                "LinearLoop".to_string()
+            LinearLoop{ offset, factors, .. } => {
+                if factors.contains_key(&0) {
+                    return Err(violation(Invariant::NoSelfFactor));
+                }
+                std::iter::once(*offset).chain(factors.keys().map(|off| offset + off)).collect()
             },
-            MovePtr(val) => format!("MovePtr({})", val),
-            Loop(instrs) => {
-                let mut ret = "[\n".to_string();
-                for instr in instrs {
-                    ret += &instr.to_string();
-                    ret += "\n";
+            Loop(body, _) => {
+                if body.is_empty() {
+                    return Err(violation(Invariant::NonEmptyLoop));
                 }
-                ret += "]\n";
-                ret
+                verify(body, opts)?;
+                vec![]
             },
-            Read(offset) => format!("Read(@{})", offset),
-            Write(offset) => format!("Read(@{})", offset),
+            Set{ offset, .. } | MovePtr(offset) | Read(offset) | Write(offset) => vec![*offset],
+            Debug => vec![],
+        };
+        if opts.cell_layout == CellLayout::Trusting && offsets.iter().any(|offset| offset.unsigned_abs() > opts.memory_size as u64) {
+            return Err(violation(Invariant::OffsetInRange));
+        }
+    }
+    Ok(())
+}
+
+/// Panics if a pass broke an invariant, only in debug builds
+pub fn debug_verify(pass: &str, instrs: &[Instruction], opts: &Options) {
+    if cfg!(debug_assertions) {
+        if let Err(violation) = verify(instrs, opts) {
+            panic!("the {} pass broke an invariant: {}", pass, violation);
         }
     }
 }
@@ -93,7 +353,7 @@ pub trait MutVisitor {
     }
 
     fn visit_loop(&mut self, l: &mut Instruction) -> Self::Ret {
-        if let Instruction::Loop(instrs) = l {
+        if let Instruction::Loop(instrs, _) = l {
             self.visit_instructions(instrs);
         }
         Self::Ret::default()
@@ -107,17 +367,22 @@ pub trait MutVisitor {
         Self::Ret::default()
     }
 
+    fn visit_debug(&mut self, debug: &mut Instruction) -> Self::Ret {
+        Self::Ret::default()
+    }
+
     fn walk_instruction(&mut self, inst: &mut Instruction) -> Self::Ret {
         use self::Instruction::*;
         match inst {
             Nop => self.visit_nop(inst),
             Add { offset: _, value: _ } => self.visit_add(inst),
             Set { offset: _, value: _ } => self.visit_set(inst),
-            LinearLoop { offset: _, factors: _ } => self.visit_linear_loop(inst),
+            LinearLoop { .. } => self.visit_linear_loop(inst),
             MovePtr(_) => self.visit_move_ptr(inst),
-            Loop(_) => self.visit_loop(inst),
+            Loop(_, _) => self.visit_loop(inst),
             Read(_) => self.visit_read(inst),
             Write(_) => self.visit_write(inst),
+            Debug => self.visit_debug(inst),
         }
     }
 }
@@ -126,12 +391,20 @@ pub trait MutVisitor {
 pub trait ConstVisitor {
     type Ret: Default;
 
-    fn visit_instructions(&mut self, instr: &Vec<Instruction>) {
+    fn visit_instructions(&mut self, instr: &[Instruction]) {
         for inst in instr {
+            self.before(inst);
             self.walk_instruction(inst);
+            self.after(inst);
         }
     }
 
+    /// Called by `visit_instructions` before an instruction is visited
+    fn before(&mut self, inst: &Instruction) {}
+
+    /// Called by `visit_instructions` after an instruction is visited
+    fn after(&mut self, inst: &Instruction) {}
+
     fn visit_nop(&mut self, nop: &Instruction) -> Self::Ret {
         Self::Ret::default()
     }
@@ -153,7 +426,7 @@ pub trait ConstVisitor {
     }
 
     fn visit_loop(&mut self, l: &Instruction) -> Self::Ret {
-        if let Instruction::Loop(instrs) = l {
+        if let Instruction::Loop(instrs, _) = l {
             self.visit_instructions(instrs);
         }
         Self::Ret::default()
@@ -167,17 +440,22 @@ pub trait ConstVisitor {
         Self::Ret::default()
     }
 
+    fn visit_debug(&mut self, debug: &Instruction) -> Self::Ret {
+        Self::Ret::default()
+    }
+
     fn walk_instruction(&mut self, inst: &Instruction) -> Self::Ret {
         use self::Instruction::*;
         match inst {
             Nop => self.visit_nop(inst),
             Add {offset: _, value: _} => self.visit_add(inst),
             Set {offset: _, value: _} => self.visit_set(inst),
-            LinearLoop { offset: _, factors: _ } => self.visit_linear_loop(inst),
+            LinearLoop { .. } => self.visit_linear_loop(inst),
             MovePtr(_) => self.visit_move_ptr(inst),
-            Loop(_) => self.visit_loop(inst),
+            Loop(_, _) => self.visit_loop(inst),
             Read(_) => self.visit_read(inst),
             Write(_) => self.visit_write(inst),
+            Debug => self.visit_debug(inst),
         }
     }
 }
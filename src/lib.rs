@@ -0,0 +1,24 @@
+//#![feature(plugin)]
+//#![plugin(dynasm)]
+//#![feature(proc_macro_hygiene)]
+#[macro_use]
+extern crate dynasm;
+
+//extern crate winapi;
+extern crate typed_arena;
+
+pub mod options;
+pub mod ir;
+pub mod parser;
+pub mod interpret;
+pub mod optimize;
+pub mod analysis;
+pub mod compile;
+pub mod formatter;
+pub mod trans;
+pub mod debugger;
+pub mod profile;
+pub mod trace;
+pub mod generate;
+pub mod golden;
+pub mod bench;
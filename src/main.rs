@@ -1,32 +1,16 @@
-//#![feature(plugin)]
-//#![plugin(dynasm)]
-//#![feature(proc_macro_hygiene)]
-#[macro_use]
-extern crate dynasm;
-
-//extern crate winapi;
-extern crate typed_arena;
-
-use std::io::{self, Read};
+use std::io::{self, Read, Write, BufWriter};
 use std::fs::File;
-use clap::{Arg, App};
+use std::path::Path;
+use clap::{Arg, App, ArgMatches, SubCommand};
 use std::str::FromStr;
 use std::process::exit;
 
-pub mod options;
-pub mod ir;
-pub mod parser;
-pub mod interpret;
-pub mod optimize;
-pub mod compile;
-pub mod formatter;
-pub mod trans;
-
-
-use crate::ir::MutVisitor;
+use zombie::{options, ir, parser, interpret, optimize, analysis, compile, trans, debugger, profile, trace, golden, bench};
+use zombie::trans::Backend;
 use typed_arena::Arena;
 
 fn main() -> io::Result<()> {
+    let targets: Vec<&str> = trans::backends().iter().map(|backend| backend.name()).collect();
     let matches = App::new("Zombie")
         .version("0.1.0")
         .author("Nicolas Winkler <nicolas.winkler@gmx.ch>")
@@ -34,6 +18,11 @@ fn main() -> io::Result<()> {
         .arg(Arg::with_name("input")
                 .takes_value(true)
                 .help("Input file"))
+        .arg(Arg::with_name("input format")
+                .long("input-format")
+                .takes_value(true)
+                .possible_values(&["bf", "zir"])
+                .help("language of the input file, zir is the textual zombie IR (default: bf)"))
         .arg(Arg::with_name("interpret")
                 .long("interpret")
                 .short("i")
@@ -42,54 +31,144 @@ fn main() -> io::Result<()> {
                 .long("transpile")
                 .short("t")
                 .takes_value(true)
+                .possible_values(&targets)
                 .help("Transpile to language"))
+        .arg(Arg::with_name("annotate")
+                .long("annotate")
+                .requires("transpile")
+                .help("comment the transpiled code with the brainfuck it comes from"))
+        .arg(Arg::with_name("source map")
+                .long("source-map")
+                .takes_value(true)
+                .requires("transpile")
+                .help("write the source range of the transpiled lines to a file (JSON lines)"))
+        .arg(Arg::with_name("emit")
+                .long("emit")
+                .takes_value(true)
+                .possible_values(&["wasm", "ir"])
+                .help("write the program compiled to a binary format, or the optimized IR with the known cells and pointer ranges, to stdout"))
         .arg(Arg::with_name("cell size")
                 .long("cell-size")
                 .short("c")
                 .takes_value(true)
+                .global(true)
                 .help("defines the cell size in bits"))
         .arg(Arg::with_name("cell modulus")
                 .long("cell-modulus")
                 .short("m")
                 .takes_value(true)
+                .global(true)
                 .help("defines the cell modulus"))
+        .arg(Arg::with_name("memory size")
+                .long("memory-size")
+                .takes_value(true)
+                .global(true)
+                .help("number of cells on the tape, or auto for the cells the program can reach (default: 65536)"))
+        .arg(Arg::with_name("cell layout")
+                .long("cell-layout")
+                .takes_value(true)
+                .global(true)
+                .possible_values(&["trusting", "wrapping", "unbounded"])
+                .help("whether the tape is bounds checked unless the program provably stays on it, wraps around or grows (default: trusting)"))
+        .arg(Arg::with_name("debug instruction")
+                .long("debug-instruction")
+                .short("d")
+                .global(true)
+                .help("parse '#' as an instruction dumping the tape to stderr"))
+        .arg(Arg::with_name("inline input")
+                .long("inline-input")
+                .short("x")
+                .global(true)
+                .help("use everything after the first '!' in the source as input"))
+        .arg(Arg::with_name("eof")
+                .long("eof")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .global(true)
+                .possible_values(&["0", "zero", "-1", "minus-one", "unchanged"])
+                .help("what reading stores in the cell at the end of the input (default: 0)"))
                 
         .arg(Arg::with_name("optimize")
                 .long("optimize")
                 .short("O")
                 .takes_value(true)
                 .help("defines the cell modulus"))
+        .arg(Arg::with_name("profile")
+                .long("profile")
+                .help("count executions of loops and writes and print the hottest ones"))
+        .arg(Arg::with_name("trace")
+                .long("trace")
+                .takes_value(true)
+                .help("run in the interpreter and write every executed instruction to a file (JSON lines)"))
+        .arg(Arg::with_name("trace range")
+                .long("trace-range")
+                .takes_value(true)
+                .requires("trace")
+                .help("only trace code in the range <from>..<to> (line:col or byte offsets)"))
+        .arg(Arg::with_name("trace limit")
+                .long("trace-limit")
+                .takes_value(true)
+                .requires("trace")
+                .help("maximum number of instructions traced"))
+        .subcommand(SubCommand::with_name("debug")
+                .about("Step through a program in the interpreter")
+                .arg(Arg::with_name("input")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Input file"))
+                .arg(Arg::with_name("program input")
+                        .long("program-input")
+                        .short("p")
+                        .takes_value(true)
+                        .help("file the program reads its input from")))
+        .subcommand(SubCommand::with_name("test")
+                .about("Run all programs in a directory and compare their output with the .out files")
+                .arg(Arg::with_name("dir")
+                        .takes_value(true)
+                        .required(true)
+                        .help("directory containing <name>.bf, <name>.out and optionally <name>.in files"))
+                .arg(Arg::with_name("interpret")
+                        .long("interpret")
+                        .short("i")
+                        .help("run the tests in the interpreter instead of the JIT")))
+        .subcommand(SubCommand::with_name("bench")
+                .about("Measure how long parsing, optimizing, compiling and running programs takes")
+                .arg(Arg::with_name("programs")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("programs to run, reading <name>.in as input if present (default: examples/mandel.bf)"))
+                .arg(Arg::with_name("runs")
+                        .long("runs")
+                        .short("n")
+                        .takes_value(true)
+                        .help("number of runs per program, backend and optimizer passes (default: 10)"))
+                .arg(Arg::with_name("backends")
+                        .long("backend")
+                        .short("b")
+                        .takes_value(true)
+                        .help("comma separated list of backends: interpret, jit (default: jit)"))
+                .arg(Arg::with_name("passes")
+                        .long("passes")
+                        .short("p")
+                        .takes_value(true)
+                        .help("comma separated list of optimizer passes to run: none, linear (default: none,linear)"))
+                .arg(Arg::with_name("json")
+                        .long("json")
+                        .help("print the results as JSON")))
         .get_matches();
-    
-    let mut buffer = String::new();
-    if let Some(input) = matches.value_of("input") {
-        File::open(&input)?.read_to_string(&mut buffer)?;
-    }
-    else {
-        io::stdin().read_to_string(&mut buffer)?;
-    }
 
-    let mut options = options::Options::default();
-
-    if let Some(cell_size) = matches.value_of("cell size") {
-        match options::CellSize::from_str(cell_size) {
-            Ok(cs) => options.cell_size = cs,
-            Err(_e) => {
-                eprintln!("invalid cell size '{}'", cell_size);
-                exit(1);
-            }
-        }
+    if let Some(debug_matches) = matches.subcommand_matches("debug") {
+        return debug(debug_matches);
     }
-    else if let Some(cell_modulus) = matches.value_of("cell modulus") {
-        match u64::from_str(cell_modulus) {
-            Ok(cs) => options.cell_size = options::CellSize::Modular(cs),
-            Err(_e) => {
-                eprintln!("invalid cell modulus '{}'", cell_modulus);
-                exit(1);
-            }
-        }
+    if let Some(test_matches) = matches.subcommand_matches("test") {
+        return golden_test(test_matches);
     }
-
+    if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        return bench(bench_matches);
+    }
+    
+    let buffer = read_source(matches.value_of("input"))?;
+    let mut options = read_options(&matches);
 
     let opt_lvl: u64 = if let Some(opt) = matches.value_of("optimize") {
         match u64::from_str(opt) {
@@ -104,15 +183,42 @@ fn main() -> io::Result<()> {
         0
     };
 
-    let insts = parser::parse(&buffer);
+    let (code, inline_input) = parser::split_inline_input(&buffer, &options);
+    let stdin = io::stdin();
+    let mut input: Box<dyn Read> = match inline_input {
+        Some(text) => Box::new(text.as_bytes()),
+        None => Box::new(stdin.lock()),
+    };
+
+    // the IR is run as written, without optimizing it again
+    let insts = match matches.value_of("input format") {
+        Some("zir") => trans::zombie_ir::parse_verified(code, &options).map_err(|err| err.to_string()),
+        _ => parser::parse(code, &options).map(|insts| optimize::lin_optimize(insts, &options)).map_err(str::to_string),
+    };
     if let Ok(mut insts) = insts {
-        let mut lin_loop_optimizer = optimize::LinOptimizer::new();
-        lin_loop_optimizer.visit_instructions(&mut insts);
-        let _ = std::mem::replace(&mut insts, lin_loop_optimizer.instructions);
-        
+        let ranges = analysis::PointerRanges::analyze(&insts);
+        fit_memory_size(&matches, &mut options, &ranges);
 
-        if matches.is_present("interpret") {
-            interpret::run(&insts, &options);
+        if let Some(trace_file) = matches.value_of("trace") {
+            let filter = read_trace_filter(&matches, code);
+            let out = BufWriter::new(File::create(trace_file)?);
+            trace::run(&insts, code, &options, &mut input, &mut io::stdout(), &filter, out)?;
+        }
+        else if matches.is_present("profile") && !matches.is_present("transpile") {
+            let profile = if matches.is_present("interpret") {
+                profile::run(&insts, &options, &mut input, &mut io::stdout())
+            }
+            else {
+                if let Err(err) = compile::supports(&options) {
+                    eprintln!("cannot compile: {}", err);
+                    exit(1)
+                }
+                compile::compile_and_profile(&insts, &options, &mut input, &mut io::stdout())
+            };
+            profile::print_report(&profile, &insts, code);
+        }
+        else if matches.is_present("interpret") {
+            interpret::run(&insts, &options, &mut input, &mut io::stdout());
         }
         else {
             //for ref inst in &insts {
@@ -121,43 +227,309 @@ fn main() -> io::Result<()> {
             //println!("{}", trans::java::transpile(&insts));
 
 
+            if matches.value_of("emit") == Some("ir") {
+                let known = analysis::KnownCells::analyze(&insts, &options);
+                println!("// cells {}", ranges.cells());
+                print!("{}", ir::print(&insts, true, &(known, ranges)));
+                return Ok(());
+            }
+
             if opt_lvl == 1 {
                 let arena = Arena::new();
                 let dfg = optimize::create_dfg(&mut insts, &arena);
-                let c = trans::c::transpile_dfg(&dfg);
+                if let Err(err) = trans::c::supports_dfg(&options) {
+                    eprintln!("cannot transpile to c: {}", err);
+                    exit(1)
+                }
+                let c = trans::c::transpile_dfg(&options, &dfg);
                 println!("{}", c);
                 exit(0);
             }
             
-            
+            if matches.value_of("emit") == Some("wasm") {
+                if let Err(err) = trans::wasm::WatBackend.supports(&options) {
+                    eprintln!("cannot emit wasm: {}", err);
+                    exit(1)
+                }
+                io::stdout().write_all(&trans::wasm::assemble(&options, &insts))?;
+                return Ok(());
+            }
+
             match matches.value_of("transpile") {
-                Some(lang) => {
-                    //let arena = Arena::new();
-                    //let dfg = optimize::create_dfg(&mut insts, &arena);
-                    let code = if lang == "c" {
-                        //trans::c::transpile_dfg(&dfg)
-                        trans::c::transpile(&options, &insts)
-                    } else if lang == "java" {
-                        trans::java::transpile(&options, &insts)
-                    } else if lang == "python" {
-                        trans::python::transpile(&options, &insts)
-                    } else if lang == "zombie_ir" {
-                        trans::zombie_ir::transpile(&insts)
-                    } else {
-                        eprintln!("invalid transpiler lang '{}'", lang);
-                        "".to_owned()
+                Some(name) => {
+                    // the name is one of the possible values
+                    let backend = trans::find(name).unwrap();
+                    let annotate = matches.is_present("annotate");
+                    let map_file = matches.value_of("source map");
+                    if annotate && backend.comment().is_none() {
+                        eprintln!("cannot annotate {} code, it has no comments", name);
+                        exit(1)
+                    }
+                    // the spans in the IR point into the brainfuck it was generated from
+                    if (annotate || map_file.is_some()) && matches.value_of("input format") == Some("zir") {
+                        eprintln!("cannot map transpiled IR to its source");
+                        exit(1)
+                    }
+                    let transpiled = if annotate || map_file.is_some() {
+                        backend.emit_annotated(&options, &insts, code, annotate)
+                    }
+                    else {
+                        backend.emit(&options, &insts).map(|transpiled| (transpiled, Vec::new()))
                     };
-                    println!("{}", code);
+                    match transpiled {
+                        Ok((transpiled, line_map)) => {
+                            if let Some(map_file) = map_file {
+                                let mut out = BufWriter::new(File::create(map_file)?);
+                                for mapping in &line_map {
+                                    writeln!(out, "{}", mapping.to_json())?;
+                                }
+                                out.flush()?;
+                            }
+                            println!("{}", transpiled);
+                        },
+                        Err(err) => {
+                            eprintln!("cannot transpile to {}: {}", name, err);
+                            exit(1)
+                        }
+                    }
                 },
                 None => {
-                    let _code = compile::compile_and_run(&insts, &options);
+                    if let Err(err) = compile::supports(&options) {
+                        eprintln!("cannot compile: {}", err);
+                        exit(1)
+                    }
+                    if let Err(err) = compile::compile_and_run(&insts, &options, &mut input, &mut io::stdout()) {
+                        eprintln!("{}", err);
+                        exit(1)
+                    }
                 }
             }
         }
     }
     else if let Err(msg) = insts {
-        println!("error parsing: {}", msg);
+        eprintln!("error parsing: {}", msg);
+        exit(1)
     }
 
     Ok(())
 }
+
+fn debug(matches: &ArgMatches) -> io::Result<()> {
+    let buffer = read_source(matches.value_of("input"))?;
+    let mut options = read_options(matches);
+
+    let (code, inline_input) = parser::split_inline_input(&buffer, &options);
+    let mut input: Box<dyn Read> = match (inline_input, matches.value_of("program input")) {
+        (Some(text), _) => Box::new(text.as_bytes()),
+        (None, Some(file)) => Box::new(File::open(file)?),
+        (None, None) => Box::new(io::empty()),
+    };
+
+    match parser::parse(code, &options) {
+        Ok(insts) => {
+            let insts = optimize::lin_optimize(insts, &options);
+            fit_memory_size(matches, &mut options, &analysis::PointerRanges::analyze(&insts));
+            debugger::run(code, &insts, &options, &mut input)
+        },
+        Err(msg) => {
+            eprintln!("error parsing: {}", msg);
+            exit(1)
+        },
+    }
+    Ok(())
+}
+
+fn golden_test(matches: &ArgMatches) -> io::Result<()> {
+    reject_auto_memory_size(matches, "test");
+    let options = read_options(matches);
+    let backend = if matches.is_present("interpret") {
+        golden::Backend::Interpreter
+    }
+    else {
+        golden::Backend::Jit
+    };
+    let dir = matches.value_of("dir").unwrap_or(".");
+    let summary = golden::run(Path::new(dir), backend, &options)?;
+    if summary.failed > 0 {
+        exit(1);
+    }
+    Ok(())
+}
+
+fn bench(matches: &ArgMatches) -> io::Result<()> {
+    reject_auto_memory_size(matches, "bench");
+    let options = read_options(matches);
+    let programs: Vec<&str> = match matches.values_of("programs") {
+        Some(programs) => programs.collect(),
+        None => vec!["examples/mandel.bf"],
+    };
+    let runs = match usize::from_str(matches.value_of("runs").unwrap_or("10")) {
+        Ok(n) if n > 0 => n,
+        _ => {
+            eprintln!("invalid number of runs '{}'", matches.value_of("runs").unwrap_or_default());
+            exit(1);
+        }
+    };
+    let backends: Vec<golden::Backend> = matches.value_of("backends").unwrap_or("jit").split(',').map(|name| {
+        match name {
+            "interpret" => golden::Backend::Interpreter,
+            "jit" => golden::Backend::Jit,
+            _ => {
+                eprintln!("invalid backend '{}'", name);
+                exit(1);
+            }
+        }
+    }).collect();
+    let passes: Vec<bench::Passes> = matches.value_of("passes").unwrap_or("none,linear").split(',').map(|name| {
+        match name {
+            "none" => bench::Passes::None,
+            "linear" => bench::Passes::Linear,
+            _ => {
+                eprintln!("invalid optimizer passes '{}'", name);
+                exit(1);
+            }
+        }
+    }).collect();
+
+    let mut measurements = Vec::new();
+    for program in programs {
+        let code = read_source(Some(program))?;
+        let input = std::fs::read(Path::new(program).with_extension("in")).unwrap_or_default();
+        for &backend in &backends {
+            for &passes in &passes {
+                match bench::measure(program, &code, &input, &options, backend, passes, runs) {
+                    Ok(measurement) => measurements.push(measurement),
+                    Err(msg) => {
+                        eprintln!("{}", msg);
+                        exit(1);
+                    }
+                }
+            }
+        }
+    }
+
+    if matches.is_present("json") {
+        println!("{}", bench::to_json(&measurements));
+    }
+    else {
+        bench::print_report(&measurements);
+    }
+    Ok(())
+}
+
+/// Fits the tape to the cells the program can reach for `--memory-size auto`
+fn fit_memory_size(matches: &ArgMatches, options: &mut options::Options, ranges: &analysis::PointerRanges) {
+    if matches.value_of("memory size") == Some("auto") {
+        match ranges.memory_size(&options.cell_layout) {
+            Some(size) => options.memory_size = size,
+            None => eprintln!("the cells {} the program can reach are unbounded, using {} cells", ranges.cells(), options.memory_size),
+        }
+    }
+}
+
+/// Stops subcommands running several programs, which cannot share a tape
+/// fitted to one of them
+fn reject_auto_memory_size(matches: &ArgMatches, command: &str) {
+    if matches.value_of("memory size") == Some("auto") {
+        eprintln!("--memory-size auto fits the tape to a single program, zombie {} runs several", command);
+        exit(1);
+    }
+}
+
+fn read_trace_filter(matches: &ArgMatches, code: &str) -> trace::TraceFilter {
+    let range = matches.value_of("trace range").map(|range| {
+        let bounds = range.find("..").and_then(|dots| {
+            let start = parser::parse_position(code, &range[..dots])?;
+            let end = parser::parse_position(code, &range[(dots + 2)..])?;
+            Some(ir::Span{ start, end })
+        });
+        match bounds {
+            Some(span) => span,
+            None => {
+                eprintln!("invalid trace range '{}'", range);
+                exit(1);
+            }
+        }
+    });
+    let limit = matches.value_of("trace limit").map(|limit| {
+        match u64::from_str(limit) {
+            Ok(l) => l,
+            Err(_e) => {
+                eprintln!("invalid trace limit '{}'", limit);
+                exit(1);
+            }
+        }
+    });
+    trace::TraceFilter{ range, limit }
+}
+
+fn read_source(file: Option<&str>) -> io::Result<String> {
+    let mut buffer = String::new();
+    if let Some(input) = file {
+        File::open(input)?.read_to_string(&mut buffer)?;
+    }
+    else {
+        io::stdin().read_to_string(&mut buffer)?;
+    }
+    Ok(buffer)
+}
+
+fn read_options(matches: &ArgMatches) -> options::Options {
+    let mut options = options::Options::default();
+
+    if let Some(cell_size) = matches.value_of("cell size") {
+        match options::CellSize::from_str(cell_size) {
+            Ok(cs) if cs != options::CellSize::Bits(0) => options.cell_size = cs,
+            _ => {
+                eprintln!("invalid cell size '{}'", cell_size);
+                exit(1);
+            }
+        }
+    }
+    else if let Some(cell_modulus) = matches.value_of("cell modulus") {
+        match u64::from_str(cell_modulus) {
+            Ok(cs) if cs > 0 => options.cell_size = options::CellSize::Modular(cs),
+            _ => {
+                eprintln!("invalid cell modulus '{}'", cell_modulus);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(memory_size) = matches.value_of("memory size") {
+        match usize::from_str(memory_size) {
+            Ok(size) if size > 0 => options.memory_size = size,
+            // chosen once the program is parsed
+            _ if memory_size == "auto" => {},
+            _ => {
+                eprintln!("invalid memory size '{}'", memory_size);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(cell_layout) = matches.value_of("cell layout") {
+        match options::CellLayout::from_str(cell_layout) {
+            Ok(layout) => options.cell_layout = layout,
+            Err(_e) => {
+                eprintln!("invalid cell layout '{}'", cell_layout);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(eof) = matches.value_of("eof") {
+        match options::EofMode::from_str(eof) {
+            Ok(mode) => options.eof_mode = mode,
+            Err(_e) => {
+                eprintln!("invalid eof mode '{}'", eof);
+                exit(1);
+            }
+        }
+    }
+
+    options.debug_instruction = matches.is_present("debug instruction");
+    options.inline_input = matches.is_present("inline input");
+
+    options
+}
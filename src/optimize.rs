@@ -2,6 +2,7 @@ use std::collections::BTreeMap;
 use super::{ir};
 use super::ir::Instruction;
 use super::ir::MutVisitor;
+use super::options::Options;
 use typed_arena::Arena;
 
 pub fn create_dfg<'a>(instrs: &mut Vec<ir::Instruction>, arena: &'a Arena<DfgNode<'a>>) -> DfgOptimizer<'a> {
@@ -16,6 +17,7 @@ pub struct DfgOptimizer<'a> {
     pub cfg: Vec<DfInstr<'a>>,
 }
 
+#[allow(dead_code)]
 pub struct BasicBlock<'a> {
     arena: &'a Arena<DfgNode<'a>>,
     pub cell_states: BTreeMap<i64, &'a DfgNode<'a>>,
@@ -35,6 +37,7 @@ pub enum DfInstr<'a> {
     WriteMem(i64, &'a DfgNode<'a>),
     MovePtr(i64),
     Loop(i64, Vec<DfInstr<'a>>),
+    Debug,
 }
 
 
@@ -42,7 +45,7 @@ impl<'a> DfgOptimizer<'a> {
 
     fn new(arena: &'a Arena<DfgNode<'a>>) -> Self {
         DfgOptimizer {
-            arena: arena,
+            arena,
             cell_states: BTreeMap::new(),
             cfg: Vec::new()
         }
@@ -100,7 +103,7 @@ impl<'a> ir::MutVisitor for DfgOptimizer<'a> {
     }
 
     fn visit_linear_loop(&mut self, lloop: &mut Instruction) {
-        if let Instruction::LinearLoop{ offset, factors } = lloop {
+        if let Instruction::LinearLoop{ offset, factors, .. } = lloop {
             let multiplier = self.get_cell(*offset);
             for (off, fact) in factors {
                 if *fact == 1 {
@@ -129,13 +132,13 @@ impl<'a> ir::MutVisitor for DfgOptimizer<'a> {
     }
 
     fn visit_loop(&mut self, l: &mut Instruction) {
-        if let Instruction::Loop(instrs) = l {
+        if let Instruction::Loop(instrs, _) = l {
 
             for (off, cell) in &self.cell_states {
                 self.cfg.push(DfInstr::WriteMem(*off, cell))
             }
 
-            let mut optimizer = DfgOptimizer::new(&self.arena);
+            let mut optimizer = DfgOptimizer::new(self.arena);
             optimizer.visit_instructions(instrs);
             self.cfg.push(DfInstr::Loop(0, optimizer.cfg));
             self.cell_states.clear();
@@ -154,16 +157,27 @@ impl<'a> ir::MutVisitor for DfgOptimizer<'a> {
             self.cfg.push(DfInstr::Print(self.get_cell(*off)));
         }
     }
+
+    fn visit_debug(&mut self, _debug: &'_ mut Instruction) {
+        // the dump has to see the actual memory, so write back all cells
+        for (off, cell) in &self.cell_states {
+            self.cfg.push(DfInstr::WriteMem(*off, cell))
+        }
+        self.cfg.push(DfInstr::Debug);
+        self.cell_states.clear();
+    }
 }
 
 
 
 
+#[allow(dead_code)]
 struct MemoryState {
     cell_states: BTreeMap<i64, CellState>,
     default_cell: CellState
 }
 
+#[allow(dead_code)]
 enum CellState {
     Unknown,
     Const(i64),
@@ -191,6 +205,15 @@ enum CellState {
 
 
 
+
+/// Turns loops that only add to cells into linear loops, checking the result
+/// in debug builds
+pub fn lin_optimize(mut instrs: Vec<Instruction>, opts: &Options) -> Vec<Instruction> {
+    let mut lin_loop_optimizer = LinOptimizer::new();
+    lin_loop_optimizer.visit_instructions(&mut instrs);
+    ir::debug_verify("linear loop", &lin_loop_optimizer.instructions, opts);
+    lin_loop_optimizer.instructions
+}
 
 pub struct LinOptimizer {
     offset: i64,
@@ -206,6 +229,12 @@ impl LinOptimizer {
     }
 }
 
+impl Default for LinOptimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ir::MutVisitor for LinOptimizer {
     type Ret = Option<Instruction>;
 
@@ -242,7 +271,7 @@ impl ir::MutVisitor for LinOptimizer {
     }
 
     fn visit_loop(&mut self, l: &mut Instruction) -> Self::Ret {
-        if let Instruction::Loop(instrs) = l {
+        if let Instruction::Loop(instrs, span) = l {
             let mut increments: BTreeMap<i64, i64> = BTreeMap::new();
             let mut dirty = false;
 
@@ -277,26 +306,28 @@ impl ir::MutVisitor for LinOptimizer {
             }
             std::mem::swap(&mut self.instructions, &mut swap);
 
-            if !dirty && increments.len() == 1 {
+            // an even step like [--] skips zero on odd cells, and a loop like
+            // [>+<] never changes its counter, these stay loops
+            if !dirty && increments.len() == 1 && increments.get(&0).is_some_and(|v| v % 2 != 0) {
+                // cases like [-]
+                // also [---]
                 self.offset = offset_before;
-                if let Some(&v) = increments.get(&0) {
-                    if v % 2 != 0 {
-                        // cases like [-]
-                        // also [---]
-                        self.instructions.push(Instruction::Set{ offset: self.offset, value: 0 });
-                    }
-                }
+                self.instructions.push(Instruction::Set{ offset: self.offset, value: 0 });
             }
             else if !dirty && increments.get(&0) == Some(&-1) {
                 self.offset = offset_before;
                 increments.remove(&0);
-                self.instructions.push(Instruction::LinearLoop{ offset: self.offset, factors: increments });
+                self.instructions.push(Instruction::LinearLoop{ offset: self.offset, factors: increments, span: *span });
             }
             else {
                 if offset_before != 0 {
                     self.instructions.push(Instruction::MovePtr(offset_before));
                 }
-                self.instructions.push(Instruction::Loop(swap));
+                if swap.is_empty() {
+                    // the moves of a loop like [<>] cancel out, it still spins
+                    swap.push(Instruction::MovePtr(0));
+                }
+                self.instructions.push(Instruction::Loop(swap, *span));
             }
             // set cell at offset 0 to 0
         }
@@ -316,4 +347,14 @@ impl ir::MutVisitor for LinOptimizer {
         }
         None
     }
+
+    fn visit_debug(&mut self, _debug: &'_ mut Instruction) -> Self::Ret {
+        // the dump shows the pointer, so it has to be up to date here
+        if self.offset != 0 {
+            self.instructions.push(Instruction::MovePtr(self.offset));
+            self.offset = 0;
+        }
+        self.instructions.push(Instruction::Debug);
+        None
+    }
 }
@@ -1,13 +1,16 @@
 use std::str::FromStr;
 
-#[derive(PartialEq, Clone)]
+/// What happens to a pointer leaving the tape
+#[derive(PartialEq, Clone, Debug)]
 pub enum CellLayout {
+    // the program is trusted to stay on the tape, it is bounds checked
+    // unless the pointer range analysis proves that it does
     Trusting,
     Wrapping,
     Unbounded
 }
 
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum CellSize {
     Bits(usize),
     Modular(u64),
@@ -15,11 +18,28 @@ pub enum CellSize {
 }
 
 
-#[derive(PartialEq, Clone)]
+/// What a read stores in the cell once the input is exhausted
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum EofMode {
+    Zero,
+    MinusOne,
+    Unchanged,
+}
+
+
+#[derive(PartialEq, Clone, Debug)]
 pub struct Options {
     pub cell_layout: CellLayout,
     pub memory_size: usize,
     pub cell_size: CellSize,
+    // parse '#' as a debug instruction instead of ignoring it
+    pub debug_instruction: bool,
+    // everything after the first '!' in the source is the program's input
+    pub inline_input: bool,
+    pub eof_mode: EofMode,
+    // transpilers mark the code of each instruction with its source range,
+    // see `trans::source_map`
+    pub source_marks: bool,
 }
 
 
@@ -29,6 +49,10 @@ impl Default for Options {
             cell_layout: CellLayout::Trusting,
             memory_size: 0x10000,
             cell_size: CellSize::Bits(8),
+            debug_instruction: false,
+            inline_input: false,
+            eof_mode: EofMode::Zero,
+            source_marks: false,
         }
     }
 }
@@ -40,11 +64,25 @@ impl FromStr for CellLayout {
         match s {
             "trusting" => Ok(CellLayout::Trusting),
             "wrapping" => Ok(CellLayout::Wrapping),
+            "unbounded" => Ok(CellLayout::Unbounded),
             _ => Err("invalid cell layout"),
         }
     }
 }
 
+impl FromStr for EofMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" | "zero" => Ok(EofMode::Zero),
+            "-1" | "minus-one" => Ok(EofMode::MinusOne),
+            "unchanged" => Ok(EofMode::Unchanged),
+            _ => Err("invalid eof mode"),
+        }
+    }
+}
+
 impl FromStr for CellSize {
     type Err = &'static str;
 
@@ -52,7 +90,7 @@ impl FromStr for CellSize {
         let integer = s.parse::<usize>();
         match integer {
             Ok(i) => Ok(CellSize::Bits(i)),
-            Err(e) => match s {
+            Err(_) => match s {
                 "8" => Ok(CellSize::Bits(8)),
                 "16" => Ok(CellSize::Bits(16)),
                 "32" => Ok(CellSize::Bits(16)),
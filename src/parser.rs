@@ -1,15 +1,73 @@
 use std::collections::BTreeMap;
+use std::str::FromStr;
 use super::ir;
+use super::options::Options;
 
-pub fn parse(code: &str) -> Result<Vec<ir::Instruction>, &str> {
+const COMMANDS: &str = "+-<>[],.#";
+
+/// Converts a byte position in the code to a 1-based line and column
+pub fn line_col(code: &str, pos: usize) -> (usize, usize) {
+    let before = &code[..pos.min(code.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.len() - before.rfind('\n').map(|p| p + 1).unwrap_or(0) + 1;
+    (line, col)
+}
+
+/// Parses a position given as `line:col` (1-based) or as a byte offset
+pub fn parse_position(code: &str, pos: &str) -> Option<usize> {
+    if let Some(colon) = pos.find(':') {
+        let line = usize::from_str(&pos[..colon]).ok()?;
+        let col = usize::from_str(&pos[(colon + 1)..]).ok()?;
+        if line == 0 || col == 0 {
+            return None;
+        }
+        let line_start = if line == 1 {
+            0
+        }
+        else {
+            code.match_indices('\n').nth(line - 2)?.0 + 1
+        };
+        Some(line_start + col - 1)
+    }
+    else {
+        usize::from_str(pos).ok()
+    }
+}
+
+/// Position of the first command in a span and the commands in it, stripped of
+/// comments and whitespace (at most `max` characters)
+pub fn commands_in(code: &str, span: ir::Span, max: usize) -> (usize, String) {
+    let source = &code[span.start.min(code.len())..span.end.min(code.len())];
+    let start = span.start + source.find(|c| COMMANDS.contains(c)).unwrap_or(0);
+    let commands = source.chars()
+        .filter(|c| COMMANDS.contains(*c))
+        .take(max)
+        .collect();
+    (start, commands)
+}
+
+/// Splits off the input following the first '!' if `inline_input` is enabled
+pub fn split_inline_input<'a>(code: &'a str, opts: &Options) -> (&'a str, Option<&'a str>) {
+    if opts.inline_input {
+        if let Some(pos) = code.find('!') {
+            return (&code[..pos], Some(&code[(pos + 1)..]));
+        }
+    }
+    (code, None)
+}
+
+pub fn parse<'a>(code: &str, opts: &Options) -> Result<Vec<ir::Instruction>, &'a str> {
     let mut ptr: i64 = 0;
     let mut add_map: BTreeMap<i64, i64> = BTreeMap::new();
-    let mut instruction_stack: Vec<Vec<ir::Instruction>> = Vec::new();
+    let mut instruction_stack: Vec<(Vec<ir::Instruction>, usize)> = Vec::new();
     let mut instructions: Vec<ir::Instruction> = Vec::new();
 
     let implement = |add_map: &mut BTreeMap<i64, i64>, instructions: &mut Vec<ir::Instruction>, ptr: &mut i64| {
         for (&offset, &value) in add_map.iter() {
-            instructions.push(ir::Instruction::Add{ value, offset });
+            let value = ir::normalize(value, &opts.cell_size);
+            if value != 0 {
+                instructions.push(ir::Instruction::Add{ value, offset });
+            }
         }
         add_map.clear();
         if *ptr != 0 {
@@ -18,7 +76,7 @@ pub fn parse(code: &str) -> Result<Vec<ir::Instruction>, &str> {
         }
     };
 
-    for c in code.chars() {
+    for (pos, c) in code.char_indices() {
         match c {
             '+' => {
                 match add_map.get_mut(&ptr) {
@@ -42,16 +100,29 @@ pub fn parse(code: &str) -> Result<Vec<ir::Instruction>, &str> {
                 implement(&mut add_map, &mut instructions, &mut ptr);
                 instructions.push(ir::Instruction::Read(ptr));
             },
+            '!' if opts.inline_input => {
+                // the rest is input data
+                break;
+            },
+            '#' if opts.debug_instruction => {
+                implement(&mut add_map, &mut instructions, &mut ptr);
+                instructions.push(ir::Instruction::Debug);
+            },
             '[' => {
                 implement(&mut add_map, &mut instructions, &mut ptr);
-                instruction_stack.push(instructions);
+                instruction_stack.push((instructions, pos));
                 instructions = Vec::new();
             },
             ']' => {
                 implement(&mut add_map, &mut instructions, &mut ptr);
+                if instructions.is_empty() {
+                    // loops like `[]` still spin on a cell that is not zero
+                    instructions.push(ir::Instruction::MovePtr(0));
+                }
                 let top = instruction_stack.pop();
-                if let Some(mut inst) = top {
-                    inst.push(ir::Instruction::Loop(instructions));
+                if let Some((mut inst, start)) = top {
+                    let span = ir::Span{ start, end: pos + 1 };
+                    inst.push(ir::Instruction::Loop(instructions, Some(span)));
                     instructions = inst;
                 }
                 else {
@@ -64,10 +135,12 @@ pub fn parse(code: &str) -> Result<Vec<ir::Instruction>, &str> {
         }
     }
 
-    if instruction_stack.len() > 0 {
+    if !instruction_stack.is_empty() {
         return Err("found '[' without matching ']'");
     }
+    implement(&mut add_map, &mut instructions, &mut ptr);
 
+    ir::debug_verify("parse", &instructions, opts);
     Ok(instructions)
 }
 
@@ -0,0 +1,126 @@
+use super::ir::{self, Instruction, Span};
+use super::interpret::{self, Monitor, Tape};
+use super::options::Options;
+use super::parser;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+// number of entries shown in the report
+const REPORT_LENGTH: usize = 20;
+
+/// How often a loop or write has been executed
+#[derive(Default, Clone)]
+pub struct Counter {
+    pub executions: u64,
+    // for linear loops, the iterations the original loop would have had
+    pub iterations: u64,
+    // time spent in the instruction including nested loops
+    pub time: Duration,
+}
+
+pub struct Profile {
+    pub counters: HashMap<*const Instruction, Counter>,
+    pub total_time: Duration,
+    // whether the time of the single instructions has been measured
+    pub timed: bool,
+}
+
+/// Whether the profiler keeps track of an instruction
+pub fn is_profiled(inst: &Instruction) -> bool {
+    matches!(inst, Instruction::Loop(_, _) | Instruction::LinearLoop{ .. } | Instruction::Write(_))
+}
+
+struct Profiler {
+    counters: HashMap<*const Instruction, Counter>,
+    // start times of the instructions currently executed
+    started: Vec<Instant>,
+}
+
+impl Monitor for Profiler {
+    fn before(&mut self, inst: &Instruction, tape: &dyn Tape) {
+        if is_profiled(inst) {
+            let counter = self.counters.entry(inst as *const _).or_default();
+            counter.executions += 1;
+            if let Instruction::LinearLoop{ offset, .. } = inst {
+                counter.iterations += tape.cell(tape.ptr() + offset);
+            }
+            self.started.push(Instant::now());
+        }
+    }
+
+    fn after(&mut self, inst: &Instruction, _tape: &dyn Tape) {
+        if is_profiled(inst) {
+            if let Some(start) = self.started.pop() {
+                self.counters.entry(inst as *const _).or_default().time += start.elapsed();
+            }
+        }
+    }
+
+    fn iteration(&mut self, inst: &Instruction, _tape: &dyn Tape) {
+        self.counters.entry(inst as *const _).or_default().iterations += 1;
+    }
+}
+
+/// Runs the program in the interpreter, counting and timing all loops and writes
+pub fn run(instrs: &Vec<Instruction>, opts: &Options, input: &mut dyn Read, output: &mut dyn Write) -> Profile {
+    let mut profiler = Profiler {
+        counters: HashMap::new(),
+        started: Vec::new(),
+    };
+    let start = Instant::now();
+    interpret::run_with_monitor(instrs, opts, input, output, &mut profiler);
+    Profile {
+        counters: profiler.counters,
+        total_time: start.elapsed(),
+        timed: true,
+    }
+}
+
+/// Prints the hottest loops and writes to stderr
+pub fn print_report(profile: &Profile, instrs: &[Instruction], code: &str) {
+    let ranges = ir::source_ranges(instrs, Span{ start: 0, end: code.len() });
+    let mut entries: Vec<(&Instruction, &Counter)> = Vec::new();
+    collect_entries(instrs, profile, &mut entries);
+
+    if profile.timed {
+        entries.sort_by_key(|&(_, counter)| std::cmp::Reverse(counter.time));
+    }
+    else {
+        entries.sort_by_key(|&(_, counter)| std::cmp::Reverse((counter.iterations, counter.executions)));
+    }
+
+    eprintln!("profile: {:.3}s total", profile.total_time.as_secs_f64());
+    eprintln!("{:>4}  {:<10}  {:>9}  {:>12}  {:>12}  {:>6}  source",
+              "rank", "kind", "location", "executions", "iterations", "time");
+    for (rank, (inst, counter)) in entries.iter().take(REPORT_LENGTH).enumerate() {
+        let kind = match inst {
+            Instruction::Loop(_, _) => "Loop",
+            Instruction::LinearLoop{ .. } => "LinearLoop",
+            _ => "Write",
+        };
+        let range = ranges.get(&(*inst as *const _)).copied().unwrap_or(Span{ start: 0, end: 0 });
+        let (start, snippet) = parser::commands_in(code, range, 40);
+        let (line, col) = parser::line_col(code, start);
+        let share = if profile.timed && profile.total_time.as_nanos() > 0 {
+            format!("{:.1}%", 100.0 * counter.time.as_secs_f64() / profile.total_time.as_secs_f64())
+        }
+        else {
+            "-".to_string()
+        };
+        eprintln!("{:>4}  {:<10}  {:>9}  {:>12}  {:>12}  {:>6}  {}",
+                  rank + 1, kind, format!("{}:{}", line, col), counter.executions,
+                  counter.iterations, share, snippet);
+    }
+}
+
+fn collect_entries<'a>(instrs: &'a [Instruction], profile: &'a Profile, entries: &mut Vec<(&'a Instruction, &'a Counter)>) {
+    for inst in instrs {
+        if let Some(counter) = profile.counters.get(&(inst as *const _)) {
+            entries.push((inst, counter));
+        }
+        if let Instruction::Loop(body, _) = inst {
+            collect_entries(body, profile, entries);
+        }
+    }
+}
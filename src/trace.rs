@@ -0,0 +1,113 @@
+use super::ir::{self, Instruction, Span};
+use super::interpret::{self, Monitor, Tape};
+use super::options::Options;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+/// Which instructions end up in the trace
+pub struct TraceFilter {
+    // only instructions generated from code overlapping this range
+    pub range: Option<Span>,
+    // maximum number of records written
+    pub limit: Option<u64>,
+}
+
+struct Tracer<'a, W: Write> {
+    out: W,
+    filter: &'a TraceFilter,
+    ranges: HashMap<*const Instruction, Span>,
+    records: u64,
+    error: Option<io::Error>,
+}
+
+impl<'a, W: Write> Tracer<'a, W> {
+    fn is_traced(&self, inst: &Instruction) -> bool {
+        if self.error.is_some() || self.filter.limit.is_some_and(|limit| self.records >= limit) {
+            return false;
+        }
+        match (self.filter.range, self.ranges.get(&(inst as *const _))) {
+            (Some(filter), Some(range)) => range.start < filter.end && filter.start < range.end,
+            _ => true
+        }
+    }
+
+    fn record(&mut self, inst: &Instruction, tape: &dyn Tape) {
+        let ptr = tape.ptr();
+        let (kind, cells): (&str, Vec<i64>) = match inst {
+            Instruction::Nop => ("Nop", vec![]),
+            Instruction::Add{ offset, .. } => ("Add", vec![ptr + offset]),
+            Instruction::Set{ offset, .. } => ("Set", vec![ptr + offset]),
+            Instruction::LinearLoop{ offset, factors, .. } => {
+                let mut cells = vec![ptr + offset];
+                cells.extend(factors.keys().map(|off| ptr + offset + off));
+                ("LinearLoop", cells)
+            },
+            Instruction::MovePtr(_) => ("MovePtr", vec![]),
+            Instruction::Loop(_, _) => ("Loop", vec![ptr]),
+            Instruction::Read(offset) => ("Read", vec![ptr + offset]),
+            Instruction::Write(offset) => ("Write", vec![ptr + offset]),
+            Instruction::Debug => ("Debug", vec![]),
+        };
+        let cells: Vec<String> = cells.iter()
+            .map(|&cell| format!("[{},{}]", cell, tape.cell(cell)))
+            .collect();
+        let source = match self.ranges.get(&(inst as *const _)) {
+            Some(range) => format!("[{},{}]", range.start, range.end),
+            None => "null".to_string(),
+        };
+        let result = writeln!(self.out, "{{\"kind\":\"{}\",\"ptr\":{},\"cells\":[{}],\"source\":{}}}",
+                              kind, ptr, cells.join(","), source);
+        match result {
+            Ok(()) => self.records += 1,
+            Err(e) => self.error = Some(e),
+        }
+    }
+}
+
+impl<'a, W: Write> Monitor for Tracer<'a, W> {
+    fn before(&mut self, inst: &Instruction, tape: &dyn Tape) {
+        // loops are recorded with the cell they check on entry
+        if let Instruction::Loop(_, _) = inst {
+            if self.is_traced(inst) {
+                self.record(inst, tape);
+            }
+        }
+    }
+
+    fn after(&mut self, inst: &Instruction, tape: &dyn Tape) {
+        // everything else with the values after executing it
+        match inst {
+            Instruction::Loop(_, _) => {},
+            _ => if self.is_traced(inst) {
+                self.record(inst, tape);
+            }
+        }
+    }
+}
+
+/// Runs the program in the interpreter and writes one JSON object per executed
+/// instruction to `out`, e.g.
+///
+/// `{"kind":"Add","ptr":2,"cells":[[3,7]],"source":[10,14]}`
+///
+/// `cells` lists the index and value of every cell the instruction touches
+/// (after executing it, for loops on entry) and `source` the byte range of the
+/// code the instruction comes from.
+pub fn run<W: Write>(instrs: &Vec<Instruction>, code: &str, opts: &Options,
+                     input: &mut dyn Read, output: &mut dyn Write, filter: &TraceFilter, out: W) -> io::Result<u64> {
+    let mut tracer = Tracer {
+        out,
+        filter,
+        ranges: ir::source_ranges(instrs, Span{ start: 0, end: code.len() }),
+        records: 0,
+        error: None,
+    };
+    interpret::run_with_monitor(instrs, opts, input, output, &mut tracer);
+    match tracer.error {
+        Some(e) => Err(e),
+        None => {
+            tracer.out.flush()?;
+            Ok(tracer.records)
+        }
+    }
+}
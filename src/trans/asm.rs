@@ -0,0 +1,326 @@
+use super::super::{ir, formatter, options};
+use super::{Backend, BackendError, require_cell_layout, require_cell_size};
+use super::source_map::{add_mark, SourceMarks};
+
+use ir::{Instruction, ConstVisitor, DEBUG_WINDOW};
+use formatter::Formatter;
+use options::*;
+
+// bytes of output collected before they are written
+const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+struct AsmTranspiler {
+    pub code_buf: Formatter,
+    marks: SourceMarks,
+    next_label: usize,
+}
+
+/// x86-64 assembly for the GNU assembler in Intel syntax, with a `_start`
+/// using Linux syscalls, e.g. `as -o prog.o prog.s && ld -o prog prog.o`.
+/// Like the JIT, it only supports 8 bit cells and generates the same
+/// instructions with the tape pointer in rdi.
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = AsmTranspiler {
+        code_buf: Formatter::new(),
+        marks: SourceMarks::new(opts, instrs),
+        next_label: 0,
+    };
+    let memory_size = opts.memory_size.max(1);
+    // the pointer starts in the middle of the tape, so moving left does not
+    // leave it right away
+    let origin = memory_size / 2;
+
+    transpiler.code_buf.add_line(&format!(r#"    .intel_syntax noprefix
+    .equ MEMORY_SIZE, {memory_size}
+    .equ ORIGIN, {origin}
+    .equ OUTPUT_BUFFER_SIZE, {buffer_size}
+
+    .bss
+memory:
+    .zero MEMORY_SIZE
+output_buffer:
+    .zero OUTPUT_BUFFER_SIZE
+output_length:
+    .zero 8
+debug_buffer:
+    .zero 256
+
+    .text
+    .globl _start
+_start:
+    lea rdi, [rip + memory + ORIGIN]"#, memory_size = memory_size, origin = origin, buffer_size = OUTPUT_BUFFER_SIZE));
+    transpiler.code_buf.indent();
+    transpiler.visit_instructions(instrs);
+    transpiler.code_buf.add_line("call flush");
+    transpiler.code_buf.add_line("mov eax, 60");
+    transpiler.code_buf.add_line("xor edi, edi");
+    transpiler.code_buf.add_line("syscall");
+    transpiler.code_buf.unindent();
+    transpiler.code_buf.add_line("");
+    transpiler.code_buf.add_line(&runtime(opts.eof_mode));
+    transpiler.code_buf.get_code()
+}
+
+impl AsmTranspiler {
+    fn label(&mut self, name: &str) -> String {
+        self.next_label += 1;
+        format!(".L{}{}", name, self.next_label - 1)
+    }
+}
+
+impl ir::ConstVisitor for AsmTranspiler {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.enter(inst));
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.leave(inst));
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            self.code_buf.add_line(&format!("add BYTE PTR [rdi + {}], {}", *offset as i32, *value as i8));
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            self.code_buf.add_line(&format!("mov BYTE PTR [rdi + {}], {}", *offset as i32, *value as i8));
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            if !factors.is_empty() {
+                self.code_buf.add_line(&format!("movzx ecx, BYTE PTR [rdi + {}]", *glob_offset as i32));
+            }
+            for (&offset, &factor) in factors {
+                if offset == 0 || factor == 0 {
+                    continue;
+                }
+                self.code_buf.add_line(&format!("mov al, {}", factor as i8));
+                self.code_buf.add_line("mul cl");
+                self.code_buf.add_line(&format!("add BYTE PTR [rdi + {}], al", (glob_offset + offset) as i32));
+            }
+            self.code_buf.add_line(&format!("mov BYTE PTR [rdi + {}], 0", *glob_offset as i32));
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            self.code_buf.add_line(&format!("lea rdi, [rdi + {}]", *offset as i32));
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            let begin = self.label("begin");
+            let end = self.label("end");
+            self.code_buf.add_line("cmp BYTE PTR [rdi], 0");
+            self.code_buf.add_line(&format!("jz {}", end));
+            self.code_buf.unindent();
+            self.code_buf.add_line(&format!("{}:", begin));
+            self.code_buf.indent();
+            self.visit_instructions(insts);
+            self.code_buf.add_line("cmp BYTE PTR [rdi], 0");
+            self.code_buf.add_line(&format!("jnz {}", begin));
+            self.code_buf.unindent();
+            self.code_buf.add_line(&format!("{}:", end));
+            self.code_buf.indent();
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            self.code_buf.add_line(&format!("movzx esi, BYTE PTR [rdi + {}]", *offset as i32));
+            self.code_buf.add_line("call readbyte");
+            self.code_buf.add_line(&format!("mov BYTE PTR [rdi + {}], al", *offset as i32));
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            self.code_buf.add_line(&format!("mov al, BYTE PTR [rdi + {}]", *offset as i32));
+            self.code_buf.add_line("call putbyte");
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        self.code_buf.add_line("call debug_dump");
+    }
+}
+
+/// The subroutines called by the generated code, all of them keep rdi
+fn runtime(eof_mode: EofMode) -> String {
+    let eof_value = match eof_mode {
+        EofMode::Zero => "xor r8d, r8d",
+        EofMode::MinusOne => "mov r8d, 255",
+        EofMode::Unchanged => "mov r8d, esi",
+    };
+    format!(r#"# appends the byte in al to the output buffer
+putbyte:
+    mov rcx, [rip + output_length]
+    lea rdx, [rip + output_buffer]
+    mov [rdx + rcx], al
+    inc rcx
+    mov [rip + output_length], rcx
+    cmp rcx, OUTPUT_BUFFER_SIZE
+    je flush
+    ret
+
+# writes the output buffer to stdout
+flush:
+    push rdi
+    lea rsi, [rip + output_buffer]
+    mov rdx, [rip + output_length]
+1:
+    test rdx, rdx
+    jz 2f
+    mov eax, 1
+    mov edi, 1
+    syscall
+    test rax, rax
+    jle 2f
+    add rsi, rax
+    sub rdx, rax
+    jmp 1b
+2:
+    mov QWORD PTR [rip + output_length], 0
+    pop rdi
+    ret
+
+# reads a byte from stdin into al, the current cell is passed in sil
+readbyte:
+    push rdi
+    push rsi
+    call flush
+    pop rsi
+    {eof_value}
+    sub rsp, 8
+    xor eax, eax
+    xor edi, edi
+    mov rsi, rsp
+    mov edx, 1
+    syscall
+    cmp rax, 1
+    movzx eax, BYTE PTR [rsp]
+    cmovne eax, r8d
+    add rsp, 8
+    pop rdi
+    ret
+
+# prints the pointer and the cells around it to stderr
+debug_dump:
+    push rdi
+    push rbx
+    push r12
+    push r13
+    call flush
+    mov rbx, rdi
+    lea r12, [rip + debug_buffer]
+    mov BYTE PTR [r12], '#'
+    inc r12
+    lea rcx, [rip + memory + ORIGIN]
+    mov rax, rbx
+    sub rax, rcx
+    call write_decimal
+    mov BYTE PTR [r12], ':'
+    inc r12
+    mov r13, -{window}
+1:
+    mov BYTE PTR [r12], ' '
+    inc r12
+    test r13, r13
+    jnz 2f
+    mov BYTE PTR [r12], '['
+    inc r12
+2:
+    lea rcx, [rip + memory]
+    mov rax, rbx
+    sub rax, rcx
+    add rax, r13
+    jns 3f
+    add rax, MEMORY_SIZE
+3:
+    cmp rax, MEMORY_SIZE
+    jb 4f
+    sub rax, MEMORY_SIZE
+4:
+    movzx eax, BYTE PTR [rcx + rax]
+    call write_decimal
+    test r13, r13
+    jnz 5f
+    mov BYTE PTR [r12], ']'
+    inc r12
+5:
+    inc r13
+    cmp r13, {window}
+    jle 1b
+    mov BYTE PTR [r12], 10
+    inc r12
+    mov eax, 1
+    mov edi, 2
+    lea rsi, [rip + debug_buffer]
+    mov rdx, r12
+    sub rdx, rsi
+    syscall
+    pop r13
+    pop r12
+    pop rbx
+    pop rdi
+    ret
+
+# writes rax as a signed decimal number to r12 and advances it
+write_decimal:
+    test rax, rax
+    jns 1f
+    mov BYTE PTR [r12], '-'
+    inc r12
+    neg rax
+1:
+    xor ecx, ecx
+    mov r8, 10
+2:
+    xor edx, edx
+    div r8
+    add dl, '0'
+    push rdx
+    inc ecx
+    test rax, rax
+    jnz 2b
+3:
+    pop rdx
+    mov BYTE PTR [r12], dl
+    inc r12
+    dec ecx
+    jnz 3b
+    ret"#, eof_value = eof_value, window = DEBUG_WINDOW)
+}
+
+pub struct AsmBackend;
+
+impl Backend for AsmBackend {
+    fn name(&self) -> &'static str {
+        "asm"
+    }
+
+    fn extension(&self) -> &'static str {
+        "s"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("#")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        require_cell_size(opts, opts.cell_size == CellSize::Bits(8))?;
+        // like the JIT, the pointer is not wrapped around or the tape grown
+        require_cell_layout(opts, opts.cell_layout == CellLayout::Trusting)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
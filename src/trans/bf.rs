@@ -0,0 +1,182 @@
+use super::super::{ir, options};
+use super::{Backend, BackendError, require_cell_size};
+
+use ir::{Instruction, ConstVisitor};
+use options::*;
+
+struct BfTranspiler<'a> {
+    code: String,
+    opts: &'a Options,
+    // position of the brainfuck pointer relative to the pointer of the
+    // instructions, moves are only written when a command needs them
+    head: i64,
+}
+
+/// Brainfuck doing the same as the instructions, with `Set` as `[-]` and linear
+/// loops as copy loops. The pointer only moves where cells are accessed, so
+/// this also minifies programs.
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = BfTranspiler {
+        code: String::new(),
+        opts,
+        head: 0,
+    };
+    transpiler.visit_instructions(instrs);
+    transpiler.code
+}
+
+impl<'a> BfTranspiler<'a> {
+    fn move_to(&mut self, offset: i64) {
+        let distance = offset - self.head;
+        let command = if distance < 0 { "<" } else { ">" };
+        self.code += &command.repeat(distance.unsigned_abs() as usize);
+        self.head = offset;
+    }
+
+    /// Writes a run of `Add`s and `Set`s, they can be reordered as long as
+    /// the ones on the same cell stay in order. Going through the cells from
+    /// the end closer to the pointer avoids moving back and forth.
+    fn visit_cell_changes(&mut self, changes: &[&Instruction]) {
+        let offset = |inst: &Instruction| match inst {
+            Instruction::Add{ offset, .. } | Instruction::Set{ offset, .. } => *offset,
+            _ => 0,
+        };
+        let mut changes = changes.to_vec();
+        // stable, so changes of the same cell keep their order
+        changes.sort_by_key(|inst| offset(inst));
+        if let (Some(first), Some(last)) = (changes.first(), changes.last()) {
+            if (offset(last) - self.head).abs() < (offset(first) - self.head).abs() {
+                changes.sort_by_key(|inst| -offset(inst));
+            }
+        }
+        for inst in changes {
+            self.walk_instruction(inst);
+        }
+    }
+
+    fn add(&mut self, value: i64) {
+        let value = self.shortest(value);
+        let command = if value < 0 { "-" } else { "+" };
+        self.code += &command.repeat(value.unsigned_abs() as usize);
+    }
+
+    /// The value with the smallest magnitude that is added to a cell with
+    /// the same effect as `value`
+    fn shortest(&self, value: i64) -> i64 {
+        let modulus: i128 = match self.opts.cell_size {
+            CellSize::Bits(n) if n < 64 => 1 << n,
+            CellSize::Modular(n) => n as i128,
+            _ => return value,
+        };
+        let value = (value as i128).rem_euclid(modulus);
+        if value > modulus / 2 { (value - modulus) as i64 } else { value as i64 }
+    }
+}
+
+impl<'a> ir::ConstVisitor for BfTranspiler<'a> {
+    type Ret = ();
+
+    fn visit_instructions(&mut self, instrs: &[Instruction]) {
+        let mut changes = Vec::new();
+        for inst in instrs {
+            if let Instruction::Add{ .. } | Instruction::Set{ .. } = inst {
+                changes.push(inst);
+                continue;
+            }
+            self.visit_cell_changes(&changes);
+            changes.clear();
+            self.walk_instruction(inst);
+        }
+        self.visit_cell_changes(&changes);
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            self.move_to(*offset);
+            self.add(*value);
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            self.move_to(*offset);
+            self.code += "[-]";
+            self.add(*value);
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            self.move_to(*glob_offset);
+            self.code += "[-";
+            for (&offset, &factor) in factors {
+                // the loop's own cell is cleared anyway
+                if offset == 0 || self.shortest(factor) == 0 {
+                    continue;
+                }
+                self.move_to(glob_offset + offset);
+                self.add(factor);
+            }
+            self.move_to(*glob_offset);
+            self.code += "]";
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            self.head -= offset;
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            self.move_to(0);
+            self.code += "[";
+            self.visit_instructions(insts);
+            self.move_to(0);
+            self.code += "]";
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            self.move_to(*offset);
+            self.code += ",";
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            self.move_to(*offset);
+            self.code += ".";
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        // the dump shows where the pointer is
+        self.move_to(0);
+        self.code += "#";
+    }
+}
+
+pub struct BfBackend;
+
+impl Backend for BfBackend {
+    fn name(&self) -> &'static str {
+        "bf"
+    }
+
+    fn extension(&self) -> &'static str {
+        "bf"
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        // `[-]` never clears negative cells if they do not wrap around, the
+        // layout is up to whatever runs the code
+        require_cell_size(opts, opts.cell_size != CellSize::Int)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
@@ -1,5 +1,7 @@
-
+use super::{Backend, BackendError, require_cells, require_cell_size, require_eof_mode};
+use super::source_map::{add_mark, SourceMarks};
 use super::super::{ir, formatter, optimize, options};
+use ir::DEBUG_WINDOW;
 
 use ir::Instruction;
 use ir::ConstVisitor;
@@ -7,132 +9,401 @@ use formatter::Formatter;
 use optimize::{DfInstr, DfgNode};
 use options::*;
 
+// how the arithmetic on a cell is brought into the range of the cell size
+enum Arithmetic {
+    // the cell size matches a C integer type, unsigned overflow wraps around
+    Native,
+    // a smaller bit width in a larger type, masked after each operation
+    Masked(u64),
+    Modular(u64),
+}
+
+/// The cell type and the tape, shared by the transpiler of the instructions
+/// and the one of the data flow graph
+struct Tape {
+    layout: CellLayout,
+    memory_size: usize,
+    cell_type: &'static str,
+    arithmetic: Arithmetic,
+}
+
+impl Tape {
+    fn create(opts: &Options) -> Self {
+        let (cell_type, arithmetic) = match opts.cell_size {
+            CellSize::Bits(8) => ("uint8_t", Arithmetic::Native),
+            CellSize::Bits(16) => ("uint16_t", Arithmetic::Native),
+            CellSize::Bits(32) => ("uint32_t", Arithmetic::Native),
+            CellSize::Bits(64) | CellSize::Int => ("uint64_t", Arithmetic::Native),
+            CellSize::Bits(n) => {
+                let cell_type = match n {
+                    0..=8 => "uint8_t",
+                    9..=16 => "uint16_t",
+                    17..=32 => "uint32_t",
+                    _ => "uint64_t",
+                };
+                (cell_type, Arithmetic::Masked((1u64 << n) - 1))
+            },
+            CellSize::Modular(n) => ("uint64_t", Arithmetic::Modular(n)),
+        };
+        Tape {
+            layout: opts.cell_layout.clone(),
+            memory_size: opts.memory_size.max(1),
+            cell_type,
+            arithmetic,
+        }
+    }
+
+    /// The includes, the tape and the helper functions
+    fn prelude(&self, eof_mode: EofMode) -> String {
+        let mut formatter = Formatter::new();
+        formatter.add_line(&format!(r#"#include <stddef.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <inttypes.h>
+
+#define MEMORY_SIZE {}
+
+typedef {} cell_t;
+"#, self.memory_size, self.cell_type));
+
+        match self.layout {
+            CellLayout::Trusting => formatter.add_line(r#"// the pointer starts in the middle of the tape and has to stay inside it
+#define CHECK(LOW, HIGH) do { if (ptr + (LOW) < 0 || ptr + (HIGH) >= MEMORY_SIZE) abort(); } while (0)
+
+static cell_t mem[MEMORY_SIZE];
+static ptrdiff_t ptr = MEMORY_SIZE / 2;
+"#),
+            CellLayout::Wrapping => formatter.add_line(r#"// the tape wraps around at its ends
+static cell_t mem[MEMORY_SIZE];
+static ptrdiff_t ptr = 0;
+"#),
+            CellLayout::Unbounded => formatter.add_line(r#"// the tape grows in both directions as the pointer moves
+#define RESERVE(LOW, HIGH) do { if (ptr + (LOW) < 0 || ptr + (HIGH) >= mem_size) reserve(ptr + (LOW), ptr + (HIGH)); } while (0)
+
+static cell_t* mem;
+static ptrdiff_t mem_size = MEMORY_SIZE;
+static ptrdiff_t ptr = 0;
+// index of the starting cell in mem
+static ptrdiff_t origin = 0;
+
+// grows the tape until the cells from low to high are in it
+static inline void reserve(ptrdiff_t low, ptrdiff_t high) {
+    while (low < 0 || high >= mem_size) {
+        ptrdiff_t shift = low < 0 ? mem_size : 0;
+        cell_t* grown = (cell_t*) calloc(2 * mem_size, sizeof(cell_t));
+        if (!grown) {
+            abort();
+        }
+        memcpy(grown + shift, mem, mem_size * sizeof(cell_t));
+        free(mem);
+        mem = grown;
+        mem_size *= 2;
+        ptr += shift;
+        origin += shift;
+        low += shift;
+        high += shift;
+    }
+}
+"#),
+        }
+
+        if let Arithmetic::Modular(n) = self.arithmetic {
+            // wide enough to add or multiply two cells without overflowing
+            let wide_type = if n <= 1 << 32 { "uint64_t" } else { "unsigned __int128" };
+            formatter.add_line(&format!(r#"static inline cell_t addmod(cell_t a, cell_t b) {{
+    return (cell_t) ((({wide}) a + b) % {n}u);
+}}
+
+static inline cell_t mulmod(cell_t a, cell_t b) {{
+    return (cell_t) (({wide}) a * b % {n}u);
+}}
+"#, wide = wide_type, n = n));
+        }
+
+        let eof_value = match eof_mode {
+            EofMode::Zero => "0".to_string(),
+            EofMode::MinusOne => self.literal(-1),
+            EofMode::Unchanged => "current".to_string(),
+        };
+        formatter.add_line(&format!(r#"// reads a byte into a cell that holds `current`
+static inline cell_t read_cell(cell_t current) {{
+    (void) current;
+    fflush(stdout);
+    int c = getchar();
+    return c == EOF ? {} : {};
+}}
+"#, eof_value, self.reduce("(cell_t) c")));
+        formatter.get_code()
+    }
+
+    /// Statements at the start of main
+    fn initialize(&self, formatter: &mut Formatter) {
+        if let CellLayout::Unbounded = self.layout {
+            formatter.add_line("mem = (cell_t*) calloc(mem_size, sizeof(cell_t));");
+        }
+    }
+
+    fn finalize(&self, formatter: &mut Formatter) {
+        if let CellLayout::Unbounded = self.layout {
+            formatter.add_line("free(mem);");
+        }
+    }
+
+    /// The cell at an offset from the pointer
+    fn cell(&self, offset: i64) -> String {
+        match self.layout {
+            CellLayout::Wrapping => match offset.rem_euclid(self.memory_size as i64) {
+                0 => "mem[ptr]".to_string(),
+                off => format!("mem[(ptr + {}) % MEMORY_SIZE]", off),
+            },
+            _ => match offset {
+                0 => "mem[ptr]".to_string(),
+                off if off < 0 => format!("mem[ptr - {}]", -off),
+                off => format!("mem[ptr + {}]", off),
+            },
+        }
+    }
+
+    /// Makes sure the cells from `low` to `high` relative to the pointer can
+    /// be accessed. The pointer itself always points into the tape.
+    fn access(&self, formatter: &mut Formatter, low: i64, high: i64) {
+        if low == 0 && high == 0 {
+            return;
+        }
+        match self.layout {
+            CellLayout::Trusting => formatter.add_line(&format!("CHECK({}, {});", low, high)),
+            CellLayout::Wrapping => {},
+            CellLayout::Unbounded => formatter.add_line(&format!("RESERVE({}, {});", low, high)),
+        }
+    }
+
+    fn move_ptr(&self, formatter: &mut Formatter, offset: i64) {
+        match self.layout {
+            CellLayout::Wrapping => match offset.rem_euclid(self.memory_size as i64) {
+                0 => {},
+                off => formatter.add_line(&format!("ptr = (ptr + {}) % MEMORY_SIZE;", off)),
+            },
+            CellLayout::Trusting => {
+                formatter.add_line(&format!("ptr += {};", offset));
+                formatter.add_line("CHECK(0, 0);");
+            },
+            CellLayout::Unbounded => {
+                formatter.add_line(&format!("ptr += {};", offset));
+                formatter.add_line("RESERVE(0, 0);");
+            },
+        }
+    }
+
+    /// A value as a literal in the range of the cell
+    fn literal(&self, value: i64) -> String {
+        let value = match self.arithmetic {
+            Arithmetic::Native => match self.cell_type {
+                "uint8_t" => value as u8 as u64,
+                "uint16_t" => value as u16 as u64,
+                "uint32_t" => value as u32 as u64,
+                _ => value as u64,
+            },
+            Arithmetic::Masked(mask) => value as u64 & mask,
+            Arithmetic::Modular(n) => (value as i128).rem_euclid(n as i128) as u64,
+        };
+        // unsigned, so products of promoted cells cannot overflow
+        format!("{}u", value)
+    }
+
+    /// Brings the result of additions and multiplications on cells into
+    /// the range of the cell
+    fn reduce(&self, value: &str) -> String {
+        match self.arithmetic {
+            Arithmetic::Native => format!("(cell_t) ({})", value),
+            Arithmetic::Masked(mask) => format!("(cell_t) (({}) & {}u)", value, mask),
+            Arithmetic::Modular(n) => format!("(cell_t) (({}) % {}u)", value, n),
+        }
+    }
+
+    fn add_to_cell(&self, formatter: &mut Formatter, cell: &str, value: &str) {
+        let line = match self.arithmetic {
+            Arithmetic::Native => format!("{} += {};", cell, value),
+            Arithmetic::Masked(mask) => format!("{c} = ({c} + {v}) & {m}u;", c = cell, v = value, m = mask),
+            Arithmetic::Modular(_) => format!("{c} = addmod({c}, {v});", c = cell, v = value),
+        };
+        formatter.add_line(&line);
+    }
+
+    fn multiply(&self, cell: &str, factor: i64) -> String {
+        match self.arithmetic {
+            Arithmetic::Modular(_) => format!("mulmod({}, {})", cell, self.literal(factor)),
+            _ if factor == 1 => cell.to_string(),
+            _ => format!("{} * {}", cell, self.literal(factor)),
+        }
+    }
+
+    fn generate_debug(&self, formatter: &mut Formatter) {
+        let (position, cell) = match self.layout {
+            CellLayout::Trusting => ("ptr - MEMORY_SIZE / 2", "i >= 0 && i < MEMORY_SIZE ? mem[i] : 0"),
+            CellLayout::Wrapping => ("ptr", "mem[(i % MEMORY_SIZE + MEMORY_SIZE) % MEMORY_SIZE]"),
+            CellLayout::Unbounded => ("ptr - origin", "i >= 0 && i < mem_size ? mem[i] : 0"),
+        };
+        formatter.add_line("fflush(stdout);");
+        formatter.add_line(&format!("fprintf(stderr, \"#%td:\", {});", position));
+        formatter.add_line(&format!("for (ptrdiff_t i = ptr - {w}; i <= ptr + {w}; i++) {{", w = DEBUG_WINDOW));
+        formatter.indent();
+        formatter.add_line(&format!("fprintf(stderr, i == ptr ? \" [%llu]\" : \" %llu\", (unsigned long long) ({}));", cell));
+        formatter.unindent();
+        formatter.add_line("}");
+        formatter.add_line("fprintf(stderr, \"\\n\");");
+    }
+}
+
 struct CTranspiler {
-    pub code_buf: Formatter
+    pub code_buf: Formatter,
+    tape: Tape,
+    marks: SourceMarks,
 }
 
-fn eval(dn: &DfgNode) -> String {
+fn eval(dn: &DfgNode, tape: &Tape) -> String {
     match dn {
         DfgNode::Const(c) => {
-            format!("{}", c)
+            tape.literal(*c)
         },
         DfgNode::Cell(off) => {
-            format!("mem[OFF({})]", off)
+            tape.cell(*off)
         },
         DfgNode::Add(a, b) => {
-            format!("{} + {}", eval(a), eval(b))
+            match tape.arithmetic {
+                Arithmetic::Modular(_) => format!("addmod({}, {})", eval(a, tape), eval(b, tape)),
+                _ => format!("{} + {}", eval(a, tape), eval(b, tape)),
+            }
         },
         DfgNode::Multiply(a, b) => {
-            format!("({}) * ({})", eval(a), eval(b))
+            match tape.arithmetic {
+                Arithmetic::Modular(_) => format!("mulmod({}, {})", eval(a, tape), eval(b, tape)),
+                _ => format!("({}) * ({})", eval(a, tape), eval(b, tape)),
+            }
         },
         DfgNode::Read() => {
-            format!("getchar()")
+            "read_cell(0)".to_string()
         }
     }
 }
 
-pub fn transpile_dfg(dfg: &optimize::DfgOptimizer) -> String {
-    let mut formatter = Formatter::new();
-    formatter.add_line(r#"#include <stdio.h>
-#include <stdlib.h>
-#include <string.h>
-#include <inttypes.h>
+/// The offsets of the cells a node reads
+fn cells(dn: &DfgNode, offsets: &mut Vec<i64>) {
+    match dn {
+        DfgNode::Cell(off) => offsets.push(*off),
+        DfgNode::Add(a, b) | DfgNode::Multiply(a, b) => {
+            cells(a, offsets);
+            cells(b, offsets);
+        },
+        DfgNode::Const(_) | DfgNode::Read() => {},
+    }
+}
 
-#define OFF(X) ((uint16_t)(ptr + (uint16_t) (X)))
+/// Checks whether `transpile_dfg` can implement the options, reads in the
+/// data flow graph do not know the cell they go to
+pub fn supports_dfg(opts: &Options) -> Result<(), BackendError> {
+    require_cells(opts)?;
+    CBackend.supports(opts)?;
+    require_eof_mode(opts, &[EofMode::Zero, EofMode::MinusOne])
+}
 
-int main() {
-    uint8_t* mem = (uint8_t*) calloc(0x10000, 1);
-    uint16_t ptr = 0;"#);
+pub fn transpile_dfg(opts: &Options, dfg: &optimize::DfgOptimizer) -> String {
+    let tape = Tape::create(opts);
+    let mut formatter = Formatter::new();
+    formatter.add_line(&tape.prelude(opts.eof_mode));
+    formatter.add_line("int main() {");
     formatter.indent();
-    generate_dfg(&dfg.cfg, &mut formatter);
+    tape.initialize(&mut formatter);
+    generate_dfg(&dfg.cfg, &mut formatter, &tape);
+    tape.finalize(&mut formatter);
     formatter.unindent();
     formatter.add_line("}");
 
     formatter.get_code()
 }
 
-fn generate_dfg(cfg: &Vec<DfInstr>, formatter: &mut Formatter) {
+/// Reserves the cells a statement reads and writes, for unbounded tapes
+fn access_dfg(formatter: &mut Formatter, tape: &Tape, mut offsets: Vec<i64>) {
+    offsets.push(0);
+    let low = offsets.iter().copied().min().unwrap_or(0);
+    let high = offsets.iter().copied().max().unwrap_or(0);
+    tape.access(formatter, low, high);
+}
+
+fn generate_dfg(cfg: &[DfInstr], formatter: &mut Formatter, tape: &Tape) {
     let mut memoffs: Vec<(i64, u64)> = Vec::new();
     let mut tmp_counter: u64 = 0;
-    let offset_creator = |o: i64| {
-        if o < 0 {
-            format!("minus_{}", -o)
-        }
-        else {
-            format!("{}", o)
+
+    let write_back = |memoffs: &mut Vec<(i64, u64)>, formatter: &mut Formatter| {
+        access_dfg(formatter, tape, memoffs.iter().map(|(off, _)| *off).collect());
+        for (off, tmp) in memoffs.iter() {
+            formatter.add_line(&format!("{} = tmp_{};", tape.cell(*off), *tmp));
         }
+        memoffs.clear();
     };
+
     for stmt in cfg {
         match stmt {
             DfInstr::MovePtr(off) => {
-                formatter.add_line(&format!("ptr += {};", off));
+                tape.move_ptr(formatter, *off);
             },
             DfInstr::WriteMem(off, val) => {
-                formatter.add_line(&format!("uint8_t tmp_{} = {};", tmp_counter, eval(val)));
+                let mut offsets = Vec::new();
+                cells(val, &mut offsets);
+                access_dfg(formatter, tape, offsets);
+                formatter.add_line(&format!("cell_t tmp_{} = {};", tmp_counter, tape.reduce(&eval(val, tape))));
                 memoffs.push((*off, tmp_counter));
                 tmp_counter += 1;
             },
             DfInstr::Print(val) => {
-                formatter.add_line(&format!("putchar({});", eval(val)));
+                let mut offsets = Vec::new();
+                cells(val, &mut offsets);
+                access_dfg(formatter, tape, offsets);
+                formatter.add_line(&format!("putchar({});", tape.reduce(&eval(val, tape))));
             },
             DfInstr::Loop(_val, instrs) => {
-                for (off, tmp) in &memoffs {
-                    formatter.add_line(&format!("mem[OFF({})] = tmp_{};", *off, *tmp));
-                }
-                memoffs.clear();
-                formatter.add_line("while(mem[OFF(0)]) {");
+                write_back(&mut memoffs, formatter);
+                formatter.add_line(&format!("while ({}) {{", tape.cell(0)));
                 formatter.indent();
-                generate_dfg(&instrs, formatter);
+                generate_dfg(instrs, formatter, tape);
                 formatter.unindent();
                 formatter.add_line("}");
             },
+            DfInstr::Debug => {
+                write_back(&mut memoffs, formatter);
+                tape.generate_debug(formatter);
+            },
         }
     }
-    for (off, tmp) in memoffs {
-        formatter.add_line(&format!("mem[OFF({})] = tmp_{};", off, tmp));
-    }
+    write_back(&mut memoffs, formatter);
 }
 
 
-pub fn transpile(opts: &Options, instrs: &Vec<ir::Instruction>) -> String {
-    let mut transpiler = CTranspiler::create(opts);
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = CTranspiler::create(opts, instrs);
     transpiler.visit_instructions(instrs);
     transpiler.finalize();
-    return transpiler.code_buf.get_code();
+    transpiler.code_buf.get_code()
 }
 
 
 impl CTranspiler {
-    fn create(opts: &Options) -> Self {
-        let mut transpiler = CTranspiler{ code_buf: Formatter::new() };
-
-        let cell_type = match opts.cell_size {
-            CellSize::Bits(8) => "uint8_t",
-            CellSize::Bits(16) => "uint16_t",
-            CellSize::Bits(32) => "uint32_t",
-            _ => "uint64_t"
-        };
-
+    fn create(opts: &Options, instrs: &[Instruction]) -> Self {
+        let tape = Tape::create(opts);
+        let mut transpiler = CTranspiler{ code_buf: Formatter::new(), tape, marks: SourceMarks::new(opts, instrs) };
 
-        transpiler.code_buf.add_line(&format!(r#"#include <stdio.h>
-#include <stdlib.h>
-#include <string.h>
-#include <inttypes.h>
-
-#define OFF(X) ((uint16_t)(ptr + (uint16_t) (X)))
-
-int main() {{
-    {ct}* mem = ({ct}*) malloc(0x10000 * sizeof({ct}));
-    memset(mem, 0, 0x10000 * sizeof({ct}));
-    uint16_t ptr = 0;"#, ct = cell_type));
+        let prelude = transpiler.tape.prelude(opts.eof_mode);
+        transpiler.code_buf.add_line(&prelude);
+        transpiler.code_buf.add_line("int main() {");
         transpiler.code_buf.indent();
+        transpiler.tape.initialize(&mut transpiler.code_buf);
         transpiler
     }
 }
 
 impl CTranspiler {
     pub fn finalize(&mut self) {
-        self.code_buf.add_line("free(mem);");
+        self.tape.finalize(&mut self.code_buf);
         self.code_buf.unindent();
         self.code_buf.add_line("}");
     }
@@ -142,77 +413,123 @@ impl CTranspiler {
 impl ir::ConstVisitor for CTranspiler {
     type Ret = ();
 
-    fn visit_nop(&mut self, nop: &Instruction) {
+    fn before(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.enter(inst));
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.leave(inst));
+    }
+
+    fn visit_nop(&mut self, _nop: &Instruction) {
         self.code_buf.add_line("");
     }
 
     fn visit_add(&mut self, add: &'_ Instruction) {
         if let Instruction::Add{ offset, value } = add {
-            self.code_buf.add_line(&format!("mem[OFF({})] += {};", offset, value));
+            self.tape.access(&mut self.code_buf, *offset, *offset);
+            let (cell, value) = (self.tape.cell(*offset), self.tape.literal(*value));
+            self.tape.add_to_cell(&mut self.code_buf, &cell, &value);
         }
     }
 
     fn visit_set(&mut self, set: &'_ Instruction) {
         if let Instruction::Set{ offset, value } = set {
-            self.code_buf.add_line(&format!("mem[OFF({})] = {};", offset, value));
+            self.tape.access(&mut self.code_buf, *offset, *offset);
+            self.code_buf.add_line(&format!("{} = {};", self.tape.cell(*offset), self.tape.literal(*value)));
         }
     }
 
     fn visit_linear_loop(&mut self, l: &Instruction) {
-        if let Instruction::LinearLoop{ offset: glob_offset, factors } = l {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            let offsets = factors.keys().map(|offset| glob_offset + offset);
+            let low = offsets.clone().fold(*glob_offset, i64::min);
+            let high = offsets.fold(*glob_offset, i64::max);
+            self.tape.access(&mut self.code_buf, low, high);
+
+            let source = self.tape.cell(*glob_offset);
             for (&offset, &factor) in factors {
-                if offset == 0 {
+                if offset == 0 || factor == 0 {
                     continue;
                 }
-
-                if factor == 0 {
-                }
-                else if factor == 1 {
-                    self.code_buf.add_line(&format!("mem[OFF({})] += mem[OFF({})];", glob_offset + offset, glob_offset));
-                }
-                else if factor == -1 {
-                    self.code_buf.add_line(&format!("mem[OFF({})] -= mem[OFF({})];", glob_offset + offset, glob_offset));
-                }
-                else {
-                    self.code_buf.add_line(&format!("mem[OFF({})] += {} * mem[OFF({})];", glob_offset + offset, factor, glob_offset));
-                }
+                let product = self.tape.multiply(&source, factor);
+                let cell = self.tape.cell(glob_offset + offset);
+                self.tape.add_to_cell(&mut self.code_buf, &cell, &product);
             }
-            self.code_buf.add_line(&format!("mem[OFF({})] = 0;", glob_offset));
+            self.code_buf.add_line(&format!("{} = 0;", source));
         }
     }
 
     fn visit_move_ptr(&mut self, mp: &Instruction) {
         if let Instruction::MovePtr(offset) = mp {
-            self.code_buf.add_line(&format!("ptr = OFF({});", offset));
+            self.tape.move_ptr(&mut self.code_buf, *offset);
         }
     }
 
     fn visit_loop(&mut self, l: &Instruction) {
-        if let Instruction::Loop(insts) = l {
-            if insts.len() == 1 {
-                if let Instruction::MovePtr(1) = insts[0] {
-                    //self.code_buf.add_line("printf(\"strlen(%s, %d)\\n\", buffer, strlen(buffer));");
-                    self.code_buf.add_line("ptr = OFF(strlen(&mem[ptr]));");
+        if let Instruction::Loop(insts, _) = l {
+            // `[>]` finds the next zero byte, only on a tape that does not
+            // wrap around or grow. The search stops at the end of the tape.
+            if let [Instruction::MovePtr(1)] = insts[..] {
+                if self.tape.cell_type == "uint8_t" && self.tape.layout == CellLayout::Trusting {
+                    self.code_buf.add_line("{");
+                    self.code_buf.indent();
+                    self.code_buf.add_line("cell_t* zero = (cell_t*) memchr(&mem[ptr], 0, MEMORY_SIZE - ptr);");
+                    self.code_buf.add_line("if (!zero) abort();");
+                    self.code_buf.add_line("ptr = zero - mem;");
+                    self.code_buf.unindent();
+                    self.code_buf.add_line("}");
                     return;
                 }
             }
-            self.code_buf.add_line("while(mem[OFF(0)]) {");
+            self.code_buf.add_line(&format!("while ({}) {{", self.tape.cell(0)));
             self.code_buf.indent();
             self.visit_instructions(insts);
             self.code_buf.unindent();
             self.code_buf.add_line("}");
         }
     }
-    
+
     fn visit_read(&mut self, r: &Instruction) {
         if let Instruction::Read(offset) = r {
-            self.code_buf.add_line(&format!("mem[OFF({})] = getchar();", offset));
+            self.tape.access(&mut self.code_buf, *offset, *offset);
+            let cell = self.tape.cell(*offset);
+            self.code_buf.add_line(&format!("{c} = read_cell({c});", c = cell));
         }
     }
 
     fn visit_write(&mut self, w: &Instruction) {
         if let Instruction::Write(offset) = w {
-            self.code_buf.add_line(&format!("putchar(mem[OFF({})]);", offset));
+            self.tape.access(&mut self.code_buf, *offset, *offset);
+            self.code_buf.add_line(&format!("putchar({});", self.tape.cell(*offset)));
         }
     }
-}
\ No newline at end of file
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        self.tape.generate_debug(&mut self.code_buf);
+    }
+}
+
+pub struct CBackend;
+
+impl Backend for CBackend {
+    fn name(&self) -> &'static str {
+        "c"
+    }
+
+    fn extension(&self) -> &'static str {
+        "c"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("//")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        require_cell_size(opts, !matches!(opts.cell_size, CellSize::Bits(n) if n > 64))
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
@@ -0,0 +1,227 @@
+use super::super::{ir, formatter, options};
+use super::{Backend, BackendError, require_cell_size, require_wrapping};
+use super::source_map::{add_mark, SourceMarks};
+
+use ir::{Instruction, DEBUG_WINDOW};
+use formatter::Formatter;
+use options::*;
+
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut formatter = Formatter::new();
+
+    let modular = matches!(opts.cell_size, CellSize::Modular(_));
+
+    formatter.add_line("package main");
+    formatter.add_line("");
+    formatter.add_line("import (");
+    formatter.indent();
+    formatter.add_line("\"bufio\"");
+    if super::contains_debug(instrs) {
+        formatter.add_line("\"fmt\"");
+    }
+    if modular {
+        formatter.add_line("\"math/bits\"");
+    }
+    formatter.add_line("\"os\"");
+    formatter.unindent();
+    formatter.add_line(")");
+    formatter.add_line("");
+    formatter.add_line(&format!("const memorySize = {}", opts.memory_size.max(1)));
+    formatter.add_line("");
+    // package level, so programs not using some of them still compile
+    formatter.add_line(&format!("var mem = make([]{}, memorySize)", cell_type(opts)));
+    formatter.add_line("var ptr = 0");
+    formatter.add_line("var input = bufio.NewReader(os.Stdin)");
+    formatter.add_line("var output = bufio.NewWriter(os.Stdout)");
+    if let CellSize::Modular(n) = opts.cell_size {
+        formatter.add_line("");
+        formatter.add_line("func addmod(a, b uint64) uint64 {");
+        formatter.add_line("    sum, carry := bits.Add64(a, b, 0)");
+        formatter.add_line(&format!("    return bits.Rem64(carry, sum, {})", n));
+        formatter.add_line("}");
+        formatter.add_line("");
+        formatter.add_line("func mulmod(a, b uint64) uint64 {");
+        formatter.add_line("    hi, lo := bits.Mul64(a, b)");
+        formatter.add_line(&format!("    return bits.Rem64(hi, lo, {})", n));
+        formatter.add_line("}");
+    }
+    formatter.add_line("");
+    formatter.add_line("func main() {");
+    formatter.indent();
+    formatter.add_line("defer output.Flush()");
+    formatter.add_line("");
+
+    generate(&mut formatter, instrs, opts, &SourceMarks::new(opts, instrs));
+
+    formatter.unindent();
+    formatter.add_line("}");
+
+    formatter.get_code()
+}
+
+
+fn generate(formatter: &mut Formatter, instrs: &[Instruction], opts: &Options, marks: &SourceMarks) {
+    for instr in instrs {
+        add_mark(formatter, marks.enter(instr));
+        match instr {
+            Instruction::Nop => {},
+            Instruction::Add{ offset, value } => {
+                let cell = index(*offset, opts);
+                formatter.add_line(&add_to_cell(&cell, &literal(*value, opts), opts));
+            },
+            Instruction::Set{ offset, value } => {
+                formatter.add_line(&format!("mem[{}] = {}", index(*offset, opts), literal(*value, opts)));
+            },
+            Instruction::LinearLoop{ offset, factors, .. } => {
+                let factors: Vec<(&i64, &i64)> = factors.iter().filter(|(&off, _)| off != 0).collect();
+                if !factors.is_empty() {
+                    formatter.add_line("{");
+                    formatter.indent();
+                    formatter.add_line(&format!("v := mem[{}]", index(*offset, opts)));
+                    for (off, factor) in factors {
+                        let product = match opts.cell_size {
+                            CellSize::Modular(_) => format!("mulmod(v, {})", literal(*factor, opts)),
+                            _ => format!("v * {}", literal(*factor, opts)),
+                        };
+                        formatter.add_line(&add_to_cell(&index(offset + off, opts), &product, opts));
+                    }
+                    formatter.unindent();
+                    formatter.add_line("}");
+                }
+                formatter.add_line(&format!("mem[{}] = 0", index(*offset, opts)));
+            },
+            Instruction::MovePtr(offset) => {
+                let new_ptr = index(*offset, opts);
+                if new_ptr != "ptr" {
+                    formatter.add_line(&format!("ptr = {}", new_ptr));
+                }
+            },
+            Instruction::Loop(instructions, _) => {
+                formatter.add_line("for mem[ptr] != 0 {");
+                formatter.indent();
+                generate(formatter, instructions, opts, marks);
+                formatter.unindent();
+                formatter.add_line("}");
+            },
+            Instruction::Read(offset) => {
+                let cell = index(*offset, opts);
+                formatter.add_line("output.Flush()");
+                formatter.add_line("if b, err := input.ReadByte(); err == nil {");
+                formatter.indent();
+                formatter.add_line(&format!("mem[{}] = {}", cell, byte_to_cell(opts)));
+                formatter.unindent();
+                match opts.eof_mode {
+                    EofMode::Zero => {
+                        formatter.add_line("} else {");
+                        formatter.add_line(&format!("    mem[{}] = 0", cell));
+                    },
+                    EofMode::MinusOne => {
+                        formatter.add_line("} else {");
+                        formatter.add_line(&format!("    mem[{}] = {}", cell, literal(-1, opts)));
+                    },
+                    EofMode::Unchanged => {},
+                }
+                formatter.add_line("}");
+            },
+            Instruction::Write(offset) => {
+                formatter.add_line(&format!("output.WriteByte(byte(mem[{}]))", index(*offset, opts)));
+            },
+            Instruction::Debug => {
+                formatter.add_line("output.Flush()");
+                formatter.add_line("fmt.Fprintf(os.Stderr, \"#%d:\", ptr)");
+                formatter.add_line(&format!("for i := {}; i <= {}; i++ {{", -DEBUG_WINDOW, DEBUG_WINDOW));
+                formatter.indent();
+                formatter.add_line("cell := mem[((ptr+i)%memorySize+memorySize)%memorySize]");
+                formatter.add_line("if i == 0 {");
+                formatter.add_line("    fmt.Fprintf(os.Stderr, \" [%d]\", cell)");
+                formatter.add_line("} else {");
+                formatter.add_line("    fmt.Fprintf(os.Stderr, \" %d\", cell)");
+                formatter.add_line("}");
+                formatter.unindent();
+                formatter.add_line("}");
+                formatter.add_line("fmt.Fprintln(os.Stderr)");
+            }
+        }
+        add_mark(formatter, marks.leave(instr));
+    }
+}
+
+/// The index of a cell relative to the pointer
+fn index(offset: i64, opts: &Options) -> String {
+    match offset.rem_euclid(opts.memory_size.max(1) as i64) {
+        0 => "ptr".to_string(),
+        off => format!("(ptr + {}) % memorySize", off),
+    }
+}
+
+/// Bits that are not a whole go integer type are masked after each operation
+fn mask(opts: &Options) -> Option<u64> {
+    match opts.cell_size {
+        CellSize::Bits(n) if n < 64 && ![8, 16, 32].contains(&n) => Some((1 << n) - 1),
+        _ => None,
+    }
+}
+
+/// A value as a constant in the range of the cell, go rejects constants
+/// overflowing their type
+fn literal(value: i64, opts: &Options) -> String {
+    let value = match opts.cell_size {
+        CellSize::Bits(8) => value as u8 as u64,
+        CellSize::Bits(16) => value as u16 as u64,
+        CellSize::Bits(32) => value as u32 as u64,
+        CellSize::Modular(n) => (value as i128).rem_euclid(n as i128) as u64,
+        _ => value as u64 & mask(opts).unwrap_or(u64::MAX),
+    };
+    format!("{}", value)
+}
+
+fn add_to_cell(cell: &str, value: &str, opts: &Options) -> String {
+    match (opts.cell_size.clone(), mask(opts)) {
+        (CellSize::Modular(_), _) => format!("mem[{c}] = addmod(mem[{c}], {v})", c = cell, v = value),
+        (_, Some(mask)) => format!("mem[{c}] = (mem[{c}] + {v}) & {m}", c = cell, v = value, m = mask),
+        _ => format!("mem[{}] += {}", cell, value),
+    }
+}
+
+fn cell_type(opts: &Options) -> &'static str {
+    match opts.cell_size {
+        CellSize::Bits(n) if n <= 8 => "uint8",
+        CellSize::Bits(n) if n <= 16 => "uint16",
+        CellSize::Bits(n) if n <= 32 => "uint32",
+        _ => "uint64"
+    }
+}
+
+/// An expression converting the byte `b` into a cell
+fn byte_to_cell(opts: &Options) -> String {
+    match (opts.cell_size.clone(), mask(opts)) {
+        (CellSize::Modular(n), _) => format!("uint64(b) % {}", n),
+        (_, Some(mask)) => format!("{}(b) & {}", cell_type(opts), mask),
+        _ => format!("{}(b)", cell_type(opts)),
+    }
+}
+
+pub struct GoBackend;
+
+impl Backend for GoBackend {
+    fn name(&self) -> &'static str {
+        "go"
+    }
+
+    fn extension(&self) -> &'static str {
+        "go"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("//")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        require_cell_size(opts, !matches!(opts.cell_size, CellSize::Bits(n) if n > 64))?;
+        require_wrapping(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
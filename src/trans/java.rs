@@ -1,73 +1,324 @@
 use super::super::{ir, formatter, options};
+use super::{count_instructions, Backend, BackendError, require_cell_size, require_memory_size, require_wrapping};
+use super::source_map::{add_mark, SourceMarks};
 
-use ir::Instruction;
+use ir::{Instruction, ConstVisitor, DEBUG_WINDOW};
 use formatter::Formatter;
 use options::*;
 
-pub fn transpile(opts: &Options, instrs: &Vec<ir::Instruction>) -> String {
-    let mut formatter = Formatter::new();
+// how the arithmetic on a cell is brought into the range of the cell size
+enum Arithmetic {
+    // the cell size matches a java integer type, overflow wraps around
+    Native,
+    // a smaller bit width in a larger type, masked after each operation
+    Masked(u64),
+    Modular(u64),
+}
+
+// loops with more instructions than this get a method of their own, the jvm
+// rejects methods with more than 64KiB of bytecode
+const MAX_INLINE_LOOP: usize = 500;
+
+// the pointer plus an offset must not overflow an int
+const MAX_MEMORY_SIZE: usize = 1 << 30;
+
+struct JavaTranspiler {
+    pub code_buf: Formatter,
+    marks: SourceMarks,
+    // methods for the top level and the large loops, appended after main
+    methods: Vec<Formatter>,
+    // loops the current method is nested in
+    depth: usize,
+    memory_size: usize,
+    cell_size: CellSize,
+    eof_mode: EofMode,
+    // "int" or "long"
+    cell_type: &'static str,
+    arithmetic: Arithmetic,
+}
+
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = JavaTranspiler::create(opts, instrs);
+    transpiler.visit_instructions(instrs);
+    transpiler.finalize();
+    transpiler.code_buf.get_code()
+}
+
+impl JavaTranspiler {
+    fn create(opts: &Options, instrs: &[Instruction]) -> Self {
+        let (cell_type, arithmetic) = match opts.cell_size {
+            CellSize::Bits(32) => ("int", Arithmetic::Native),
+            CellSize::Bits(64) | CellSize::Int => ("long", Arithmetic::Native),
+            CellSize::Bits(n) if n < 32 => ("int", Arithmetic::Masked((1 << n) - 1)),
+            CellSize::Bits(n) => ("long", Arithmetic::Masked((1 << n) - 1)),
+            CellSize::Modular(n) => ("long", Arithmetic::Modular(n)),
+        };
+
+        let mut transpiler = JavaTranspiler {
+            code_buf: Formatter::new(),
+            marks: SourceMarks::new(opts, instrs),
+            methods: Vec::new(),
+            depth: 0,
+            memory_size: opts.memory_size.max(1),
+            cell_size: opts.cell_size.clone(),
+            eof_mode: opts.eof_mode,
+            cell_type,
+            arithmetic,
+        };
+
+        let eof_value = match transpiler.eof_mode {
+            EofMode::Zero => "0".to_string(),
+            EofMode::MinusOne => transpiler.literal(-1),
+            EofMode::Unchanged => "current".to_string(),
+        };
+        transpiler.code_buf.add_line(&format!(r#"import java.io.*;
+
+class Brainfuck {{
+    static final int MEMORY_SIZE = {size};
+    static final {ct}[] mem = new {ct}[MEMORY_SIZE];
+    static int ptr = 0;
+    static final InputStream in = new BufferedInputStream(System.in);
+    static final OutputStream out = new BufferedOutputStream(new FileOutputStream(FileDescriptor.out));
+
+    // reads a byte into a cell that holds `current`
+    static {ct} readCell({ct} current) throws IOException {{
+        out.flush();
+        int b = in.read();
+        return b < 0 ? {eof} : {byte};
+    }}
+"#, size = transpiler.memory_size, ct = cell_type, eof = eof_value, byte = transpiler.byte_to_cell()));
+
+        if let Arithmetic::Modular(n) = transpiler.arithmetic {
+            // products of two cells only fit into a long for small moduli
+            let product = if n <= 1 << 31 {
+                format!("a * b % {}L", n)
+            }
+            else {
+                format!("java.math.BigInteger.valueOf(a).multiply(java.math.BigInteger.valueOf(b)).mod(java.math.BigInteger.valueOf({}L)).longValue()", n)
+            };
+            transpiler.code_buf.add_line(&format!(r#"    static long addmod(long a, long b) {{
+        return Long.remainderUnsigned(a + b, {n}L);
+    }}
+
+    static long mulmod(long a, long b) {{
+        return {product};
+    }}
+"#, n = n, product = product));
+        }
+
+        transpiler.code_buf.indent();
+        transpiler.code_buf.add_line("public static void main(String[] args) throws IOException {");
+        transpiler.code_buf.indent();
+        transpiler
+    }
 
+    fn finalize(&mut self) {
+        self.code_buf.add_line("out.flush();");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+        // the methods are indented already
+        self.code_buf.unindent();
+        for method in self.methods.drain(..) {
+            self.code_buf.add_line("");
+            self.code_buf.add_line(method.get_code().trim_end());
+        }
+        self.code_buf.add_line("}");
+    }
 
-    let cell_type = match opts.cell_size {
-        CellSize::Bits(8) => "byte",
-        CellSize::Bits(16) => "short",
-        CellSize::Bits(32) => "int",
-        CellSize::Int => "long",
-        _ => "long"
-    };
+    fn generate_loop(&mut self, insts: &[Instruction]) {
+        self.code_buf.add_line("while (mem[ptr] != 0) {");
+        self.code_buf.indent();
+        self.depth += 1;
+        self.visit_instructions(insts);
+        self.depth -= 1;
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+    }
 
-    formatter.add_line("class Brainfuck {");
-    formatter.indent();
-    formatter.add_line("public static void main(String[] args) {");
-    formatter.indent();
-    formatter.add_line(&format!("{ct}[] mem = new {ct}[0x10000];", ct = cell_type));
-    formatter.add_line("int ptr = 0;");
-    formatter.add_line("");
+    /// The memory index of a cell relative to the pointer
+    fn index(&self, offset: i64) -> String {
+        match offset.rem_euclid(self.memory_size as i64) {
+            0 => "ptr".to_string(),
+            off => format!("(ptr + {}) % MEMORY_SIZE", off),
+        }
+    }
 
-    generate(&mut formatter, instrs);
+    /// A value as a literal of the cell type, reduced into the range of the
+    /// cell. Java integers are signed, so native cells wrap to negative values.
+    fn literal(&self, value: i64) -> String {
+        match self.arithmetic {
+            Arithmetic::Native if self.cell_type == "int" => format!("{}", value as i32),
+            Arithmetic::Native => format!("{}L", value),
+            Arithmetic::Masked(mask) if self.cell_type == "int" => format!("{}", value as u64 & mask),
+            Arithmetic::Masked(mask) => format!("{}L", value as u64 & mask),
+            Arithmetic::Modular(n) => format!("{}L", (value as i128).rem_euclid(n as i128)),
+        }
+    }
+
+    /// An expression converting the byte `b` into a cell
+    fn byte_to_cell(&self) -> String {
+        match self.arithmetic {
+            Arithmetic::Native => "b".to_string(),
+            Arithmetic::Masked(mask) => format!("b & {}", self.literal(mask as i64)),
+            Arithmetic::Modular(n) => format!("b % {}L", n),
+        }
+    }
 
-    formatter.unindent();
-    formatter.add_line("}");
-    formatter.unindent();
-    formatter.add_line("}");
+    fn add_to_cell(&mut self, index: &str, value: &str) {
+        let line = match self.arithmetic {
+            Arithmetic::Native => format!("mem[{}] += {};", index, value),
+            Arithmetic::Masked(mask) => format!("mem[{i}] = (mem[{i}] + {v}) & {m};", i = index, v = value, m = self.literal(mask as i64)),
+            Arithmetic::Modular(_) => format!("mem[{i}] = addmod(mem[{i}], {v});", i = index, v = value),
+        };
+        self.code_buf.add_line(&line);
+    }
 
-    formatter.get_code()
+    /// The value of a cell as an unsigned number, for debug output
+    fn unsigned(&self, cell: &str) -> String {
+        match self.cell_size {
+            CellSize::Bits(32) => format!("Integer.toUnsignedString({})", cell),
+            CellSize::Bits(64) => format!("Long.toUnsignedString({})", cell),
+            _ => format!("String.valueOf({})", cell),
+        }
+    }
 }
 
+impl ir::ConstVisitor for JavaTranspiler {
+    type Ret = ();
 
-fn generate(formatter: &mut Formatter, instrs: &Vec<Instruction>) {
-    for instr in instrs {
-        match instr {
-            Instruction::Nop => {},
-            Instruction::Add{ offset, value } => {
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] += {};", offset, value));
-            },
-            Instruction::Set{ offset, value } => {
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = {};", offset, value));
-            },
-            Instruction::LinearLoop{ offset, factors } => {
-                for (off, factor) in factors {
-                    formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] += {} * mem[(ptr + {}) & 0xFFFF];", offset + off, factor, offset));
+    fn before(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.enter(inst));
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.leave(inst));
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            let (index, value) = (self.index(*offset), self.literal(*value));
+            self.add_to_cell(&index, &value);
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            self.code_buf.add_line(&format!("mem[{}] = {};", self.index(*offset), self.literal(*value)));
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            let source = format!("mem[{}]", self.index(*glob_offset));
+            for (&offset, &factor) in factors {
+                if offset == 0 || factor == 0 {
+                    continue;
                 }
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = 0;", offset));
-            },
-            Instruction::MovePtr(offset) => {
-                formatter.add_line(&format!("ptr += {};", offset));
-            },
-            Instruction::Loop(instructions) => {
-                formatter.add_line("while(mem[ptr & 0xFFFF] != 0) {");
-                formatter.indent();
-                generate(formatter, instructions);
-                formatter.unindent();
-                formatter.add_line("}");
-            },
-            Instruction::Read(offset) => {
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = (byte) System.in.read();", offset));
-            },
-            Instruction::Write(offset) => {
-                formatter.add_line(&format!("System.out.write(mem[(ptr + {}) & 0xFFFF]);", offset));
-                formatter.add_line(&format!("System.out.flush();"));
+                let index = self.index(glob_offset + offset);
+                let product = match self.arithmetic {
+                    Arithmetic::Modular(_) => format!("mulmod({}, {})", source, self.literal(factor)),
+                    _ if factor == 1 => source.clone(),
+                    _ => format!("{} * {}", source, self.literal(factor)),
+                };
+                self.add_to_cell(&index, &product);
+            }
+            self.code_buf.add_line(&format!("{} = 0;", source));
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            let index = self.index(*offset);
+            if index != "ptr" {
+                self.code_buf.add_line(&format!("ptr = {};", index));
             }
         }
     }
-}
\ No newline at end of file
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            // main itself would grow too large with all the top level loops
+            if self.depth > 0 && count_instructions(insts) <= MAX_INLINE_LOOP {
+                self.generate_loop(insts);
+                return;
+            }
+            let name = format!("loop{}", self.methods.len());
+            self.code_buf.add_line(&format!("{}();", name));
+
+            let mut method = Formatter::new();
+            method.indent();
+            add_mark(&mut method, self.marks.enter(l));
+            method.add_line(&format!("static void {}() throws IOException {{", name));
+            method.indent();
+            // reserve the name before nested loops take the next ones
+            self.methods.push(Formatter::new());
+            let index = self.methods.len() - 1;
+            std::mem::swap(&mut self.code_buf, &mut method);
+            let depth = std::mem::replace(&mut self.depth, 0);
+            self.generate_loop(insts);
+            self.depth = depth;
+            std::mem::swap(&mut self.code_buf, &mut method);
+            method.unindent();
+            method.add_line("}");
+            add_mark(&mut method, self.marks.leave(l));
+            self.methods[index] = method;
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            let index = self.index(*offset);
+            self.code_buf.add_line(&format!("mem[{i}] = readCell(mem[{i}]);", i = index));
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            let cast = if self.cell_type == "int" { "" } else { "(int) " };
+            self.code_buf.add_line(&format!("out.write({}mem[{}]);", cast, self.index(*offset)));
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        self.code_buf.add_line("out.flush();");
+        self.code_buf.add_line("System.err.print(\"#\" + ptr + \":\");");
+        self.code_buf.add_line(&format!("for (int i = {}; i <= {}; i++) {{", -DEBUG_WINDOW, DEBUG_WINDOW));
+        self.code_buf.indent();
+        self.code_buf.add_line(&format!("String cell = {};", self.unsigned("mem[Math.floorMod(ptr + i, MEMORY_SIZE)]")));
+        self.code_buf.add_line("System.err.print(i == 0 ? \" [\" + cell + \"]\" : \" \" + cell);");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+        self.code_buf.add_line("System.err.println();");
+    }
+}
+
+pub struct JavaBackend;
+
+impl Backend for JavaBackend {
+    fn name(&self) -> &'static str {
+        "java"
+    }
+
+    fn extension(&self) -> &'static str {
+        "java"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("//")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        // the sums of two cells have to fit into an unsigned long for addmod
+        require_cell_size(opts, match opts.cell_size {
+            CellSize::Bits(n) => n <= 64,
+            CellSize::Modular(n) => n < 1 << 63,
+            CellSize::Int => true,
+        })?;
+        require_memory_size(opts, opts.memory_size <= MAX_MEMORY_SIZE)?;
+        require_wrapping(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
@@ -0,0 +1,310 @@
+use super::super::{ir, formatter, options};
+use super::{Backend, BackendError, require_cell_size, require_wrapping};
+use super::source_map::{add_mark, SourceMarks};
+
+use ir::{Instruction, ConstVisitor, DEBUG_WINDOW};
+use formatter::Formatter;
+use options::*;
+
+// how the cells are stored and their arithmetic is done
+enum Arithmetic {
+    // typed arrays of up to 32 bits, wrapping on assignment; `mask` for cell
+    // sizes that are not exactly 8, 16 or 32 bits
+    Number{ mask: Option<u64> },
+    // BigUint64Array with BigInt arithmetic, for up to 64 bits
+    BigInt{ mask: Option<u64> },
+    // Float64Array, reduced with % after each operation
+    Modular(u64),
+}
+
+struct JsTranspiler {
+    pub code_buf: Formatter,
+    marks: SourceMarks,
+    memory_size: usize,
+    eof_mode: EofMode,
+    array_type: &'static str,
+    arithmetic: Arithmetic,
+}
+
+/// An ES module exporting `run(input)`, taking and returning a Uint8Array
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = JsTranspiler::create(opts, instrs);
+    transpiler.code_buf.add_line("export function run(input) {");
+    transpiler.generate_run(instrs);
+    transpiler.code_buf.get_code()
+}
+
+/// A script for node reading its input from stdin and writing to stdout
+pub fn transpile_node(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = JsTranspiler::create(opts, instrs);
+    transpiler.code_buf.add_line("function run(input) {");
+    transpiler.generate_run(instrs);
+    transpiler.code_buf.add_line("");
+    transpiler.code_buf.add_line("process.stdout.write(run(require('fs').readFileSync(0)));");
+    transpiler.code_buf.get_code()
+}
+
+impl JsTranspiler {
+    fn create(opts: &Options, instrs: &[Instruction]) -> Self {
+        let bits_mask = |n: usize| if n >= 64 { None } else { Some((1u64 << n) - 1) };
+        let (array_type, arithmetic) = match opts.cell_size {
+            CellSize::Bits(8) => ("Uint8Array", Arithmetic::Number{ mask: None }),
+            CellSize::Bits(16) => ("Uint16Array", Arithmetic::Number{ mask: None }),
+            CellSize::Bits(32) => ("Uint32Array", Arithmetic::Number{ mask: None }),
+            CellSize::Bits(n) if n < 8 => ("Uint8Array", Arithmetic::Number{ mask: bits_mask(n) }),
+            CellSize::Bits(n) if n < 16 => ("Uint16Array", Arithmetic::Number{ mask: bits_mask(n) }),
+            CellSize::Bits(n) if n < 32 => ("Uint32Array", Arithmetic::Number{ mask: bits_mask(n) }),
+            CellSize::Bits(n) => ("BigUint64Array", Arithmetic::BigInt{ mask: bits_mask(n) }),
+            CellSize::Int => ("BigUint64Array", Arithmetic::BigInt{ mask: None }),
+            CellSize::Modular(n) => ("Float64Array", Arithmetic::Modular(n)),
+        };
+
+        JsTranspiler {
+            code_buf: Formatter::new(),
+            marks: SourceMarks::new(opts, instrs),
+            memory_size: opts.memory_size.max(1),
+            eof_mode: opts.eof_mode,
+            array_type,
+            arithmetic,
+        }
+    }
+
+    fn generate_run(&mut self, instrs: &[Instruction]) {
+        self.code_buf.indent();
+        self.code_buf.add_line(&format!("const MEMORY_SIZE = {};", self.memory_size));
+        self.code_buf.add_line(&format!("const mem = new {}(MEMORY_SIZE);", self.array_type));
+        self.code_buf.add_line("const output = [];");
+        self.code_buf.add_line("let ptr = 0;");
+        self.code_buf.add_line("let inputPos = 0;");
+        self.code_buf.add_line("");
+        self.visit_instructions(instrs);
+        self.code_buf.add_line("return Uint8Array.from(output);");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+    }
+
+    /// The memory index of a cell relative to the pointer
+    fn index(&self, offset: i64) -> String {
+        match offset.rem_euclid(self.memory_size as i64) {
+            0 => "ptr".to_string(),
+            off => format!("(ptr + {}) % MEMORY_SIZE", off),
+        }
+    }
+
+    /// A value as a literal in the range of the cell
+    fn literal(&self, value: i64) -> String {
+        match self.arithmetic {
+            Arithmetic::Number{ mask } => {
+                let width = match self.array_type {
+                    "Uint8Array" => 0xFF,
+                    "Uint16Array" => 0xFFFF,
+                    _ => 0xFFFF_FFFF,
+                };
+                format!("{}", value as u64 & mask.unwrap_or(width))
+            },
+            Arithmetic::BigInt{ mask } => {
+                let value = value as u64;
+                format!("{}n", mask.map_or(value, |m| value & m))
+            },
+            Arithmetic::Modular(n) => format!("{}", value.rem_euclid(n as i64)),
+        }
+    }
+
+    /// Adds an expression to a cell
+    fn add_to_cell(&mut self, index: &str, value: &str) {
+        let line = match self.arithmetic {
+            Arithmetic::Number{ mask: None } | Arithmetic::BigInt{ mask: None } =>
+                format!("mem[{}] += {};", index, value),
+            Arithmetic::Number{ mask: Some(mask) } =>
+                format!("mem[{i}] = (mem[{i}] + {v}) & {m};", i = index, v = value, m = mask),
+            Arithmetic::BigInt{ mask: Some(mask) } =>
+                format!("mem[{i}] = (mem[{i}] + {v}) & {m}n;", i = index, v = value, m = mask),
+            Arithmetic::Modular(n) =>
+                format!("mem[{i}] = (mem[{i}] + {v}) % {n};", i = index, v = value, n = n),
+        };
+        self.code_buf.add_line(&line);
+    }
+
+    /// The product of the cell value `v` with a factor, ready to be added to a cell
+    fn product(&self, factor: i64) -> String {
+        let factor = self.literal(factor);
+        match self.arithmetic {
+            // the low 32 bits of the product, exact unlike v * factor
+            Arithmetic::Number{ .. } => format!("Math.imul(v, {}) >>> 0", factor),
+            Arithmetic::BigInt{ .. } => format!("v * {}", factor),
+            // products above 2^53 are not exact anymore
+            Arithmetic::Modular(n) if n <= 1 << 26 => format!("v * {} % {}", factor, n),
+            Arithmetic::Modular(n) => format!("Number(BigInt(v) * {}n % {}n)", factor, n),
+        }
+    }
+
+    /// An expression converting the byte `b` into a cell
+    fn byte_to_cell(&self) -> String {
+        match self.arithmetic {
+            Arithmetic::Number{ mask: None } => "b".to_string(),
+            Arithmetic::Number{ mask: Some(mask) } => format!("b & {}", mask),
+            Arithmetic::BigInt{ mask: None } => "BigInt(b)".to_string(),
+            Arithmetic::BigInt{ mask: Some(mask) } => format!("BigInt(b) & {}n", mask),
+            Arithmetic::Modular(n) => format!("b % {}", n),
+        }
+    }
+}
+
+impl ir::ConstVisitor for JsTranspiler {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.enter(inst));
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.leave(inst));
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            let (index, value) = (self.index(*offset), self.literal(*value));
+            self.add_to_cell(&index, &value);
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            self.code_buf.add_line(&format!("mem[{}] = {};", self.index(*offset), self.literal(*value)));
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            self.code_buf.add_line("{");
+            self.code_buf.indent();
+            self.code_buf.add_line(&format!("const v = mem[{}];", self.index(*glob_offset)));
+            for (&offset, &factor) in factors {
+                let index = self.index(glob_offset + offset);
+                let product = self.product(factor);
+                self.add_to_cell(&index, &product);
+            }
+            self.code_buf.add_line(&format!("mem[{}] = {};", self.index(*glob_offset), self.literal(0)));
+            self.code_buf.unindent();
+            self.code_buf.add_line("}");
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            let index = self.index(*offset);
+            if index != "ptr" {
+                self.code_buf.add_line(&format!("ptr = {};", index));
+            }
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            self.code_buf.add_line("while (mem[ptr]) {");
+            self.code_buf.indent();
+            self.visit_instructions(insts);
+            self.code_buf.unindent();
+            self.code_buf.add_line("}");
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            let index = self.index(*offset);
+            self.code_buf.add_line("if (inputPos < input.length) {");
+            self.code_buf.indent();
+            self.code_buf.add_line("const b = input[inputPos++];");
+            self.code_buf.add_line(&format!("mem[{}] = {};", index, self.byte_to_cell()));
+            self.code_buf.unindent();
+            match self.eof_mode {
+                EofMode::Zero => {
+                    self.code_buf.add_line("} else {");
+                    self.code_buf.add_line(&format!("    mem[{}] = {};", index, self.literal(0)));
+                },
+                EofMode::MinusOne => {
+                    self.code_buf.add_line("} else {");
+                    self.code_buf.add_line(&format!("    mem[{}] = {};", index, self.literal(-1)));
+                },
+                EofMode::Unchanged => {},
+            }
+            self.code_buf.add_line("}");
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            let byte = match self.arithmetic {
+                Arithmetic::BigInt{ .. } => format!("Number(mem[{}] & 255n)", self.index(*offset)),
+                _ => format!("mem[{}] & 255", self.index(*offset)),
+            };
+            self.code_buf.add_line(&format!("output.push({});", byte));
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        self.code_buf.add_line("{");
+        self.code_buf.indent();
+        self.code_buf.add_line("let line = '#' + ptr + ':';");
+        self.code_buf.add_line(&format!("for (let i = {}; i <= {}; i++) {{", -DEBUG_WINDOW, DEBUG_WINDOW));
+        self.code_buf.indent();
+        self.code_buf.add_line("const cell = mem[((ptr + i) % MEMORY_SIZE + MEMORY_SIZE) % MEMORY_SIZE];");
+        self.code_buf.add_line("line += i === 0 ? ' [' + cell + ']' : ' ' + cell;");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+        self.code_buf.add_line("console.error(line);");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+    }
+}
+
+pub struct JavaScriptBackend;
+
+impl Backend for JavaScriptBackend {
+    fn name(&self) -> &'static str {
+        "javascript"
+    }
+
+    fn extension(&self) -> &'static str {
+        "mjs"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("//")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        // Float64Array holds integers up to 2^53 exactly, which the sum of two cells must not exceed
+        require_cell_size(opts, !matches!(opts.cell_size, CellSize::Bits(n) if n > 64) &&
+                                !matches!(opts.cell_size, CellSize::Modular(n) if n > 1 << 52))?;
+        require_wrapping(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
+
+pub struct NodeBackend;
+
+impl Backend for NodeBackend {
+    fn name(&self) -> &'static str {
+        "node"
+    }
+
+    fn extension(&self) -> &'static str {
+        "js"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("//")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        JavaScriptBackend.supports(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile_node(opts, instrs)
+    }
+}
@@ -0,0 +1,353 @@
+use super::super::{ir, formatter, options};
+use super::{Backend, BackendError, require_wrapping};
+use super::source_map::{add_mark, SourceMarks};
+
+use ir::{Instruction, ConstVisitor, DEBUG_WINDOW};
+use formatter::Formatter;
+use options::*;
+
+// how the arithmetic on a cell is brought into the range of the cell size
+enum Arithmetic {
+    // llvm has integers of any width, they wrap by themselves
+    Wrapping(usize),
+    // cells are stored as i64, the arithmetic is done in i128 and reduced
+    // with urem after each operation
+    Modular(u64),
+}
+
+struct LlvmTranspiler {
+    pub code_buf: Formatter,
+    marks: SourceMarks,
+    memory_size: usize,
+    eof_mode: EofMode,
+    // the integer type of the cells in memory
+    cell_type: String,
+    // the integer type the arithmetic is done in
+    arith_type: String,
+    arithmetic: Arithmetic,
+    next_temp: usize,
+    next_loop: usize,
+    uses_debug: bool,
+}
+
+/// A module with a `main` function reading from `getchar` and writing with
+/// `putchar`, e.g. for `llc` or `clang`. Pointers are written in the opaque
+/// `ptr` syntax, LLVM 14 needs `-opaque-pointers` for it.
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = LlvmTranspiler::create(opts, instrs);
+    transpiler.visit_instructions(instrs);
+    transpiler.finalize()
+}
+
+impl LlvmTranspiler {
+    fn create(opts: &Options, instrs: &[Instruction]) -> Self {
+        let arithmetic = match opts.cell_size {
+            CellSize::Bits(n) => Arithmetic::Wrapping(n),
+            CellSize::Int => Arithmetic::Wrapping(64),
+            CellSize::Modular(n) => Arithmetic::Modular(n),
+        };
+        let (cell_type, arith_type) = match arithmetic {
+            Arithmetic::Wrapping(n) => (format!("i{}", n), format!("i{}", n)),
+            Arithmetic::Modular(_) => ("i64".to_string(), "i128".to_string()),
+        };
+
+        let mut transpiler = LlvmTranspiler {
+            code_buf: Formatter::new(),
+            marks: SourceMarks::new(opts, instrs),
+            memory_size: opts.memory_size.max(1),
+            eof_mode: opts.eof_mode,
+            cell_type,
+            arith_type,
+            arithmetic,
+            next_temp: 0,
+            next_loop: 0,
+            uses_debug: false,
+        };
+        transpiler.code_buf.add_line("define i32 @main() {");
+        transpiler.code_buf.add_line("entry:");
+        transpiler.code_buf.indent();
+        transpiler.code_buf.add_line("%ptr = alloca i64");
+        transpiler.code_buf.add_line("store i64 0, ptr %ptr");
+        transpiler
+    }
+
+    fn finalize(mut self) -> String {
+        self.code_buf.add_line("ret i32 0");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+
+        let mut module = Formatter::new();
+        module.add_line(&format!("@mem = internal global [{} x {}] zeroinitializer", self.memory_size, self.cell_type));
+        if self.uses_debug {
+            module.add_line(&string_constant("@.ptr_format", "#%lld:"));
+            module.add_line(&string_constant("@.cell_format", " %llu"));
+            module.add_line(&string_constant("@.current_format", " [%llu]"));
+            module.add_line(&string_constant("@.newline_format", "\n"));
+        }
+        module.add_line("");
+        module.add_line("declare i32 @getchar()");
+        module.add_line("declare i32 @putchar(i32)");
+        if self.uses_debug {
+            module.add_line("declare i32 @fflush(ptr)");
+            module.add_line("declare i32 @dprintf(i32, ptr, ...)");
+        }
+        module.add_line("");
+        module.get_code() + &self.code_buf.get_code()
+    }
+
+    fn temp(&mut self) -> String {
+        self.next_temp += 1;
+        format!("%t{}", self.next_temp - 1)
+    }
+
+    /// Adds an instruction assigning to a new temporary and returns its name
+    fn assign(&mut self, instruction: &str) -> String {
+        let temp = self.temp();
+        self.code_buf.add_line(&format!("{} = {}", temp, instruction));
+        temp
+    }
+
+    /// A pointer to the cell at an offset relative to the pointer
+    fn address(&mut self, offset: i64) -> String {
+        let ptr = self.assign("load i64, ptr %ptr");
+        let index = self.index(&ptr, offset);
+        self.assign(&format!("getelementptr inbounds [{} x {}], ptr @mem, i64 0, i64 {}",
+                             self.memory_size, self.cell_type, index))
+    }
+
+    /// The memory index of the cell at an offset relative to `ptr`
+    fn index(&mut self, ptr: &str, offset: i64) -> String {
+        match offset.rem_euclid(self.memory_size as i64) {
+            0 => ptr.to_string(),
+            off => {
+                let sum = self.assign(&format!("add i64 {}, {}", ptr, off));
+                if self.memory_size.is_power_of_two() {
+                    self.assign(&format!("and i64 {}, {}", sum, self.memory_size - 1))
+                }
+                else {
+                    self.assign(&format!("urem i64 {}, {}", sum, self.memory_size))
+                }
+            },
+        }
+    }
+
+    /// A value as a constant of the arithmetic type, reduced into the range of the cell
+    fn literal(&self, value: i64) -> String {
+        match self.arithmetic {
+            // llvm wants constants in the signed range of their type
+            Arithmetic::Wrapping(n) if n < 64 => format!("{}", (value << (64 - n)) >> (64 - n)),
+            Arithmetic::Wrapping(_) => format!("{}", value),
+            Arithmetic::Modular(n) => format!("{}", (value as i128).rem_euclid(n as i128)),
+        }
+    }
+
+    fn load(&mut self, address: &str) -> String {
+        let value = self.assign(&format!("load {}, ptr {}", self.cell_type, address));
+        match self.arithmetic {
+            Arithmetic::Wrapping(_) => value,
+            Arithmetic::Modular(_) => self.assign(&format!("zext i64 {} to i128", value)),
+        }
+    }
+
+    /// Stores a value of the arithmetic type, reducing it if necessary
+    fn store(&mut self, value: &str, address: &str) {
+        let value = match self.arithmetic {
+            Arithmetic::Wrapping(_) => value.to_string(),
+            Arithmetic::Modular(n) => {
+                let reduced = self.assign(&format!("urem i128 {}, {}", value, n));
+                self.assign(&format!("trunc i128 {} to i64", reduced))
+            },
+        };
+        self.code_buf.add_line(&format!("store {} {}, ptr {}", self.cell_type, value, address));
+    }
+
+    fn add_to_cell(&mut self, offset: i64, value: &str) {
+        let address = self.address(offset);
+        let cell = self.load(&address);
+        let sum = self.assign(&format!("add {} {}, {}", self.arith_type, cell, value));
+        self.store(&sum, &address);
+    }
+
+    /// Converts a value of the arithmetic type into an i32 or i64 with at
+    /// least the low byte intact
+    fn convert(&mut self, value: &str, to: usize) -> String {
+        let from = match self.arithmetic {
+            Arithmetic::Wrapping(n) => n,
+            Arithmetic::Modular(_) => 128,
+        };
+        if from < to {
+            self.assign(&format!("zext {} {} to i{}", self.arith_type, value, to))
+        }
+        else if from > to {
+            self.assign(&format!("trunc {} {} to i{}", self.arith_type, value, to))
+        }
+        else {
+            value.to_string()
+        }
+    }
+
+    /// Converts the i32 result of getchar into a cell
+    fn byte_to_cell(&mut self, byte: &str) -> String {
+        match self.arithmetic {
+            Arithmetic::Wrapping(32) => byte.to_string(),
+            Arithmetic::Wrapping(n) if n < 32 => self.assign(&format!("trunc i32 {} to i{}", byte, n)),
+            Arithmetic::Wrapping(n) => self.assign(&format!("zext i32 {} to i{}", byte, n)),
+            Arithmetic::Modular(n) => {
+                let wide = self.assign(&format!("zext i32 {} to i128", byte));
+                self.assign(&format!("urem i128 {}, {}", wide, n))
+            },
+        }
+    }
+
+    fn print_debug_cell(&mut self, ptr: &str, offset: i64) {
+        let index = self.index(ptr, offset);
+        let address = self.assign(&format!("getelementptr inbounds [{} x {}], ptr @mem, i64 0, i64 {}",
+                                           self.memory_size, self.cell_type, index));
+        let cell = self.load(&address);
+        let cell = self.convert(&cell, 64);
+        let format = if offset == 0 { "@.current_format" } else { "@.cell_format" };
+        self.code_buf.add_line(&format!("call i32 (i32, ptr, ...) @dprintf(i32 2, ptr {}, i64 {})", format, cell));
+    }
+}
+
+impl ir::ConstVisitor for LlvmTranspiler {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.enter(inst));
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.leave(inst));
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            let value = self.literal(*value);
+            self.add_to_cell(*offset, &value);
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            let address = self.address(*offset);
+            let value = self.literal(*value);
+            self.store(&value, &address);
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            let address = self.address(*glob_offset);
+            let v = self.load(&address);
+            for (&offset, &factor) in factors {
+                let product = self.assign(&format!("mul {} {}, {}", self.arith_type, v, self.literal(factor)));
+                self.add_to_cell(glob_offset + offset, &product);
+            }
+            self.store("0", &address);
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            let ptr = self.assign("load i64, ptr %ptr");
+            let index = self.index(&ptr, *offset);
+            if index != ptr {
+                self.code_buf.add_line(&format!("store i64 {}, ptr %ptr", index));
+            }
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            let name = format!("loop{}", self.next_loop);
+            self.next_loop += 1;
+            self.code_buf.add_line(&format!("br label %{}.cond", name));
+            self.code_buf.unindent();
+            self.code_buf.add_line(&format!("{}.cond:", name));
+            self.code_buf.indent();
+            let address = self.address(0);
+            let cell = self.assign(&format!("load {}, ptr {}", self.cell_type, address));
+            let nonzero = self.assign(&format!("icmp ne {} {}, 0", self.cell_type, cell));
+            self.code_buf.add_line(&format!("br i1 {}, label %{n}.body, label %{n}.end", nonzero, n = name));
+            self.code_buf.unindent();
+            self.code_buf.add_line(&format!("{}.body:", name));
+            self.code_buf.indent();
+            self.visit_instructions(insts);
+            self.code_buf.add_line(&format!("br label %{}.cond", name));
+            self.code_buf.unindent();
+            self.code_buf.add_line(&format!("{}.end:", name));
+            self.code_buf.indent();
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            let byte = self.assign("call i32 @getchar()");
+            let address = self.address(*offset);
+            let eof = self.assign(&format!("icmp slt i32 {}, 0", byte));
+            let value = self.byte_to_cell(&byte);
+            let eof_value = match self.eof_mode {
+                EofMode::Zero => "0".to_string(),
+                EofMode::MinusOne => self.literal(-1),
+                EofMode::Unchanged => self.load(&address),
+            };
+            let value = self.assign(&format!("select i1 {}, {t} {}, {t} {}", eof, eof_value, value, t = self.arith_type));
+            self.store(&value, &address);
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            let address = self.address(*offset);
+            let cell = self.load(&address);
+            let value = self.convert(&cell, 32);
+            let byte = self.assign(&format!("and i32 {}, 255", value));
+            self.code_buf.add_line(&format!("call i32 @putchar(i32 {})", byte));
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        self.uses_debug = true;
+        self.code_buf.add_line("call i32 @fflush(ptr null)");
+        let ptr = self.assign("load i64, ptr %ptr");
+        self.code_buf.add_line(&format!("call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @.ptr_format, i64 {})", ptr));
+        for offset in -DEBUG_WINDOW..=DEBUG_WINDOW {
+            self.print_debug_cell(&ptr, offset);
+        }
+        self.code_buf.add_line("call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @.newline_format)");
+    }
+}
+
+/// A global null terminated string
+fn string_constant(name: &str, text: &str) -> String {
+    let escaped: String = text.bytes().map(|b| match b {
+        b' '..=b'~' if b != b'"' && b != b'\\' => (b as char).to_string(),
+        b => format!("\\{:02X}", b),
+    }).collect();
+    format!("{} = private constant [{} x i8] c\"{}\\00\"", name, text.len() + 1, escaped)
+}
+
+pub struct LlvmBackend;
+
+impl Backend for LlvmBackend {
+    fn name(&self) -> &'static str {
+        "llvm"
+    }
+
+    fn extension(&self) -> &'static str {
+        "ll"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some(";")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        require_wrapping(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
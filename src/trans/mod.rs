@@ -1,12 +1,148 @@
+use super::ir;
+use super::options::{CellLayout, CellSize, EofMode, Options};
+use std::fmt;
 
-
-
+pub mod asm;
+pub mod bf;
 pub mod c;
+pub mod go;
 pub mod java;
+pub mod javascript;
+pub mod llvm;
 pub mod python;
+pub mod rust;
+pub mod source_map;
+pub mod wasm;
 pub mod zombie_ir;
 
 
+/// Why a backend cannot generate code for the given options
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendError {
+    UnsupportedCellSize(CellSize),
+    UnsupportedMemorySize(usize),
+    UnsupportedEofMode(EofMode),
+    UnsupportedCellLayout(CellLayout),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::UnsupportedCellSize(CellSize::Bits(n)) => write!(f, "{} bit cells are not supported", n),
+            BackendError::UnsupportedCellSize(CellSize::Modular(n)) => write!(f, "cells modulo {} are not supported", n),
+            BackendError::UnsupportedCellSize(CellSize::Int) => write!(f, "int cells are not supported"),
+            BackendError::UnsupportedMemorySize(size) => write!(f, "a memory size of {} cells is not supported", size),
+            BackendError::UnsupportedEofMode(mode) => {
+                let name = match mode {
+                    EofMode::Zero => "0",
+                    EofMode::MinusOne => "-1",
+                    EofMode::Unchanged => "unchanged",
+                };
+                write!(f, "eof mode {} is not supported", name)
+            },
+            BackendError::UnsupportedCellLayout(layout) => {
+                let name = match layout {
+                    CellLayout::Trusting => "trusting",
+                    CellLayout::Wrapping => "wrapping",
+                    CellLayout::Unbounded => "unbounded",
+                };
+                write!(f, "the {} cell layout is not supported", name)
+            },
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// A target language the instructions can be transpiled to
+pub trait Backend {
+    /// The name selecting the backend with `--transpile`
+    fn name(&self) -> &'static str;
+
+    /// The extension of files in the target language, without the dot
+    fn extension(&self) -> &'static str;
+
+    /// Checks whether the generated code can implement the options
+    fn supports(&self, _opts: &Options) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Generates the code, the options are already checked
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String;
+
+    fn emit(&self, opts: &Options, instrs: &[ir::Instruction]) -> Result<String, BackendError> {
+        require_cells(opts)?;
+        self.supports(opts)?;
+        Ok(self.transpile(opts, instrs))
+    }
+
+    /// What starts a comment running to the end of the line, `None` if the
+    /// language has no comments
+    fn comment(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Generates the code with the source range of its lines, see
+    /// `source_map::annotate`. With `comments`, the code of each range starts
+    /// with a comment showing the brainfuck it comes from.
+    fn emit_annotated(&self, opts: &Options, instrs: &[ir::Instruction], code: &str, comments: bool)
+            -> Result<(String, Vec<source_map::LineMapping>), BackendError> {
+        let opts = Options { source_marks: true, ..opts.clone() };
+        let marked = self.emit(&opts, instrs)?;
+        Ok(source_map::annotate(&marked, code, self.comment().filter(|_| comments)))
+    }
+}
+
+/// All backends, in the order they are listed
+pub fn backends() -> &'static [&'static dyn Backend] {
+    &[
+        &c::CBackend,
+        &java::JavaBackend,
+        &python::PythonBackend,
+        &javascript::JavaScriptBackend,
+        &javascript::NodeBackend,
+        &rust::RustBackend,
+        &go::GoBackend,
+        &llvm::LlvmBackend,
+        &wasm::WatBackend,
+        &asm::AsmBackend,
+        &bf::BfBackend,
+        &zombie_ir::ZombieIrBackend,
+    ]
+}
+
+/// The backend with the given name
+pub fn find(name: &str) -> Option<&'static dyn Backend> {
+    backends().iter().copied().find(|backend| backend.name() == name)
+}
+
+fn require_cell_size(opts: &Options, supported: bool) -> Result<(), BackendError> {
+    if supported { Ok(()) } else { Err(BackendError::UnsupportedCellSize(opts.cell_size.clone())) }
+}
+
+/// Rejects the cells no backend can generate code for, which hold no value at
+/// all
+fn require_cells(opts: &Options) -> Result<(), BackendError> {
+    require_cell_size(opts, !matches!(opts.cell_size, CellSize::Bits(0) | CellSize::Modular(0)))
+}
+
+fn require_cell_layout(opts: &Options, supported: bool) -> Result<(), BackendError> {
+    if supported { Ok(()) } else { Err(BackendError::UnsupportedCellLayout(opts.cell_layout.clone())) }
+}
+
+/// For backends that wrap the pointer around the tape, which runs the programs
+/// trusted to stay on it just as well, but cannot grow it
+fn require_wrapping(opts: &Options) -> Result<(), BackendError> {
+    require_cell_layout(opts, opts.cell_layout != CellLayout::Unbounded)
+}
+
+fn require_memory_size(opts: &Options, supported: bool) -> Result<(), BackendError> {
+    if supported { Ok(()) } else { Err(BackendError::UnsupportedMemorySize(opts.memory_size)) }
+}
+
+fn require_eof_mode(opts: &Options, supported: &[EofMode]) -> Result<(), BackendError> {
+    if supported.contains(&opts.eof_mode) { Ok(()) } else { Err(BackendError::UnsupportedEofMode(opts.eof_mode)) }
+}
 
 fn hex_bitmask(bits: usize) -> String {
     let fs = bits / 4;
@@ -19,3 +155,22 @@ fn hex_bitmask(bits: usize) -> String {
         _ => ""
     }.to_owned() + &"F".repeat(fs)
 }
+
+/// Whether the program contains `Debug` instructions, for backends that only
+/// declare what dumping the tape needs if it is used
+fn contains_debug(instrs: &[ir::Instruction]) -> bool {
+    instrs.iter().any(|inst| match inst {
+        ir::Instruction::Debug => true,
+        ir::Instruction::Loop(body, _) => contains_debug(body),
+        _ => false,
+    })
+}
+
+/// Number of instructions including the ones in loops, for backends moving
+/// large loops into functions of their own
+fn count_instructions(instrs: &[ir::Instruction]) -> usize {
+    instrs.iter().map(|inst| match inst {
+        ir::Instruction::Loop(body, _) => 1 + count_instructions(body),
+        _ => 1,
+    }).sum()
+}
@@ -1,64 +1,261 @@
 use super::super::{ir, formatter, options};
-use super::hex_bitmask;
+use super::{hex_bitmask, require_wrapping, Backend, BackendError};
+use super::source_map::{add_mark, SourceMarks};
 
-use ir::Instruction;
+use ir::{Instruction, ConstVisitor, DEBUG_WINDOW};
 use formatter::Formatter;
 use options::*;
 
-pub fn transpile(opts: &Options, instrs: &Vec<ir::Instruction>) -> String {
-    let mut formatter = Formatter::new();
+// CPython rejects more than 20 nested blocks in a function, deeper loops get
+// a function of their own
+const MAX_NESTING: usize = 16;
+
+// bytes of output collected before they are written, as many as java's
+// BufferedOutputStream holds
+const OUTPUT_BUFFER: usize = 8192;
 
-    formatter.add_line("import sys");
-    formatter.add_line("mem = [0] * 0x10000");
-    formatter.add_line("ptr = 0");
+struct PythonTranspiler {
+    pub code_buf: Formatter,
+    marks: SourceMarks,
+    // a function for each top level or deeply nested loop, local variables
+    // are a lot faster than module level ones in CPython
+    functions: Vec<Formatter>,
+    // loops the current function is nested in
+    depth: usize,
+    memory_size: usize,
+    cell_size: CellSize,
+    eof_mode: EofMode,
+}
+
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = PythonTranspiler {
+        code_buf: Formatter::new(),
+        marks: SourceMarks::new(opts, instrs),
+        functions: Vec::new(),
+        depth: 0,
+        memory_size: opts.memory_size.max(1),
+        cell_size: opts.cell_size.clone(),
+        eof_mode: opts.eof_mode,
+    };
 
-    generate(&mut formatter, instrs, opts);
+    transpiler.code_buf.indent();
+    transpiler.code_buf.add_line(&format!("mem = [0] * {}", transpiler.memory_size));
+    transpiler.code_buf.add_line("ptr = 0");
+    transpiler.visit_instructions(instrs);
+    transpiler.code_buf.add_line("flush()");
+    transpiler.code_buf.unindent();
 
+    let mut formatter = Formatter::new();
+    formatter.add_line(&transpiler.runtime());
+    for function in transpiler.functions {
+        formatter.add_line(&function.get_code());
+    }
+    formatter.add_line("def main():");
+    formatter.add_line(&transpiler.code_buf.get_code());
+    formatter.add_line("main()");
     formatter.get_code()
 }
 
+impl PythonTranspiler {
+    /// The imports and the functions for input and output
+    fn runtime(&self) -> String {
+        let eof_value = match self.eof_mode {
+            EofMode::Zero => "0".to_string(),
+            EofMode::MinusOne => self.literal(-1),
+            EofMode::Unchanged => "current".to_string(),
+        };
+        format!(r#"import sys
 
-fn generate(formatter: &mut Formatter, instrs: &Vec<Instruction>, opts: &Options) {
+output = bytearray()
 
-    let cell_mask = match opts.cell_size {
-        CellSize::Bits(n) => {
-            " & 0x".to_owned() + &hex_bitmask(n)
-        },
-        CellSize::Modular(n) => " % ".to_owned() + &n.to_string(),
-        CellSize::Int => "".to_owned()
-    };
-    for instr in instrs {
-        match instr {
-            Instruction::Nop => {},
-            Instruction::Add{ offset, value } => {
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = (mem[(ptr + {}) & 0xFFFF] + {}){}", offset, offset, value, cell_mask));
-            },
-            Instruction::Set{ offset, value } => {
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = {}{}", offset, value, cell_mask));
-            },
-            Instruction::LinearLoop{ offset, factors } => {
-                for (off, factor) in factors {
-                    formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = (mem[(ptr + {}) & 0xFFFF] + {} * mem[(ptr + {}) & 0xFFFF]){}",
-                                                offset + off, offset + off, factor, offset, cell_mask));
+
+def flush():
+    sys.stdout.buffer.write(output)
+    sys.stdout.buffer.flush()
+    output.clear()
+
+
+# reads a byte into a cell that holds `current`
+def read_cell(current):
+    flush()
+    byte = sys.stdin.buffer.read(1)
+    return {} if byte else {}
+
+"#, self.reduce("byte[0]"), eof_value)
+    }
+
+    /// The index of a cell relative to the pointer
+    fn index(&self, offset: i64) -> String {
+        match offset.rem_euclid(self.memory_size as i64) {
+            0 => "ptr".to_string(),
+            off => format!("(ptr + {}) % {}", off, self.memory_size),
+        }
+    }
+
+    /// A value in the range of the cell
+    fn literal(&self, value: i64) -> String {
+        match self.cell_size {
+            CellSize::Bits(n) if n < 64 => (value as u64 & ((1 << n) - 1)).to_string(),
+            CellSize::Bits(_) if value >= 0 => value.to_string(),
+            CellSize::Modular(n) => (value as i128).rem_euclid(n as i128).to_string(),
+            CellSize::Bits(_) | CellSize::Int => self.reduce(&value.to_string()),
+        }
+    }
+
+    /// Brings the result of an addition or multiplication into the range of
+    /// the cell
+    fn reduce(&self, value: &str) -> String {
+        match self.cell_size {
+            CellSize::Bits(n) => format!("{} & 0x{}", value, hex_bitmask(n)),
+            CellSize::Modular(n) => format!("{} % {}", value, n),
+            CellSize::Int => value.to_string(),
+        }
+    }
+
+    fn add_to_cell(&mut self, index: &str, value: &str) {
+        let sum = format!("(mem[{}] + {})", index, value);
+        self.code_buf.add_line(&format!("mem[{}] = {}", index, self.reduce(&sum)));
+    }
+
+    fn generate_loop(&mut self, insts: &[Instruction]) {
+        self.code_buf.add_line("while mem[ptr]:");
+        self.code_buf.indent();
+        self.depth += 1;
+        let lines = self.code_buf.line_count();
+        self.visit_instructions(insts);
+        // instructions like moves around the whole tape generate no code
+        if self.code_buf.line_count() == lines {
+            self.code_buf.add_line("pass");
+        }
+        self.depth -= 1;
+        self.code_buf.unindent();
+    }
+}
+
+impl ir::ConstVisitor for PythonTranspiler {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.enter(inst));
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.leave(inst));
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            let (index, value) = (self.index(*offset), self.literal(*value));
+            self.add_to_cell(&index, &value);
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            self.code_buf.add_line(&format!("mem[{}] = {}", self.index(*offset), self.literal(*value)));
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            self.code_buf.add_line(&format!("v = mem[{}]", self.index(*glob_offset)));
+            for (&offset, &factor) in factors {
+                if offset == 0 || factor == 0 {
+                    continue;
                 }
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = 0", offset));
-            },
-            Instruction::MovePtr(offset) => {
-                formatter.add_line(&format!("ptr += {}", offset));
-            },
-            Instruction::Loop(instructions) => {
-                formatter.add_line("while mem[ptr & 0xFFFF] != 0:");
-                formatter.indent();
-                generate(formatter, instructions, opts);
-                formatter.unindent();
-            },
-            Instruction::Read(offset) => {
-                formatter.add_line(&format!("mem[(ptr + {}) & 0xFFFF] = sys.stdin.buffer.read(1){}", offset, cell_mask));
-            },
-            Instruction::Write(offset) => {
-                formatter.add_line(&format!("sys.stdout.buffer.write(mem[(ptr + {}) & 0xFFFF].to_bytes(1, 'little'))", offset));
-                formatter.add_line("sys.stdout.buffer.flush()");
+                let product = match factor {
+                    1 => "v".to_string(),
+                    _ => format!("v * {}", self.literal(factor)),
+                };
+                self.add_to_cell(&self.index(glob_offset + offset), &product);
+            }
+            self.code_buf.add_line(&format!("mem[{}] = 0", self.index(*glob_offset)));
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            let index = self.index(*offset);
+            if index != "ptr" {
+                self.code_buf.add_line(&format!("ptr = {}", index));
             }
         }
     }
-}
\ No newline at end of file
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            if self.depth > 0 && self.depth < MAX_NESTING {
+                self.generate_loop(insts);
+                return;
+            }
+            let name = format!("loop_{}", self.functions.len());
+            self.code_buf.add_line(&format!("ptr = {}(mem, ptr)", name));
+
+            let mut function = Formatter::new();
+            add_mark(&mut function, self.marks.enter(l));
+            function.add_line(&format!("def {}(mem, ptr):", name));
+            function.indent();
+            // reserve the name before nested loops take the next ones
+            self.functions.push(Formatter::new());
+            let index = self.functions.len() - 1;
+            std::mem::swap(&mut self.code_buf, &mut function);
+            let depth = std::mem::replace(&mut self.depth, 0);
+            self.generate_loop(insts);
+            self.depth = depth;
+            self.code_buf.add_line("return ptr");
+            std::mem::swap(&mut self.code_buf, &mut function);
+            function.unindent();
+            add_mark(&mut function, self.marks.leave(l));
+            function.add_line("");
+            self.functions[index] = function;
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            let index = self.index(*offset);
+            self.code_buf.add_line(&format!("mem[{i}] = read_cell(mem[{i}])", i = index));
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            self.code_buf.add_line(&format!("output.append(mem[{}] & 0xFF)", self.index(*offset)));
+            self.code_buf.add_line(&format!("if len(output) >= {}:", OUTPUT_BUFFER));
+            self.code_buf.indent();
+            self.code_buf.add_line("flush()");
+            self.code_buf.unindent();
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        self.code_buf.add_line("flush()");
+        self.code_buf.add_line(&format!("print('#%d:' % ptr + ''.join((' [%d]' if i == 0 else ' %d') % mem[(ptr + i) % {}] for i in range({}, {})), file=sys.stderr)",
+                                        self.memory_size, -DEBUG_WINDOW, DEBUG_WINDOW + 1));
+    }
+}
+
+pub struct PythonBackend;
+
+impl Backend for PythonBackend {
+    fn name(&self) -> &'static str {
+        "python"
+    }
+
+    fn extension(&self) -> &'static str {
+        "py"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("#")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        // python integers have no limit, so every cell size works
+        require_wrapping(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
@@ -0,0 +1,300 @@
+use super::super::{ir, formatter, options};
+use super::{count_instructions, Backend, BackendError, require_cell_size, require_wrapping};
+use super::source_map::{add_mark, SourceMarks};
+
+use ir::{Instruction, ConstVisitor, DEBUG_WINDOW};
+use formatter::Formatter;
+use options::*;
+
+// how the arithmetic on a cell is brought into the range of the cell size
+enum Arithmetic {
+    // the cell size matches a rust integer type, Wrapping does everything
+    Native,
+    // a smaller bit width in a larger type, masked after each operation
+    Masked(u128),
+    Modular(u64),
+}
+
+// loops with more instructions than this get their own function, rustc takes
+// very long to borrow check huge functions
+const MAX_INLINE_LOOP: usize = 200;
+
+struct RustTranspiler {
+    pub code_buf: Formatter,
+    marks: SourceMarks,
+    // functions for the large loops, appended after main
+    functions: Vec<Formatter>,
+    memory_size: usize,
+    eof_mode: EofMode,
+    value_type: &'static str,
+    arithmetic: Arithmetic,
+}
+
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    let mut transpiler = RustTranspiler::create(opts, instrs);
+    transpiler.visit_instructions(instrs);
+    transpiler.finalize();
+    transpiler.code_buf.get_code()
+}
+
+impl RustTranspiler {
+    fn create(opts: &Options, instrs: &[Instruction]) -> Self {
+        let (value_type, arithmetic) = match opts.cell_size {
+            CellSize::Bits(8) => ("u8", Arithmetic::Native),
+            CellSize::Bits(16) => ("u16", Arithmetic::Native),
+            CellSize::Bits(32) => ("u32", Arithmetic::Native),
+            CellSize::Bits(64) | CellSize::Int => ("u64", Arithmetic::Native),
+            CellSize::Bits(n) if n >= 128 => ("u128", Arithmetic::Native),
+            CellSize::Bits(n) => {
+                let value_type = match n {
+                    0..=8 => "u8",
+                    9..=16 => "u16",
+                    17..=32 => "u32",
+                    33..=64 => "u64",
+                    _ => "u128",
+                };
+                (value_type, Arithmetic::Masked((1u128 << n) - 1))
+            },
+            CellSize::Modular(n) => ("u64", Arithmetic::Modular(n)),
+        };
+
+        let mut transpiler = RustTranspiler {
+            code_buf: Formatter::new(),
+            marks: SourceMarks::new(opts, instrs),
+            functions: Vec::new(),
+            memory_size: opts.memory_size.max(1),
+            eof_mode: opts.eof_mode,
+            value_type,
+            arithmetic,
+        };
+
+        transpiler.code_buf.add_line(&format!(r#"use std::io::{{self, Read, Write}};
+use std::num::Wrapping;
+
+const MEMORY_SIZE: usize = {size};
+
+type Cell = Wrapping<{vt}>;
+type Input = io::StdinLock<'static>;
+type Output = io::BufWriter<io::StdoutLock<'static>>;
+
+#[allow(dead_code)]
+fn read_byte(input: &mut Input, output: &mut Output) -> Option<u8> {{
+    output.flush().unwrap();
+    let mut byte = [0u8];
+    match input.read(&mut byte) {{
+        Ok(1) => Some(byte[0]),
+        _ => None,
+    }}
+}}
+
+#[allow(unused_mut, unused_variables, unused_assignments)]
+fn main() {{
+    let mut memory: Vec<Cell> = vec![Wrapping(0); MEMORY_SIZE];
+    let mem = &mut memory[..];
+    let mut ptr: usize = 0;
+    let input = &mut io::stdin().lock();
+    let output = &mut io::BufWriter::new(io::stdout().lock());
+"#, size = transpiler.memory_size, vt = value_type));
+        transpiler.code_buf.indent();
+        transpiler
+    }
+
+    fn finalize(&mut self) {
+        self.code_buf.add_line("output.flush().unwrap();");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+        for function in self.functions.drain(..) {
+            self.code_buf.add_line("");
+            self.code_buf.add_line(&function.get_code());
+        }
+    }
+
+    fn generate_loop(&mut self, insts: &[Instruction]) {
+        self.code_buf.add_line("while mem[ptr].0 != 0 {");
+        self.code_buf.indent();
+        self.visit_instructions(insts);
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+    }
+
+    /// The memory index of a cell relative to the pointer
+    fn index(&self, offset: i64) -> String {
+        match offset.rem_euclid(self.memory_size as i64) {
+            0 => "ptr".to_string(),
+            off => format!("(ptr + {}) % MEMORY_SIZE", off),
+        }
+    }
+
+    /// A value as a literal of the cell type, reduced into the range of the cell
+    fn literal(&self, value: i64) -> String {
+        let value = match self.arithmetic {
+            Arithmetic::Native => match self.value_type {
+                "u8" => value as u8 as u128,
+                "u16" => value as u16 as u128,
+                "u32" => value as u32 as u128,
+                "u64" => value as u64 as u128,
+                _ => value as u128,
+            },
+            Arithmetic::Masked(mask) => value as u128 & mask,
+            // moduli above i64::MAX are negative as an i64
+            Arithmetic::Modular(n) => (value as i128).rem_euclid(n as i128) as u128,
+        };
+        format!("{}{}", value, self.value_type)
+    }
+
+    /// An expression converting the byte `b` into a cell
+    fn byte_to_cell(&self) -> String {
+        match self.arithmetic {
+            Arithmetic::Native => format!("Wrapping(b as {})", self.value_type),
+            Arithmetic::Masked(mask) => format!("Wrapping(b as {} & {})", self.value_type, mask),
+            Arithmetic::Modular(n) => format!("Wrapping(b as u64 % {})", n),
+        }
+    }
+
+    fn add_to_cell(&mut self, index: &str, value: &str) {
+        let line = match self.arithmetic {
+            Arithmetic::Native => format!("mem[{i}] += Wrapping({v});", i = index, v = value),
+            Arithmetic::Masked(mask) => format!("mem[{i}] = Wrapping(mem[{i}].0.wrapping_add({v}) & {m});", i = index, v = value, m = mask),
+            Arithmetic::Modular(n) => format!("mem[{i}] = Wrapping(((mem[{i}].0 as u128 + {v} as u128) % {n}) as u64);", i = index, v = value, n = n),
+        };
+        self.code_buf.add_line(&line);
+    }
+}
+
+impl ir::ConstVisitor for RustTranspiler {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.enter(inst));
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        add_mark(&mut self.code_buf, self.marks.leave(inst));
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            let (index, value) = (self.index(*offset), self.literal(*value));
+            self.add_to_cell(&index, &value);
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            self.code_buf.add_line(&format!("mem[{}] = Wrapping({});", self.index(*offset), self.literal(*value)));
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            self.code_buf.add_line(&format!("let v = mem[{}].0;", self.index(*glob_offset)));
+            for (&offset, &factor) in factors {
+                let index = self.index(glob_offset + offset);
+                let product = match self.arithmetic {
+                    Arithmetic::Modular(n) => format!("(v as u128 * {} as u128 % {})", self.literal(factor), n),
+                    _ => format!("v.wrapping_mul({})", self.literal(factor)),
+                };
+                self.add_to_cell(&index, &product);
+            }
+            self.code_buf.add_line(&format!("mem[{}] = Wrapping(0);", self.index(*glob_offset)));
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            let index = self.index(*offset);
+            if index != "ptr" {
+                self.code_buf.add_line(&format!("ptr = {};", index));
+            }
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            if count_instructions(insts) <= MAX_INLINE_LOOP {
+                self.generate_loop(insts);
+                return;
+            }
+            let name = format!("loop_{}", self.functions.len());
+            self.code_buf.add_line(&format!("ptr = {}(mem, ptr, input, output);", name));
+
+            let mut function = Formatter::new();
+            add_mark(&mut function, self.marks.enter(l));
+            function.add_line("#[allow(unused_mut, unused_variables, unused_assignments)]");
+            function.add_line(&format!("fn {}(mem: &mut [Cell], mut ptr: usize, input: &mut Input, output: &mut Output) -> usize {{", name));
+            function.indent();
+            // reserve the name before nested loops take the next ones
+            self.functions.push(Formatter::new());
+            let index = self.functions.len() - 1;
+            std::mem::swap(&mut self.code_buf, &mut function);
+            self.generate_loop(insts);
+            self.code_buf.add_line("ptr");
+            std::mem::swap(&mut self.code_buf, &mut function);
+            function.unindent();
+            function.add_line("}");
+            add_mark(&mut function, self.marks.leave(l));
+            self.functions[index] = function;
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            let index = self.index(*offset);
+            let byte_to_cell = self.byte_to_cell();
+            let eof = match self.eof_mode {
+                EofMode::Zero => format!("mem[{}] = Wrapping(0)", index),
+                EofMode::MinusOne => format!("mem[{}] = Wrapping({})", index, self.literal(-1)),
+                EofMode::Unchanged => "{}".to_string(),
+            };
+            self.code_buf.add_line("match read_byte(input, output) {");
+            self.code_buf.indent();
+            self.code_buf.add_line(&format!("Some(b) => mem[{}] = {},", index, byte_to_cell));
+            self.code_buf.add_line(&format!("None => {},", eof));
+            self.code_buf.unindent();
+            self.code_buf.add_line("}");
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            self.code_buf.add_line(&format!("output.write_all(&[mem[{}].0 as u8]).unwrap();", self.index(*offset)));
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        self.code_buf.add_line("output.flush().unwrap();");
+        self.code_buf.add_line("eprint!(\"#{}:\", ptr);");
+        self.code_buf.add_line(&format!("for i in {}i64..={} {{", -DEBUG_WINDOW, DEBUG_WINDOW));
+        self.code_buf.indent();
+        self.code_buf.add_line("let cell = mem[(ptr as i64 + i).rem_euclid(MEMORY_SIZE as i64) as usize];");
+        self.code_buf.add_line("if i == 0 { eprint!(\" [{}]\", cell); } else { eprint!(\" {}\", cell); }");
+        self.code_buf.unindent();
+        self.code_buf.add_line("}");
+        self.code_buf.add_line("eprintln!();");
+    }
+}
+
+pub struct RustBackend;
+
+impl Backend for RustBackend {
+    fn name(&self) -> &'static str {
+        "rust"
+    }
+
+    fn extension(&self) -> &'static str {
+        "rs"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("//")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        require_cell_size(opts, !matches!(opts.cell_size, CellSize::Bits(n) if n > 128))?;
+        require_wrapping(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
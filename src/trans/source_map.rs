@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fmt;
+use super::super::{ir, formatter, options, parser};
+
+use ir::{Instruction, Span};
+use formatter::Formatter;
+use options::Options;
+
+// starts the lines marking where the code of an instruction begins and ends,
+// no backend generates it otherwise
+const MARK: &str = "\u{1}source";
+
+// source characters shown in the comments
+const SNIPPET_LENGTH: usize = 40;
+
+/// A line in the generated code telling where the code of an instruction
+/// begins or ends, `annotate` resolves them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mark {
+    Enter(Span),
+    Leave,
+}
+
+impl fmt::Display for Mark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mark::Enter(span) => write!(f, "{} {} {}", MARK, span.start, span.end),
+            Mark::Leave => write!(f, "{}", MARK),
+        }
+    }
+}
+
+/// The source ranges of the instructions, if the options ask transpilers to
+/// mark their code
+pub struct SourceMarks {
+    ranges: HashMap<*const Instruction, Span>,
+}
+
+impl SourceMarks {
+    pub fn new(opts: &Options, instrs: &[Instruction]) -> Self {
+        let ranges = if opts.source_marks {
+            // the length of the code is not known here, `annotate` cuts the
+            // ranges off at its end
+            ir::source_ranges(instrs, Span{ start: 0, end: usize::MAX })
+        }
+        else {
+            HashMap::new()
+        };
+        SourceMarks { ranges }
+    }
+
+    /// The mark before the code of an instruction
+    pub fn enter(&self, inst: &Instruction) -> Option<Mark> {
+        self.ranges.get(&(inst as *const _)).map(|&span| Mark::Enter(span))
+    }
+
+    /// The mark after the code of an instruction
+    pub fn leave(&self, inst: &Instruction) -> Option<Mark> {
+        self.ranges.get(&(inst as *const _)).map(|_| Mark::Leave)
+    }
+}
+
+/// The marks as lines around the instructions printed by `ir::print`
+impl ir::Annotations for SourceMarks {
+    fn before(&self, inst: &Instruction) -> Option<String> {
+        self.enter(inst).map(|mark| mark.to_string())
+    }
+
+    fn after(&self, inst: &Instruction) -> Option<String> {
+        self.leave(inst).map(|mark| mark.to_string())
+    }
+}
+
+pub fn add_mark(formatter: &mut Formatter, mark: Option<Mark>) {
+    if let Some(mark) = mark {
+        formatter.add_annotation(&mark.to_string());
+    }
+}
+
+/// Lines of generated code that come from a range of the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineMapping {
+    // 1-based, the last one included
+    pub first_line: usize,
+    pub last_line: usize,
+    pub source: Span,
+}
+
+impl LineMapping {
+    /// The mapping as a JSON object, e.g. `{"lines":[12,15],"source":[10,14]}`
+    /// with the byte range of the source
+    pub fn to_json(&self) -> String {
+        format!("{{\"lines\":[{},{}],\"source\":[{},{}]}}",
+                self.first_line, self.last_line, self.source.start, self.source.end)
+    }
+}
+
+/// Removes the marks from code generated with `Options::source_marks` and
+/// returns it with the source range of its lines. With a `comment` prefix,
+/// the code of each range starts with a comment showing its position and
+/// commands in `code`.
+pub fn annotate(marked: &str, code: &str, comment: Option<&str>) -> (String, Vec<LineMapping>) {
+    let mut annotated = String::new();
+    let mut mappings: Vec<LineMapping> = Vec::new();
+    // the ranges of the instructions the current line is nested in
+    let mut ranges: Vec<Span> = Vec::new();
+    let mut commented: Option<Span> = None;
+    let mut line_number = 0;
+
+    let mut add_line = |line: &str, range: Option<Span>, annotated: &mut String| {
+        line_number += 1;
+        *annotated += line;
+        *annotated += "\n";
+        let range = match range {
+            Some(range) => range,
+            None => return,
+        };
+        match mappings.last_mut() {
+            Some(last) if last.source == range && last.last_line + 1 == line_number => last.last_line = line_number,
+            _ => mappings.push(LineMapping{ first_line: line_number, last_line: line_number, source: range }),
+        }
+    };
+
+    for line in marked.lines() {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let mark = match line.trim_start().strip_prefix(MARK) {
+            Some(mark) => mark,
+            None => {
+                add_line(line, ranges.last().copied(), &mut annotated);
+                continue;
+            }
+        };
+        let bounds: Vec<usize> = mark.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+        if let [start, end] = bounds[..] {
+            let range = Span{ start: start.min(code.len()), end: end.min(code.len()) };
+            ranges.push(range);
+            if let (Some(prefix), true) = (comment, commented != Some(range)) {
+                let (start, snippet) = parser::commands_in(code, range, SNIPPET_LENGTH);
+                let (line, col) = parser::line_col(code, start);
+                let text = format!("{}{} {}:{} {}", indent, prefix, line, col, snippet);
+                add_line(text.trim_end(), Some(range), &mut annotated);
+                commented = Some(range);
+            }
+        }
+        else {
+            ranges.pop();
+        }
+    }
+    // the formatter ends the code with a newline, keep it that way
+    if !marked.ends_with('\n') {
+        annotated.pop();
+    }
+    (annotated, mappings)
+}
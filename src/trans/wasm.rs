@@ -0,0 +1,806 @@
+use super::super::{ir, formatter, options};
+use super::{Backend, BackendError, require_cell_size, require_wrapping};
+use super::source_map::{Mark, SourceMarks};
+
+use ir::{Instruction, ConstVisitor};
+use formatter::Formatter;
+use options::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ValType {
+    I32,
+    I64,
+}
+
+// how many bytes of linear memory a cell takes
+#[derive(Clone, Copy, PartialEq)]
+enum Storage {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+// how the arithmetic on a cell is brought into the range of the cell size
+#[derive(Clone, Copy)]
+enum Arithmetic {
+    // wrapping on the store into a cell of the matching width
+    Native,
+    // a smaller bit width, masked after each operation
+    Masked(u64),
+    // in i64, reduced with rem_u after each operation
+    Modular(u64),
+    // moduli above 2^32, where products do not fit into an i64 anymore and
+    // the helper functions $addmod and $mulmod do the arithmetic
+    LargeModular(u64),
+}
+
+// the webassembly instructions the backend generates
+#[derive(Clone, Copy)]
+enum Op {
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    I32Const(i32),
+    I64Const(i64),
+    Load(ValType, Storage),
+    Store(ValType, Storage),
+    Call(u32),
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Select,
+    I32GeS,
+    I32Add,
+    I32And,
+    I32Mul,
+    I32RemU,
+    I32ShrU,
+    I32WrapI64,
+    I64Eqz,
+    I64Ne,
+    I64GeU,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64And,
+    I64RemU,
+    I64ShrU,
+    I64ExtendI32U,
+}
+
+// imported functions, they come first in the function index space
+const READ: u32 = 0;
+const WRITE: u32 = 1;
+const DEBUG: u32 = 2;
+
+// locals of the run function
+const PTR: u32 = 0;
+const ADDR: u32 = 1;
+const BYTE: u32 = 2;
+const VALUE: u32 = 3;
+
+struct Function {
+    name: &'static str,
+    export: Option<&'static str>,
+    params: Vec<(&'static str, ValType)>,
+    result: Option<ValType>,
+    locals: Vec<(&'static str, ValType)>,
+    body: Vec<Op>,
+    // source marks for the text format, with the index of the op they precede
+    marks: Vec<(usize, Mark)>,
+}
+
+struct Import {
+    name: &'static str,
+    params: Vec<ValType>,
+    result: Option<ValType>,
+}
+
+struct Module {
+    imports: Vec<Import>,
+    functions: Vec<Function>,
+    // in 64 KiB pages
+    memory_pages: u32,
+}
+
+struct WasmTranspiler {
+    body: Vec<Op>,
+    source_marks: SourceMarks,
+    marks: Vec<(usize, Mark)>,
+    // size of the tape in bytes, the pointer is a byte address into it
+    tape_bytes: u32,
+    storage: Storage,
+    value_type: ValType,
+    arithmetic: Arithmetic,
+    eof_mode: EofMode,
+    // env.debug is imported, which moves the helper functions back by one
+    uses_debug: bool,
+}
+
+/// A module in the WebAssembly text format. It imports `env.read`, returning
+/// the next input byte or -1 at the end of the input, and `env.write`, taking
+/// the byte to output, and exports its memory and a `run` function.
+/// If the program contains debug instructions, it also imports `env.debug`,
+/// which is called with the index of the current cell.
+pub fn transpile(opts: &Options, instrs: &[ir::Instruction]) -> String {
+    generate_module(opts, instrs).to_text()
+}
+
+/// The same module as `transpile` in the binary format
+pub fn assemble(opts: &Options, instrs: &[ir::Instruction]) -> Vec<u8> {
+    generate_module(opts, instrs).to_binary()
+}
+
+fn generate_module(opts: &Options, instrs: &[Instruction]) -> Module {
+    let mut transpiler = WasmTranspiler::create(opts, instrs);
+    transpiler.visit_instructions(instrs);
+
+    let mut imports = vec![
+        Import{ name: "read", params: vec![], result: Some(ValType::I32) },
+        Import{ name: "write", params: vec![ValType::I32], result: None },
+    ];
+    if transpiler.uses_debug {
+        imports.push(Import{ name: "debug", params: vec![ValType::I32], result: None });
+    }
+
+    let helpers = match transpiler.arithmetic {
+        Arithmetic::LargeModular(n) => modular_helpers(n, transpiler.helper(0)),
+        _ => vec![],
+    };
+    let mut functions = vec![Function {
+        name: "run",
+        export: Some("run"),
+        params: vec![],
+        result: None,
+        locals: vec![("ptr", ValType::I32), ("addr", ValType::I32), ("byte", ValType::I32),
+                     ("v", transpiler.value_type)],
+        body: transpiler.body,
+        marks: transpiler.marks,
+    }];
+    functions.extend(helpers);
+
+    Module {
+        memory_pages: (transpiler.tape_bytes.div_ceil(0x10000)).max(1),
+        imports,
+        functions,
+    }
+}
+
+impl WasmTranspiler {
+    fn create(opts: &Options, instrs: &[Instruction]) -> Self {
+        let (storage, value_type, arithmetic) = match opts.cell_size {
+            CellSize::Bits(8) => (Storage::U8, ValType::I32, Arithmetic::Native),
+            CellSize::Bits(16) => (Storage::U16, ValType::I32, Arithmetic::Native),
+            CellSize::Bits(32) => (Storage::U32, ValType::I32, Arithmetic::Native),
+            CellSize::Bits(n) if n < 8 => (Storage::U8, ValType::I32, Arithmetic::Masked((1 << n) - 1)),
+            CellSize::Bits(n) if n < 16 => (Storage::U16, ValType::I32, Arithmetic::Masked((1 << n) - 1)),
+            CellSize::Bits(n) if n < 32 => (Storage::U32, ValType::I32, Arithmetic::Masked((1 << n) - 1)),
+            CellSize::Bits(n) if n < 64 => (Storage::U64, ValType::I64, Arithmetic::Masked((1 << n) - 1)),
+            CellSize::Bits(_) | CellSize::Int => (Storage::U64, ValType::I64, Arithmetic::Native),
+            CellSize::Modular(n) if n <= 1 << 8 => (Storage::U8, ValType::I64, Arithmetic::Modular(n)),
+            CellSize::Modular(n) if n <= 1 << 16 => (Storage::U16, ValType::I64, Arithmetic::Modular(n)),
+            CellSize::Modular(n) if n <= 1 << 32 => (Storage::U32, ValType::I64, Arithmetic::Modular(n)),
+            CellSize::Modular(n) => (Storage::U64, ValType::I64, Arithmetic::LargeModular(n)),
+        };
+
+        WasmTranspiler {
+            body: Vec::new(),
+            source_marks: SourceMarks::new(opts, instrs),
+            marks: Vec::new(),
+            tape_bytes: (opts.memory_size.max(1) * storage.bytes()) as u32,
+            storage,
+            value_type,
+            arithmetic,
+            eof_mode: opts.eof_mode,
+            uses_debug: super::contains_debug(instrs),
+        }
+    }
+
+    fn emit(&mut self, ops: &[Op]) {
+        self.body.extend_from_slice(ops);
+    }
+
+    /// Pushes the address of a cell relative to the pointer
+    fn address(&mut self, offset: i64) {
+        let cells = (self.tape_bytes / self.storage.bytes() as u32) as i64;
+        let bytes = offset.rem_euclid(cells) as u32 * self.storage.bytes() as u32;
+        self.emit(&[Op::LocalGet(PTR)]);
+        if bytes != 0 {
+            self.emit(&[Op::I32Const(bytes as i32), Op::I32Add]);
+            if self.tape_bytes.is_power_of_two() {
+                self.emit(&[Op::I32Const((self.tape_bytes - 1) as i32), Op::I32And]);
+            }
+            else {
+                self.emit(&[Op::I32Const(self.tape_bytes as i32), Op::I32RemU]);
+            }
+        }
+    }
+
+    fn load(&mut self) {
+        self.emit(&[Op::Load(self.value_type, self.storage)]);
+    }
+
+    fn store(&mut self) {
+        self.emit(&[Op::Store(self.value_type, self.storage)]);
+    }
+
+    /// Pushes a value reduced into the range of the cell
+    fn literal(&mut self, value: i64) {
+        let op = match (self.arithmetic, self.value_type) {
+            (Arithmetic::Native, ValType::I32) => Op::I32Const(value as i32),
+            (Arithmetic::Native, ValType::I64) => Op::I64Const(value),
+            (Arithmetic::Masked(mask), ValType::I32) => Op::I32Const((value as u64 & mask) as i32),
+            (Arithmetic::Masked(mask), ValType::I64) => Op::I64Const((value as u64 & mask) as i64),
+            (Arithmetic::Modular(n), _) | (Arithmetic::LargeModular(n), _) =>
+                Op::I64Const((value as i128).rem_euclid(n as i128) as i64),
+        };
+        self.emit(&[op]);
+    }
+
+    /// Brings the sum of the two values on the stack into the range of the cell
+    fn add(&mut self) {
+        match (self.arithmetic, self.value_type) {
+            (Arithmetic::Native, ValType::I32) => self.emit(&[Op::I32Add]),
+            (Arithmetic::Native, ValType::I64) => self.emit(&[Op::I64Add]),
+            (Arithmetic::Masked(mask), ValType::I32) => self.emit(&[Op::I32Add, Op::I32Const(mask as i32), Op::I32And]),
+            (Arithmetic::Masked(mask), ValType::I64) => self.emit(&[Op::I64Add, Op::I64Const(mask as i64), Op::I64And]),
+            (Arithmetic::Modular(n), _) => self.emit(&[Op::I64Add, Op::I64Const(n as i64), Op::I64RemU]),
+            (Arithmetic::LargeModular(_), _) => self.emit(&[Op::Call(self.helper(0))]),
+        }
+    }
+
+    /// Multiplies the two values on the stack, the product only has to be
+    /// correct once it is passed to `add`
+    fn mul(&mut self) {
+        match (self.arithmetic, self.value_type) {
+            (Arithmetic::LargeModular(_), _) => self.emit(&[Op::Call(self.helper(1))]),
+            (_, ValType::I32) => self.emit(&[Op::I32Mul]),
+            // below a modulus of 2^32, the product of two cells plus a cell fits into 64 bits
+            (_, ValType::I64) => self.emit(&[Op::I64Mul]),
+        }
+    }
+
+    /// The index of $addmod (0) or $mulmod (1), after the imports and `run`
+    fn helper(&self, n: u32) -> u32 {
+        let imports = if self.uses_debug { 3 } else { 2 };
+        imports + 1 + n
+    }
+
+    /// Pushes whether the cell at the pointer is not zero
+    fn current_is_nonzero(&mut self) {
+        self.emit(&[Op::LocalGet(PTR)]);
+        self.load();
+        match self.value_type {
+            ValType::I32 => {},
+            ValType::I64 => self.emit(&[Op::I64Const(0), Op::I64Ne]),
+        }
+    }
+
+    /// Converts the byte in $byte into a cell
+    fn byte_to_cell(&mut self) {
+        self.emit(&[Op::LocalGet(BYTE)]);
+        match (self.arithmetic, self.value_type) {
+            (Arithmetic::Native, ValType::I32) => {},
+            (Arithmetic::Native, ValType::I64) | (Arithmetic::LargeModular(_), _) => self.emit(&[Op::I64ExtendI32U]),
+            (Arithmetic::Masked(mask), ValType::I32) => self.emit(&[Op::I32Const(mask as i32), Op::I32And]),
+            (Arithmetic::Masked(mask), ValType::I64) => self.emit(&[Op::I64ExtendI32U, Op::I64Const(mask as i64), Op::I64And]),
+            (Arithmetic::Modular(n), _) => self.emit(&[Op::I64ExtendI32U, Op::I64Const(n as i64), Op::I64RemU]),
+        }
+    }
+
+    /// Adds the value pushed by `value` to the cell at `offset`
+    fn add_to_cell(&mut self, offset: i64, value: impl FnOnce(&mut Self)) {
+        self.address(offset);
+        self.emit(&[Op::LocalTee(ADDR), Op::LocalGet(ADDR)]);
+        self.load();
+        value(self);
+        self.add();
+        self.store();
+    }
+}
+
+impl ir::ConstVisitor for WasmTranspiler {
+    type Ret = ();
+
+    fn before(&mut self, inst: &Instruction) {
+        if let Some(mark) = self.source_marks.enter(inst) {
+            self.marks.push((self.body.len(), mark));
+        }
+    }
+
+    fn after(&mut self, inst: &Instruction) {
+        if let Some(mark) = self.source_marks.leave(inst) {
+            self.marks.push((self.body.len(), mark));
+        }
+    }
+
+    fn visit_add(&mut self, add: &'_ Instruction) {
+        if let Instruction::Add{ offset, value } = add {
+            self.add_to_cell(*offset, |t| t.literal(*value));
+        }
+    }
+
+    fn visit_set(&mut self, set: &'_ Instruction) {
+        if let Instruction::Set{ offset, value } = set {
+            self.address(*offset);
+            self.literal(*value);
+            self.store();
+        }
+    }
+
+    fn visit_linear_loop(&mut self, l: &Instruction) {
+        if let Instruction::LinearLoop{ offset: glob_offset, factors, .. } = l {
+            self.address(*glob_offset);
+            self.load();
+            self.emit(&[Op::LocalSet(VALUE)]);
+            for (&offset, &factor) in factors {
+                self.add_to_cell(glob_offset + offset, |t| {
+                    t.emit(&[Op::LocalGet(VALUE)]);
+                    t.literal(factor);
+                    t.mul();
+                });
+            }
+            self.address(*glob_offset);
+            self.literal(0);
+            self.store();
+        }
+    }
+
+    fn visit_move_ptr(&mut self, mp: &Instruction) {
+        if let Instruction::MovePtr(offset) = mp {
+            self.address(*offset);
+            self.emit(&[Op::LocalSet(PTR)]);
+        }
+    }
+
+    fn visit_loop(&mut self, l: &Instruction) {
+        if let Instruction::Loop(insts, _) = l {
+            self.current_is_nonzero();
+            self.emit(&[Op::If, Op::Loop]);
+            self.visit_instructions(insts);
+            self.current_is_nonzero();
+            self.emit(&[Op::BrIf(0), Op::End, Op::End]);
+        }
+    }
+
+    fn visit_read(&mut self, r: &Instruction) {
+        if let Instruction::Read(offset) = r {
+            self.emit(&[Op::Call(READ), Op::LocalTee(BYTE), Op::I32Const(0), Op::I32GeS, Op::If]);
+            self.address(*offset);
+            self.byte_to_cell();
+            self.store();
+            match self.eof_mode {
+                EofMode::Zero | EofMode::MinusOne => {
+                    self.emit(&[Op::Else]);
+                    self.address(*offset);
+                    self.literal(if self.eof_mode == EofMode::Zero { 0 } else { -1 });
+                    self.store();
+                },
+                EofMode::Unchanged => {},
+            }
+            self.emit(&[Op::End]);
+        }
+    }
+
+    fn visit_write(&mut self, w: &Instruction) {
+        if let Instruction::Write(offset) = w {
+            self.address(*offset);
+            self.load();
+            if self.value_type == ValType::I64 {
+                self.emit(&[Op::I32WrapI64]);
+            }
+            self.emit(&[Op::I32Const(0xFF), Op::I32And, Op::Call(WRITE)]);
+        }
+    }
+
+    fn visit_debug(&mut self, _d: &Instruction) {
+        let shift = self.storage.bytes().trailing_zeros() as i32;
+        self.emit(&[Op::LocalGet(PTR), Op::I32Const(shift), Op::I32ShrU, Op::Call(DEBUG)]);
+    }
+}
+
+// $addmod and $mulmod for moduli where the sum of two cells may not fit into
+// 64 bits, $addmod has the index `addmod_index`
+fn modular_helpers(n: u64, addmod_index: u32) -> Vec<Function> {
+    let n = n as i64;
+    let (a, b, r) = (0, 1, 2);
+    let addmod = Function {
+        name: "addmod",
+        export: None,
+        params: vec![("a", ValType::I64), ("b", ValType::I64)],
+        result: Some(ValType::I64),
+        locals: vec![],
+        // a >= n - b ? a - (n - b) : a + b
+        body: vec![
+            Op::LocalGet(a), Op::I64Const(n), Op::LocalGet(b), Op::I64Sub, Op::I64Sub,
+            Op::LocalGet(a), Op::LocalGet(b), Op::I64Add,
+            Op::LocalGet(a), Op::I64Const(n), Op::LocalGet(b), Op::I64Sub, Op::I64GeU,
+            Op::Select,
+        ],
+        marks: vec![],
+    };
+    // double and add, so no intermediate result exceeds the modulus:
+    // while b != 0 { r = b is even ? r : r + a; a = a + a; b >>= 1 }
+    let body = vec![
+        Op::Block, Op::Loop, Op::LocalGet(b), Op::I64Eqz, Op::BrIf(1),
+        Op::LocalGet(r), Op::LocalGet(r), Op::LocalGet(a), Op::Call(addmod_index),
+        Op::LocalGet(b), Op::I64Const(1), Op::I64And, Op::I64Eqz, Op::Select, Op::LocalSet(r),
+        Op::LocalGet(a), Op::LocalGet(a), Op::Call(addmod_index), Op::LocalSet(a),
+        Op::LocalGet(b), Op::I64Const(1), Op::I64ShrU, Op::LocalSet(b),
+        Op::Br(0), Op::End, Op::End, Op::LocalGet(r),
+    ];
+    let mulmod = Function {
+        name: "mulmod",
+        export: None,
+        params: vec![("a", ValType::I64), ("b", ValType::I64)],
+        result: Some(ValType::I64),
+        locals: vec![("r", ValType::I64)],
+        body,
+        marks: vec![],
+    };
+    vec![addmod, mulmod]
+}
+
+impl Module {
+    fn function_names(&self) -> Vec<&'static str> {
+        self.imports.iter().map(|i| i.name)
+            .chain(self.functions.iter().map(|f| f.name))
+            .collect()
+    }
+
+    fn to_text(&self) -> String {
+        let names = self.function_names();
+        let mut code_buf = Formatter::new();
+        code_buf.add_line("(module");
+        code_buf.indent();
+        for import in &self.imports {
+            code_buf.add_line(&format!("(import \"env\" \"{name}\" (func ${name}{}))",
+                                       signature_text(&[], &import.params, import.result), name = import.name));
+        }
+        code_buf.add_line(&format!("(memory (export \"memory\") {})", self.memory_pages));
+        for function in &self.functions {
+            let export = function.export.map_or(String::new(), |e| format!(" (export \"{}\")", e));
+            let locals: String = function.locals.iter()
+                .map(|(name, ty)| format!(" (local ${} {})", name, ty.name()))
+                .collect();
+            code_buf.add_line("");
+            code_buf.add_line(&format!("(func ${}{}{}{}", function.name, export,
+                                       signature_text(&function.params, &[], function.result), locals));
+            code_buf.indent();
+            let local_names: Vec<&str> = function.params.iter().chain(&function.locals).map(|(n, _)| *n).collect();
+            let mut marks = function.marks.iter().peekable();
+            for (i, op) in function.body.iter().enumerate() {
+                while let Some((_, mark)) = marks.next_if(|(index, _)| *index == i) {
+                    code_buf.add_line(&mark.to_string());
+                }
+                if let Op::End | Op::Else = op {
+                    code_buf.unindent();
+                }
+                let line = match *op {
+                    Op::LocalGet(i) => format!("local.get ${}", local_names[i as usize]),
+                    Op::LocalSet(i) => format!("local.set ${}", local_names[i as usize]),
+                    Op::LocalTee(i) => format!("local.tee ${}", local_names[i as usize]),
+                    Op::I32Const(v) => format!("i32.const {}", v),
+                    Op::I64Const(v) => format!("i64.const {}", v),
+                    Op::Call(i) => format!("call ${}", names[i as usize]),
+                    Op::Br(depth) => format!("br {}", depth),
+                    Op::BrIf(depth) => format!("br_if {}", depth),
+                    op => op.name().to_string(),
+                };
+                code_buf.add_line(&line);
+                if let Op::Block | Op::Loop | Op::If | Op::Else = op {
+                    code_buf.indent();
+                }
+            }
+            for (_, mark) in marks {
+                code_buf.add_line(&mark.to_string());
+            }
+            code_buf.unindent();
+            code_buf.add_line(")");
+        }
+        code_buf.unindent();
+        code_buf.add_line(")");
+        code_buf.get_code()
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        let mut module = b"\0asm".to_vec();
+        module.extend_from_slice(&[1, 0, 0, 0]);
+
+        // one type per function, imports first
+        let mut types = Vec::new();
+        let signatures = self.imports.iter().map(|i| (i.params.clone(), i.result))
+            .chain(self.functions.iter().map(|f| (f.params.iter().map(|p| p.1).collect(), f.result)));
+        let mut count = 0;
+        for (params, result) in signatures {
+            types.push(0x60);
+            write_u32(&mut types, params.len() as u32);
+            types.extend(params.iter().map(|p| p.code()));
+            write_u32(&mut types, result.is_some() as u32);
+            types.extend(result.map(|r| r.code()));
+            count += 1;
+        }
+        section(&mut module, 1, count, &types);
+
+        let mut imports = Vec::new();
+        for (i, import) in self.imports.iter().enumerate() {
+            write_name(&mut imports, "env");
+            write_name(&mut imports, import.name);
+            imports.push(0x00);
+            write_u32(&mut imports, i as u32);
+        }
+        section(&mut module, 2, self.imports.len() as u32, &imports);
+
+        let mut functions = Vec::new();
+        for i in 0..self.functions.len() {
+            write_u32(&mut functions, (self.imports.len() + i) as u32);
+        }
+        section(&mut module, 3, self.functions.len() as u32, &functions);
+
+        let mut memory = vec![0x00];
+        write_u32(&mut memory, self.memory_pages);
+        section(&mut module, 5, 1, &memory);
+
+        let mut exports = Vec::new();
+        write_name(&mut exports, "memory");
+        exports.extend_from_slice(&[0x02, 0x00]);
+        let mut export_count = 1;
+        for (i, function) in self.functions.iter().enumerate() {
+            if let Some(export) = function.export {
+                write_name(&mut exports, export);
+                exports.push(0x00);
+                write_u32(&mut exports, (self.imports.len() + i) as u32);
+                export_count += 1;
+            }
+        }
+        section(&mut module, 7, export_count, &exports);
+
+        let mut code = Vec::new();
+        for function in &self.functions {
+            let mut body = Vec::new();
+            write_u32(&mut body, function.locals.len() as u32);
+            for (_, ty) in &function.locals {
+                write_u32(&mut body, 1);
+                body.push(ty.code());
+            }
+            for op in &function.body {
+                self.encode(op, &mut body);
+            }
+            body.push(0x0B);
+            write_u32(&mut code, body.len() as u32);
+            code.extend(body);
+        }
+        section(&mut module, 10, self.functions.len() as u32, &code);
+        module
+    }
+
+    fn encode(&self, op: &Op, out: &mut Vec<u8>) {
+        match *op {
+            Op::LocalGet(i) => { out.push(0x20); write_u32(out, i); },
+            Op::LocalSet(i) => { out.push(0x21); write_u32(out, i); },
+            Op::LocalTee(i) => { out.push(0x22); write_u32(out, i); },
+            Op::I32Const(v) => { out.push(0x41); write_i64(out, v as i64); },
+            Op::I64Const(v) => { out.push(0x42); write_i64(out, v); },
+            Op::Load(_, storage) | Op::Store(_, storage) => {
+                out.push(op.opcode());
+                // alignment as a power of two, offset
+                write_u32(out, storage.bytes().trailing_zeros());
+                write_u32(out, 0);
+            },
+            Op::Call(i) => { out.push(0x10); write_u32(out, i); },
+            // blocks without results
+            Op::Block | Op::Loop | Op::If => out.extend_from_slice(&[op.opcode(), 0x40]),
+            Op::Br(depth) | Op::BrIf(depth) => { out.push(op.opcode()); write_u32(out, depth); },
+            _ => out.push(op.opcode()),
+        }
+    }
+}
+
+impl ValType {
+    fn name(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7F,
+            ValType::I64 => 0x7E,
+        }
+    }
+}
+
+impl Storage {
+    fn bytes(self) -> usize {
+        match self {
+            Storage::U8 => 1,
+            Storage::U16 => 2,
+            Storage::U32 => 4,
+            Storage::U64 => 8,
+        }
+    }
+}
+
+impl Op {
+    fn name(self) -> &'static str {
+        use self::Op::*;
+        match self {
+            Load(ValType::I32, Storage::U8) => "i32.load8_u",
+            Load(ValType::I32, Storage::U16) => "i32.load16_u",
+            Load(ValType::I32, _) => "i32.load",
+            Load(ValType::I64, Storage::U8) => "i64.load8_u",
+            Load(ValType::I64, Storage::U16) => "i64.load16_u",
+            Load(ValType::I64, Storage::U32) => "i64.load32_u",
+            Load(ValType::I64, Storage::U64) => "i64.load",
+            Store(ValType::I32, Storage::U8) => "i32.store8",
+            Store(ValType::I32, Storage::U16) => "i32.store16",
+            Store(ValType::I32, _) => "i32.store",
+            Store(ValType::I64, Storage::U8) => "i64.store8",
+            Store(ValType::I64, Storage::U16) => "i64.store16",
+            Store(ValType::I64, Storage::U32) => "i64.store32",
+            Store(ValType::I64, Storage::U64) => "i64.store",
+            Block => "block",
+            Loop => "loop",
+            If => "if",
+            Else => "else",
+            End => "end",
+            Select => "select",
+            I32GeS => "i32.ge_s",
+            I32Add => "i32.add",
+            I32And => "i32.and",
+            I32Mul => "i32.mul",
+            I32RemU => "i32.rem_u",
+            I32ShrU => "i32.shr_u",
+            I32WrapI64 => "i32.wrap_i64",
+            I64Eqz => "i64.eqz",
+            I64Ne => "i64.ne",
+            I64GeU => "i64.ge_u",
+            I64Add => "i64.add",
+            I64Sub => "i64.sub",
+            I64Mul => "i64.mul",
+            I64And => "i64.and",
+            I64RemU => "i64.rem_u",
+            I64ShrU => "i64.shr_u",
+            I64ExtendI32U => "i64.extend_i32_u",
+            LocalGet(_) | LocalSet(_) | LocalTee(_) | I32Const(_) | I64Const(_) | Call(_) | Br(_) | BrIf(_) => "",
+        }
+    }
+
+    fn opcode(self) -> u8 {
+        use self::Op::*;
+        match self {
+            LocalGet(_) => 0x20,
+            LocalSet(_) => 0x21,
+            LocalTee(_) => 0x22,
+            I32Const(_) => 0x41,
+            I64Const(_) => 0x42,
+            Load(ValType::I32, Storage::U8) => 0x2D,
+            Load(ValType::I32, Storage::U16) => 0x2F,
+            Load(ValType::I32, _) => 0x28,
+            Load(ValType::I64, Storage::U8) => 0x31,
+            Load(ValType::I64, Storage::U16) => 0x33,
+            Load(ValType::I64, Storage::U32) => 0x35,
+            Load(ValType::I64, Storage::U64) => 0x29,
+            Store(ValType::I32, Storage::U8) => 0x3A,
+            Store(ValType::I32, Storage::U16) => 0x3B,
+            Store(ValType::I32, _) => 0x36,
+            Store(ValType::I64, Storage::U8) => 0x3C,
+            Store(ValType::I64, Storage::U16) => 0x3D,
+            Store(ValType::I64, Storage::U32) => 0x3E,
+            Store(ValType::I64, Storage::U64) => 0x37,
+            Call(_) => 0x10,
+            Block => 0x02,
+            Loop => 0x03,
+            If => 0x04,
+            Else => 0x05,
+            End => 0x0B,
+            Br(_) => 0x0C,
+            BrIf(_) => 0x0D,
+            Select => 0x1B,
+            I32GeS => 0x4E,
+            I32Add => 0x6A,
+            I32And => 0x71,
+            I32Mul => 0x6C,
+            I32RemU => 0x70,
+            I32ShrU => 0x76,
+            I32WrapI64 => 0xA7,
+            I64Eqz => 0x50,
+            I64Ne => 0x52,
+            I64GeU => 0x5A,
+            I64Add => 0x7C,
+            I64Sub => 0x7D,
+            I64Mul => 0x7E,
+            I64And => 0x83,
+            I64RemU => 0x82,
+            I64ShrU => 0x88,
+            I64ExtendI32U => 0xAD,
+        }
+    }
+}
+
+fn signature_text(params: &[(&str, ValType)], unnamed_params: &[ValType], result: Option<ValType>) -> String {
+    let mut text = String::new();
+    for (name, ty) in params {
+        text += &format!(" (param ${} {})", name, ty.name());
+    }
+    for ty in unnamed_params {
+        text += &format!(" (param {})", ty.name());
+    }
+    if let Some(ty) = result {
+        text += &format!(" (result {})", ty.name());
+    }
+    text
+}
+
+fn section(module: &mut Vec<u8>, id: u8, count: u32, contents: &[u8]) {
+    let mut body = Vec::new();
+    write_u32(&mut body, count);
+    body.extend_from_slice(contents);
+    module.push(id);
+    write_u32(module, body.len() as u32);
+    module.extend(body);
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_u32(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+// unsigned LEB128
+fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// signed LEB128
+fn write_i64(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub struct WatBackend;
+
+impl Backend for WatBackend {
+    fn name(&self) -> &'static str {
+        "wat"
+    }
+
+    fn extension(&self) -> &'static str {
+        "wat"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some(";;")
+    }
+
+    fn supports(&self, opts: &Options) -> Result<(), BackendError> {
+        require_cell_size(opts, !matches!(opts.cell_size, CellSize::Bits(n) if n > 64))?;
+        require_wrapping(opts)
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        transpile(opts, instrs)
+    }
+}
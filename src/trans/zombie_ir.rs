@@ -1,47 +1,219 @@
-use super::super::{ir, formatter};
-
-use ir::Instruction;
-use formatter::Formatter;
-
-pub fn transpile(instrs: &Vec<ir::Instruction>) -> String {
-    let mut formatter = Formatter::new();
-    generate(&mut formatter, instrs);
-    formatter.get_code()
-}
-
-
-fn generate(formatter: &mut Formatter, instrs: &Vec<Instruction>) {
-    for instr in instrs {
-        match instr {
-            Instruction::Nop => {},
-            Instruction::Add{ offset, value } => {
-                formatter.add_line(&format!("@{} += {}", offset, value));
-            },
-            Instruction::Set{ offset, value } => {
-                formatter.add_line(&format!("@{} = {}", offset, value));
-            },
-            Instruction::LinearLoop{ offset, factors } => {
-                for (off, factor) in factors {
-                    formatter.add_line(&format!("@{} = {} * @{}", offset + off, factor, offset));
-                }
-                formatter.add_line(&format!("@{} = 0 // End LL", offset));
-            },
-            Instruction::MovePtr(offset) => {
-                formatter.add_line(&format!("ptr += {}", offset));
-            },
-            Instruction::Loop(instructions) => {
-                formatter.add_line("Loop {");
-                formatter.indent();
-                generate(formatter, instructions);
-                formatter.unindent();
-                formatter.add_line("}");
-            },
-            Instruction::Read(offset) => {
-                formatter.add_line(&format!("Read(@{})", offset));
-            },
-            Instruction::Write(offset) => {
-                formatter.add_line(&format!("Write(@{})", offset));
+//! The textual zombie IR (`.zir`), one instruction per line:
+//!
+//! ```text
+//! Nop
+//! @3 += 5                   // Add{ offset: 3, value: 5 }
+//! @-1 = 0                   // Set{ offset: -1, value: 0 }
+//! ptr += 2                  // MovePtr(2)
+//! Read(@0)
+//! Write(@1)
+//! Debug
+//! Loop 10..24 {             // the source span is optional
+//!     ...
+//! }
+//! LinearLoop(@0) 30..38 {   // the span is optional here too
+//!     @2 += 3 * @0          // a factor of 3 for the cell at offset 2
+//! }
+//! ```
+//!
+//! Offsets in the factors of a linear loop are absolute like everywhere else,
+//! not relative to the loop's cell. Everything after `//` is a comment.
+//! Printing and parsing again gives back the same instructions.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use super::super::{ir, options};
+use super::Backend;
+use super::source_map::SourceMarks;
+
+use ir::{Instruction, Span};
+use options::Options;
+
+pub fn transpile(instrs: &[ir::Instruction]) -> String {
+    ir::print(instrs, true, &())
+}
+
+/// An error in the textual IR with the (1-based) line it occurred in
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A block whose instructions are still being parsed
+enum Block {
+    Loop(Option<Span>),
+    // a linear loop only contains its factors
+    LinearLoop{ offset: i64, factors: BTreeMap<i64, i64>, span: Option<Span> },
+}
+
+/// Parses instructions written in the format `transpile` generates
+pub fn parse(code: &str) -> Result<Vec<ir::Instruction>, ParseError> {
+    read(code, None)
+}
+
+/// Parses instructions like `parse` and checks that they hold the invariants
+/// of `ir::verify`, as the ones from the parser and the optimizer do
+pub fn parse_verified(code: &str, opts: &Options) -> Result<Vec<ir::Instruction>, ParseError> {
+    read(code, Some(opts))
+}
+
+fn read(code: &str, opts: Option<&Options>) -> Result<Vec<ir::Instruction>, ParseError> {
+    let verify = |inst: &Instruction, line: usize| match (opts, inst) {
+        // the body was checked as it was parsed
+        (Some(_), Instruction::Loop(body, _)) if !body.is_empty() => Ok(()),
+        (Some(opts), _) => ir::verify(std::slice::from_ref(inst), opts)
+            .map_err(|violation| ParseError{ line, message: violation.to_string() }),
+        (None, _) => Ok(()),
+    };
+    let mut block_stack: Vec<(Vec<ir::Instruction>, Block, usize)> = Vec::new();
+    let mut instructions: Vec<ir::Instruction> = Vec::new();
+
+    for (index, line) in code.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: &str| ParseError{ line: line_number, message: message.to_string() };
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        }.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "}" {
+            let (outer, block, start) = block_stack.pop().ok_or_else(|| error("found '}' without an open block"))?;
+            let inst = match block {
+                Block::Loop(span) => Instruction::Loop(instructions, span),
+                Block::LinearLoop{ offset, factors, span } => Instruction::LinearLoop{ offset, factors, span },
+            };
+            verify(&inst, start)?;
+            instructions = outer;
+            instructions.push(inst);
+            continue;
+        }
+
+        if let Some((_, Block::LinearLoop{ offset, factors, .. }, _)) = block_stack.last_mut() {
+            let (target, factor) = parse_factor(line, *offset).ok_or_else(|| error("expected a factor like '@1 += 2 * @0'"))?;
+            if factors.insert(target - *offset, factor).is_some() {
+                return Err(error("duplicate factor"));
             }
+            continue;
         }
+
+        if let Some(header) = line.strip_suffix('{') {
+            let header = header.trim();
+            let block = if let Some(rest) = header.strip_prefix("LinearLoop(") {
+                let close = rest.find(')').ok_or_else(|| error("expected ')'"))?;
+                let offset = parse_cell(&rest[..close]).ok_or_else(|| error("expected a cell like '@0'"))?;
+                let span = parse_span(&rest[(close + 1)..]).ok_or_else(|| error("expected a span like '3..17'"))?;
+                Block::LinearLoop{ offset, factors: BTreeMap::new(), span }
+            }
+            else if let Some(rest) = header.strip_prefix("Loop") {
+                Block::Loop(parse_span(rest).ok_or_else(|| error("expected a span like '3..17'"))?)
+            }
+            else {
+                return Err(error("unknown block"));
+            };
+            block_stack.push((instructions, block, line_number));
+            instructions = Vec::new();
+            continue;
+        }
+
+        let inst = parse_instruction(line).ok_or_else(|| error(&format!("invalid instruction '{}'", line)))?;
+        verify(&inst, line_number)?;
+        instructions.push(inst);
+    }
+
+    if let Some((_, _, line)) = block_stack.last() {
+        return Err(ParseError{ line: *line, message: "block is never closed".to_string() });
     }
-}
\ No newline at end of file
+
+    Ok(instructions)
+}
+
+fn parse_instruction(line: &str) -> Option<Instruction> {
+    match line {
+        "Nop" => return Some(Instruction::Nop),
+        "Debug" => return Some(Instruction::Debug),
+        _ => {},
+    }
+    if let Some(rest) = line.strip_prefix("Read(") {
+        return Some(Instruction::Read(parse_cell(rest.strip_suffix(')')?)?));
+    }
+    if let Some(rest) = line.strip_prefix("Write(") {
+        return Some(Instruction::Write(parse_cell(rest.strip_suffix(')')?)?));
+    }
+    if let Some(rest) = line.strip_prefix("ptr") {
+        return Some(Instruction::MovePtr(parse_number(rest.trim_start().strip_prefix("+=")?)?));
+    }
+    if let Some(add) = line.find("+=") {
+        let offset = parse_cell(&line[..add])?;
+        return Some(Instruction::Add{ offset, value: parse_number(&line[(add + 2)..])? });
+    }
+    let set = line.find('=')?;
+    let offset = parse_cell(&line[..set])?;
+    Some(Instruction::Set{ offset, value: parse_number(&line[(set + 1)..])? })
+}
+
+/// Parses `@target += factor * @offset` into the target and the factor
+fn parse_factor(line: &str, offset: i64) -> Option<(i64, i64)> {
+    let add = line.find("+=")?;
+    let target = parse_cell(&line[..add])?;
+    let product = &line[(add + 2)..];
+    let times = product.find('*')?;
+    let factor = parse_number(&product[..times])?;
+    if parse_cell(&product[(times + 1)..])? != offset {
+        return None;
+    }
+    Some((target, factor))
+}
+
+fn parse_cell(cell: &str) -> Option<i64> {
+    parse_number(cell.trim().strip_prefix('@')?)
+}
+
+fn parse_number(number: &str) -> Option<i64> {
+    i64::from_str(number.trim()).ok()
+}
+
+/// Parses an optional span `start..end`, `None` means the text is malformed
+fn parse_span(span: &str) -> Option<Option<Span>> {
+    let span = span.trim();
+    if span.is_empty() {
+        return Some(None);
+    }
+    let dots = span.find("..")?;
+    let start = usize::from_str(&span[..dots]).ok()?;
+    let end = usize::from_str(&span[(dots + 2)..]).ok()?;
+    Some(Some(Span{ start, end }))
+}
+
+pub struct ZombieIrBackend;
+
+impl Backend for ZombieIrBackend {
+    fn name(&self) -> &'static str {
+        "zombie_ir"
+    }
+
+    fn extension(&self) -> &'static str {
+        "zir"
+    }
+
+    fn comment(&self) -> Option<&'static str> {
+        Some("//")
+    }
+
+    fn transpile(&self, opts: &Options, instrs: &[ir::Instruction]) -> String {
+        ir::print(instrs, true, &SourceMarks::new(opts, instrs))
+    }
+}
@@ -0,0 +1,114 @@
+// Checks the registry of transpiler backends and their option validation.
+
+use zombie::options::{CellLayout, CellSize, Options};
+use zombie::parser;
+use zombie::trans::{self, BackendError};
+
+#[test]
+fn backends_are_found_by_name() {
+    let names: Vec<&str> = trans::backends().iter().map(|backend| backend.name()).collect();
+    for (i, name) in names.iter().enumerate() {
+        assert!(!names[..i].contains(name), "two backends are called {}", name);
+        assert_eq!(trans::find(name).map(|backend| backend.name()), Some(*name));
+    }
+    assert!(trans::find("cobol").is_none());
+}
+
+#[test]
+fn backends_support_the_default_options() {
+    let opts = Options::default();
+    let insts = parser::parse("+[->+<]>.,#", &opts).unwrap();
+    for backend in trans::backends() {
+        let code = backend.emit(&opts, &insts);
+        assert!(code.is_ok_and(|code| !code.is_empty()), "{} fails on the default options", backend.name());
+    }
+}
+
+#[test]
+fn unsupported_options_are_rejected() {
+    let insts = parser::parse("+.", &Options::default()).unwrap();
+    let opts = Options {
+        cell_size: CellSize::Bits(16),
+        ..Options::default()
+    };
+    let asm = trans::find("asm").unwrap();
+    assert_eq!(asm.emit(&opts, &insts), Err(BackendError::UnsupportedCellSize(CellSize::Bits(16))));
+    for layout in &[CellLayout::Wrapping, CellLayout::Unbounded] {
+        let opts = Options { cell_layout: layout.clone(), ..Options::default() };
+        assert_eq!(asm.emit(&opts, &insts), Err(BackendError::UnsupportedCellLayout(layout.clone())));
+    }
+
+    let opts = Options {
+        memory_size: 1 << 31,
+        ..Options::default()
+    };
+    let java = trans::find("java").unwrap();
+    assert_eq!(java.emit(&opts, &insts).unwrap_err().to_string(), "a memory size of 2147483648 cells is not supported");
+    assert!(trans::find("rust").unwrap().emit(&opts, &insts).is_ok());
+
+    for backend in trans::backends() {
+        for cell_size in &[CellSize::Bits(0), CellSize::Modular(0)] {
+            let opts = Options { cell_size: cell_size.clone(), ..Options::default() };
+            assert_eq!(backend.emit(&opts, &insts), Err(BackendError::UnsupportedCellSize(cell_size.clone())),
+                       "{} accepts {:?}", backend.name(), cell_size);
+        }
+    }
+    let opts = Options { cell_size: CellSize::Bits(100), ..Options::default() };
+    assert!(trans::find("python").unwrap().emit(&opts, &insts).is_ok());
+
+    // only C grows the tape, the other backends wrap the pointer around it
+    let opts = Options { cell_layout: CellLayout::Unbounded, ..Options::default() };
+    for backend in trans::backends() {
+        let supported = matches!(backend.name(), "c" | "bf" | "zombie_ir");
+        assert_eq!(backend.emit(&opts, &insts).is_ok(), supported, "{} with an unbounded tape", backend.name());
+    }
+}
+
+#[test]
+fn source_maps_leave_the_code_unchanged() {
+    let opts = Options::default();
+    let code = "+[->+<]>.,#";
+    let insts = parser::parse(code, &opts).unwrap();
+    for backend in trans::backends() {
+        let (annotated, _) = backend.emit_annotated(&opts, &insts, code, false).unwrap();
+        assert!(annotated == backend.emit(&opts, &insts).unwrap(), "{} code changes with a source map", backend.name());
+    }
+}
+
+#[test]
+fn annotations_point_to_the_source() {
+    let opts = Options::default();
+    let code = "++ set up\n[->+<] move\n>.";
+    let insts = parser::parse(code, &opts).unwrap();
+    for backend in trans::backends() {
+        let prefix = match backend.comment() {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        let (annotated, line_map) = backend.emit_annotated(&opts, &insts, code, true).unwrap();
+        let lines: Vec<&str> = annotated.lines().collect();
+        let comment = |text: &str| format!("{} {}", prefix, text);
+        for expected in ["1:1 ++", "2:1 [->+<]", "2:2 ->+<", "3:1 >."] {
+            assert!(lines.iter().any(|line| line.trim() == comment(expected)),
+                    "{} code does not show {}", backend.name(), expected);
+        }
+        assert!(!line_map.is_empty(), "{} code has no source map", backend.name());
+        for mapping in &line_map {
+            assert!(1 <= mapping.first_line && mapping.first_line <= mapping.last_line && mapping.last_line <= lines.len());
+            assert!(mapping.source.start <= mapping.source.end && mapping.source.end <= code.len());
+            // every range starts with its comment, or continues after the ones nested in it
+            let first = lines[mapping.first_line - 1].trim();
+            assert!(first.starts_with(prefix) || line_map.iter().any(|m| m.source == mapping.source && m.first_line < mapping.first_line),
+                    "{} line {} is not where a range starts", backend.name(), mapping.first_line);
+        }
+    }
+}
+
+#[test]
+fn annotated_webassembly_is_valid() {
+    let opts = Options::default();
+    let code = "++[->+<]>.";
+    let insts = parser::parse(code, &opts).unwrap();
+    let (annotated, _) = trans::find("wat").unwrap().emit_annotated(&opts, &insts, code, true).unwrap();
+    assert!(wat::parse_str(&annotated).is_ok());
+}
@@ -0,0 +1,600 @@
+// Runs the example programs and randomly generated ones through the interpreter,
+// the JIT and the transpilers, and checks that they all produce the same output.
+//
+// The slow examples are ignored by default, run them with
+// `cargo test --release -- --ignored`.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use zombie::{compile, generate, interpret, optimize, parser, trans};
+use zombie::analysis::PointerRanges;
+use zombie::ir::Instruction;
+use zombie::trans::BackendError;
+use zombie::options::{CellLayout, CellSize, EofMode, Options};
+
+#[derive(Clone)]
+struct Example {
+    file: &'static str,
+    input: &'static [u8],
+    eof_mode: EofMode,
+}
+
+const EXAMPLES: &[Example] = &[
+    Example{ file: "hello_world.bf", input: b"", eof_mode: EofMode::Zero },
+    Example{ file: "99bottles.bf", input: b"", eof_mode: EofMode::Zero },
+    Example{ file: "cat.bf", input: b"differential\ntesting\n", eof_mode: EofMode::Zero },
+    Example{ file: "wc.bf", input: b"one two\nthree\n", eof_mode: EofMode::Zero },
+    Example{ file: "factor.bf", input: b"360\n", eof_mode: EofMode::Zero },
+    Example{ file: "gameoflife.bf", input: b"bc\ncd\nbd\n\n\nq\n", eof_mode: EofMode::Zero },
+    Example{ file: "hanoi.bf", input: b"", eof_mode: EofMode::Zero },
+    Example{ file: "rot13.bf", input: b"Uryyb, Jbeyq!\n", eof_mode: EofMode::Unchanged },
+];
+
+const SLOW_EXAMPLES: &[Example] = &[
+    Example{ file: "mandel.bf", input: b"", eof_mode: EofMode::Zero },
+    Example{ file: "mandelbrot-titannic.bf", input: b"", eof_mode: EofMode::Zero },
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Backend {
+    Interpreter,
+    Jit,
+    C,
+    Python,
+    Java,
+    Rust,
+    JavaScript,
+    Wasm,
+    // the text format, assembled by the wat crate
+    Wat,
+    Llvm,
+    Asm,
+    Go,
+    // transpiled back to brainfuck and run in the interpreter
+    Bf,
+}
+
+const BACKENDS: &[Backend] = &[Backend::Interpreter, Backend::Jit, Backend::C, Backend::Python, Backend::Java,
+                               Backend::Rust, Backend::JavaScript, Backend::Wasm, Backend::Wat, Backend::Llvm, Backend::Asm, Backend::Go,
+                               Backend::Bf];
+
+impl Backend {
+    // whether the backend is expected to implement the program correctly
+    fn supports(self, opts: &Options) -> bool {
+        let cell_size = &opts.cell_size;
+        match self {
+            // the interpreter only has these cell sizes, the brainfuck backend
+            // runs in it
+            Backend::Interpreter | Backend::Bf => matches!(cell_size, CellSize::Bits(8) | CellSize::Bits(16) | CellSize::Modular(_)),
+            Backend::Jit => compile::supports(opts).is_ok(),
+            Backend::C | Backend::Python | Backend::Java | Backend::Rust | Backend::JavaScript | Backend::Wasm | Backend::Wat | Backend::Llvm | Backend::Asm | Backend::Go => {
+                trans::find(self.transpiler()).unwrap().supports(opts).is_ok()
+            },
+        }
+    }
+
+    // the name of the transpiler generating the code the backend runs
+    fn transpiler(self) -> &'static str {
+        match self {
+            Backend::Interpreter | Backend::Jit => unreachable!(),
+            Backend::C => "c",
+            Backend::Python => "python",
+            Backend::Java => "java",
+            Backend::Rust => "rust",
+            Backend::JavaScript => "node",
+            Backend::Wasm | Backend::Wat => "wat",
+            Backend::Llvm => "llvm",
+            Backend::Asm => "asm",
+            Backend::Go => "go",
+            Backend::Bf => "bf",
+        }
+    }
+
+    // the output of the program, `None` if there is no toolchain for the backend
+    fn run(self, insts: &Vec<Instruction>, opts: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        let mut output = Vec::new();
+        match self {
+            Backend::Interpreter => interpret::run(insts, opts, &mut &input[..], &mut output),
+            Backend::Jit => compile::compile_and_run(insts, opts, &mut &input[..], &mut output).unwrap(),
+            Backend::C => {
+                let dir = TempDir::new();
+                fs::write(dir.path("prog.c"), trans::c::transpile(opts, insts)).unwrap();
+                let compiled = Command::new("gcc")
+                    .arg("-O1").arg("-o").arg(dir.path("prog")).arg(dir.path("prog.c"))
+                    .status().ok()?;
+                assert!(compiled.success(), "generated C code does not compile");
+                output = run_command(Command::new(dir.path("prog")), input)?;
+            },
+            Backend::Python => {
+                let dir = TempDir::new();
+                fs::write(dir.path("prog.py"), trans::python::transpile(opts, insts)).unwrap();
+                let mut python = Command::new("python3");
+                python.arg(dir.path("prog.py"));
+                output = run_command(python, input)?;
+            },
+            Backend::Java => {
+                let dir = TempDir::new();
+                fs::write(dir.path("Brainfuck.java"), trans::java::transpile(opts, insts)).unwrap();
+                let mut java = Command::new("java");
+                java.arg(dir.path("Brainfuck.java"));
+                output = run_command(java, input)?;
+            },
+            Backend::Rust => {
+                let dir = TempDir::new();
+                fs::write(dir.path("prog.rs"), trans::rust::transpile(opts, insts)).unwrap();
+                // without optimizations, so overflows in the generated code panic
+                let compiled = Command::new("rustc")
+                    .arg("-o").arg(dir.path("prog")).arg(dir.path("prog.rs"))
+                    .status().ok()?;
+                assert!(compiled.success(), "generated rust code does not compile");
+                output = run_command(Command::new(dir.path("prog")), input)?;
+            },
+            Backend::JavaScript => {
+                let dir = TempDir::new();
+                fs::write(dir.path("prog.js"), trans::javascript::transpile_node(opts, insts)).unwrap();
+                let mut node = Command::new("node");
+                node.arg(dir.path("prog.js"));
+                output = run_command(node, input)?;
+            },
+            Backend::Wasm => output = run_wasm(&trans::wasm::assemble(opts, insts), input)?,
+            Backend::Wat => {
+                let module = wat::parse_str(trans::wasm::transpile(opts, insts)).expect("invalid wat");
+                output = run_wasm(&module, input)?;
+            },
+            Backend::Llvm => {
+                let dir = TempDir::new();
+                fs::write(dir.path("prog.ll"), trans::llvm::transpile(opts, insts)).unwrap();
+                let mut llc = Command::new("llc");
+                llc.arg("-O1").arg("-relocation-model=pic").arg("-filetype=obj")
+                    .arg("-o").arg(dir.path("prog.o")).arg(dir.path("prog.ll"));
+                if llvm_major_version()? < 15 {
+                    llc.arg("-opaque-pointers");
+                }
+                assert!(llc.status().ok()?.success(), "generated llvm ir does not compile");
+                let linked = Command::new("gcc")
+                    .arg("-o").arg(dir.path("prog")).arg(dir.path("prog.o"))
+                    .status().ok()?;
+                assert!(linked.success(), "compiled llvm ir does not link");
+                output = run_command(Command::new(dir.path("prog")), input)?;
+            },
+            Backend::Asm => {
+                let dir = TempDir::new();
+                fs::write(dir.path("prog.s"), trans::asm::transpile(opts, insts)).unwrap();
+                let assembled = Command::new("as")
+                    .arg("-o").arg(dir.path("prog.o")).arg(dir.path("prog.s"))
+                    .status().ok()?;
+                assert!(assembled.success(), "generated assembly does not assemble");
+                let linked = Command::new("ld")
+                    .arg("-o").arg(dir.path("prog")).arg(dir.path("prog.o"))
+                    .status().ok()?;
+                assert!(linked.success(), "assembled program does not link");
+                output = run_command(Command::new(dir.path("prog")), input)?;
+            },
+            Backend::Go => {
+                let dir = TempDir::new();
+                fs::write(dir.path("prog.go"), trans::go::transpile(opts, insts)).unwrap();
+                let mut go = Command::new("go");
+                go.arg("run").arg(dir.path("prog.go"));
+                output = run_command(go, input)?;
+            },
+            Backend::Bf => {
+                let code = trans::bf::transpile(opts, insts);
+                interpret::run(&compile(&code, opts), opts, &mut &input[..], &mut output);
+            },
+        }
+        Some(output)
+    }
+}
+
+fn run_command(mut command: Command, input: &[u8]) -> Option<Vec<u8>> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn().ok()?;
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", command, String::from_utf8_lossy(&output.stderr));
+    Some(output.stdout)
+}
+
+// the version of llc, `None` if it is not installed
+fn llvm_major_version() -> Option<u32> {
+    let output = Command::new("llc").arg("--version").output().ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let version = text.split("LLVM version ").nth(1)?;
+    version.split('.').next()?.trim().parse().ok()
+}
+
+// runs a module generated by the wasm backend in node
+fn run_wasm(module: &[u8], input: &[u8]) -> Option<Vec<u8>> {
+    let dir = TempDir::new();
+    fs::write(dir.path("prog.wasm"), module).unwrap();
+    fs::write(dir.path("run.js"), WASM_RUNNER).unwrap();
+    let mut node = Command::new("node");
+    node.arg(dir.path("run.js")).arg(dir.path("prog.wasm"));
+    run_command(node, input)
+}
+
+const WASM_RUNNER: &str = r#"
+const fs = require('fs');
+const input = fs.readFileSync(0);
+const output = [];
+let pos = 0;
+const wasm = new WebAssembly.Module(fs.readFileSync(process.argv[2]));
+const instance = new WebAssembly.Instance(wasm, { env: {
+    read: () => pos < input.length ? input[pos++] : -1,
+    write: b => output.push(b),
+    debug: () => {},
+}});
+instance.exports.run();
+process.stdout.write(Buffer.from(output));
+"#;
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("zombie-differential-{}-{}",
+            std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn compile(code: &str, opts: &Options) -> Vec<Instruction> {
+    optimize::lin_optimize(parser::parse(code, opts).unwrap(), opts)
+}
+
+fn with_cell_size(cell_size: CellSize) -> Options {
+    Options {
+        cell_size,
+        ..Options::default()
+    }
+}
+
+const LAYOUTS: &[CellLayout] = &[CellLayout::Trusting, CellLayout::Wrapping, CellLayout::Unbounded];
+
+/// The options with each layout, on the default tape and on one just as large
+/// as the cells the program can reach, where they are bounded. The moves the
+/// optimizer drops at the end still have to stay on the tape.
+fn tape_variants(code: &str, opts: &Options) -> Vec<Options> {
+    let ranges = PointerRanges::analyze(&parser::parse(code, opts).unwrap());
+    let mut variants = Vec::new();
+    for cell_layout in LAYOUTS {
+        for &memory_size in &[opts.memory_size, ranges.memory_size(cell_layout).unwrap_or(opts.memory_size)] {
+            let variant = Options { cell_layout: cell_layout.clone(), memory_size, ..opts.clone() };
+            if !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        }
+    }
+    variants
+}
+
+// tells about a backend without a toolchain once, outside of the output
+// the test harness captures
+fn warn_skipped(backend: Backend) {
+    static SKIPPED: Mutex<Vec<Backend>> = Mutex::new(Vec::new());
+    let mut skipped = SKIPPED.lock().unwrap();
+    if !skipped.contains(&backend) {
+        skipped.push(backend);
+        let _ = writeln!(std::io::stderr(), "warning: skipping {:?}, no toolchain found", backend);
+    }
+}
+
+// runs the program with all backends supporting it and compares the outputs
+fn check_program(name: &str, code: &str, input: &[u8], opts: &Options, backends: &[Backend]) {
+    let insts = compile(code, opts);
+    let supported: Vec<Backend> = backends.iter().copied().filter(|backend| backend.supports(opts)).collect();
+    assert!(supported.len() >= 2, "{}: only {:?} of {:?} support {:?}, there is nothing to compare",
+            name, supported, backends, opts);
+    let mut reference: Option<(Backend, Vec<u8>)> = None;
+    let mut compared = 0;
+    for backend in supported {
+        let output = match backend.run(&insts, opts, input) {
+            Some(output) => output,
+            None => {
+                warn_skipped(backend);
+                continue;
+            }
+        };
+        compared += 1;
+        match &reference {
+            Some((ref_backend, ref_output)) => {
+                if output != *ref_output {
+                    let first_diff = output.iter().zip(ref_output.iter())
+                        .position(|(a, b)| a != b)
+                        .unwrap_or_else(|| output.len().min(ref_output.len()));
+                    panic!("{}: {:?} and {:?} disagree at byte {} (lengths {} and {}) with {:?}",
+                           name, backend, ref_backend, first_diff, output.len(), ref_output.len(), opts);
+                }
+            },
+            None => reference = Some((backend, output)),
+        }
+    }
+    if compared < 2 {
+        let _ = writeln!(std::io::stderr(), "warning: {}: no two backends ran with {:?}", name, opts);
+    }
+}
+
+fn check_examples(examples: &[Example], backends: &[Backend]) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    for example in examples {
+        let code = fs::read_to_string(dir.join(example.file)).unwrap();
+        for cell_size in &[CellSize::Bits(8), CellSize::Bits(16)] {
+            let opts = Options {
+                eof_mode: example.eof_mode,
+                ..with_cell_size(cell_size.clone())
+            };
+            for opts in tape_variants(&code, &opts) {
+                check_program(example.file, &code, example.input, &opts, backends);
+            }
+        }
+    }
+}
+
+#[test]
+fn examples_agree() {
+    check_examples(EXAMPLES, &[Backend::Interpreter, Backend::Jit, Backend::C, Backend::Python, Backend::Rust,
+                                   Backend::JavaScript, Backend::Wasm, Backend::Wat, Backend::Llvm, Backend::Asm,
+                                   Backend::Go, Backend::Bf, Backend::Java]);
+}
+
+#[test]
+#[ignore]
+fn slow_examples_agree() {
+    check_examples(SLOW_EXAMPLES, &[Backend::Interpreter, Backend::Jit, Backend::C]);
+}
+
+
+fn check_random_programs(count: u64, backends: &[Backend]) {
+    let cell_sizes = [CellSize::Bits(8), CellSize::Bits(16), CellSize::Bits(32), CellSize::Modular(251)];
+    for seed in 0..count {
+        let code = generate::program(&mut generate::Xorshift::new(seed), &generate::GenOptions::default());
+        for cell_size in &cell_sizes {
+            for opts in tape_variants(&code, &with_cell_size(cell_size.clone())) {
+                // the interpreter and the JIT only share some of them
+                if backends.iter().filter(|backend| backend.supports(&opts)).count() < 2 {
+                    continue;
+                }
+                check_program(&format!("random program {} ({})", seed, code), &code, b"", &opts, backends);
+            }
+        }
+    }
+}
+
+#[test]
+fn random_programs_agree() {
+    check_random_programs(200, &[Backend::Interpreter, Backend::Jit]);
+}
+
+#[test]
+fn random_programs_agree_transpiled() {
+    check_random_programs(10, BACKENDS);
+}
+
+#[test]
+fn c_layouts_agree() {
+    // the generated programs stay in the first cells, a tape this small
+    // makes the unbounded one grow
+    let layouts = [(CellLayout::Trusting, 0x100), (CellLayout::Wrapping, 0x100), (CellLayout::Unbounded, 4)];
+    let mut programs: Vec<String> = (0..10)
+        .map(|seed| generate::program(&mut generate::Xorshift::new(seed), &generate::GenOptions::default()))
+        .collect();
+    // moves left of the starting cell
+    programs.push("+++++[<<<<<<<<<<+++++++++++++>>>>>>>>>>-]<<<<<<<<<<.".to_string());
+    for code in &programs {
+        let opts = Options::default();
+        let expected = Backend::Interpreter.run(&compile(code, &opts), &opts, b"").unwrap();
+        for (layout, memory_size) in &layouts {
+            let opts = Options {
+                cell_layout: layout.clone(),
+                memory_size: *memory_size,
+                ..Options::default()
+            };
+            if let Some(output) = Backend::C.run(&compile(code, &opts), &opts, b"") {
+                assert!(output == expected, "C and the interpreter disagree on {} with a {:?} tape", code, layout);
+            }
+        }
+    }
+}
+
+#[test]
+fn c_trusting_tape_is_checked() {
+    // the checks stay in release builds, `[>]` searches up to the end of
+    // the tape only
+    let opts = Options { memory_size: 16, ..Options::default() };
+    for (code, leaves) in &[("+>+>+>>+<<<<[>]<+++++.", false), ("+[>+]", true), ("+>+>+>+>+>+>+>+[>]", true)] {
+        let dir = TempDir::new();
+        fs::write(dir.path("prog.c"), trans::c::transpile(&opts, &compile(code, &opts))).unwrap();
+        let compiled = match Command::new("gcc").arg("-O1").arg("-DNDEBUG")
+                .arg("-o").arg(dir.path("prog")).arg(dir.path("prog.c")).status() {
+            Ok(status) => status,
+            Err(_) => return,
+        };
+        assert!(compiled.success(), "generated C code does not compile");
+        let status = Command::new(dir.path("prog")).stdout(Stdio::null()).status().unwrap();
+        assert_eq!(status.success(), !leaves, "{}", code);
+    }
+}
+
+#[test]
+fn jit_rejects_other_cell_sizes() {
+    // the generated code only adds and stores bytes, it would silently run
+    // these as 8 bit cells
+    for cell_size in &[CellSize::Bits(16), CellSize::Bits(32), CellSize::Modular(251), CellSize::Int] {
+        let opts = with_cell_size(cell_size.clone());
+        assert_eq!(compile::supports(&opts), Err(BackendError::UnsupportedCellSize(cell_size.clone())));
+        assert!(!Backend::Jit.supports(&opts));
+    }
+    assert_eq!(compile::supports(&with_cell_size(CellSize::Bits(8))), Ok(()));
+}
+
+#[test]
+fn jit_keeps_programs_on_the_tape() {
+    // the analysis places the cells the program reaches on the tape, left
+    // of the starting cell here
+    let code = "<<++[>+++<-]>.";
+    let opts = Options { memory_size: 3, ..Options::default() };
+    let mut output = Vec::new();
+    assert_eq!(compile::compile_and_run(&compile(code, &opts), &opts, &mut &b""[..], &mut output), Ok(()));
+    assert_eq!(output, b"\x06");
+    assert_eq!(compile::tape_checks(&compile(code, &opts), &opts), compile::TapeChecks::Trusting);
+    let small = Options { memory_size: 2, ..opts.clone() };
+    assert_eq!(compile::tape_checks(&compile(code, &opts), &small), compile::TapeChecks::Checked);
+
+    // the pointer is checked, the cells at an offset from it may be up to
+    // the largest offset of the program beyond the tape
+    let opts = Options { memory_size: 16, ..Options::default() };
+    for code in &["+[>+]", "+[<+]", ">>>>>>>>>>>>>>>>+[.-]", "+[>+>>>+<<<]"] {
+        assert_eq!(compile::tape_checks(&compile(code, &opts), &opts), compile::TapeChecks::Checked, "{}", code);
+        let result = compile::compile_and_run(&compile(code, &opts), &opts, &mut &b""[..], &mut Vec::new());
+        assert_eq!(result, Err(compile::OutOfBounds), "{}", code);
+    }
+
+    // there is no starting cell on an empty tape
+    let opts = Options { memory_size: 0, ..Options::default() };
+    let result = compile::compile_and_run(&compile("+.", &opts), &opts, &mut &b""[..], &mut Vec::new());
+    assert_eq!(result, Err(compile::OutOfBounds));
+    assert_eq!(compile::supports(&opts), Err(BackendError::UnsupportedMemorySize(0)));
+
+    // the pointer is not wrapped around or the tape grown
+    for layout in &[CellLayout::Wrapping, CellLayout::Unbounded] {
+        let opts = Options { cell_layout: layout.clone(), ..Options::default() };
+        assert_eq!(compile::supports(&opts), Err(BackendError::UnsupportedCellLayout(layout.clone())));
+    }
+}
+
+#[test]
+fn auto_memory_size_agrees() {
+    // `--memory-size auto` fits the tape to the cells the program reaches,
+    // these move left of the starting cell and give odd tape widths
+    let programs = ["+++++<+>.", "+++<<+++++[>+++<-]>>.<.<.", "++[<+++>-]<<+>>>+<<[.-]"];
+    for code in &programs {
+        let opts = Options::default();
+        let expected = Backend::Interpreter.run(&compile(code, &opts), &opts, b"").unwrap();
+        for layout in &[CellLayout::Trusting, CellLayout::Wrapping, CellLayout::Unbounded] {
+            let insts = compile(code, &opts);
+            let opts = Options {
+                cell_layout: layout.clone(),
+                memory_size: PointerRanges::analyze(&insts).memory_size(layout).unwrap(),
+                ..Options::default()
+            };
+            for backend in [Backend::Interpreter, Backend::Jit, Backend::C].iter().filter(|backend| backend.supports(&opts)) {
+                if let Some(output) = backend.run(&insts, &opts, b"") {
+                    assert!(output == expected, "{:?} disagrees on {} with an auto sized {:?} tape", backend, code, layout);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn deeply_nested_loops_agree() {
+    // more nested blocks than CPython allows in one function
+    let code = format!("+{}[-]{}+++.", "[>+".repeat(30), "<-]".repeat(30));
+    check_program("deeply nested loops", &code, b"", &Options::default(), &[Backend::Interpreter, Backend::Python, Backend::Rust]);
+}
+
+#[test]
+fn large_modulus_agrees() {
+    // the interpreter overflows with moduli this large, but javascript
+    // multiplies them as BigInts, webassembly in a helper function, llvm
+    // in i128 and java with BigInteger
+    let opts = with_cell_size(CellSize::Modular(1_000_000_000_039));
+    for seed in 0..20 {
+        let code = generate::program(&mut generate::Xorshift::new(seed), &generate::GenOptions::default());
+        check_program(&format!("random program {} ({})", seed, code), &code, b"", &opts,
+                      &[Backend::JavaScript, Backend::Wasm, Backend::Wat, Backend::Llvm, Backend::Java, Backend::Rust]);
+    }
+
+    // above i64::MAX, where the cells only fit into unsigned 64 bits
+    let opts = with_cell_size(CellSize::Modular(18_446_744_073_709_551_557));
+    for seed in 0..10 {
+        let code = generate::program(&mut generate::Xorshift::new(seed), &generate::GenOptions::default());
+        check_program(&format!("random program {} ({})", seed, code), &code, b"", &opts,
+                      &[Backend::Wasm, Backend::Wat, Backend::Llvm, Backend::Rust]);
+    }
+}
+
+#[test]
+fn optimized_programs_agree() {
+    let gen_opts = generate::GenOptions {
+        reads: true,
+        ..generate::GenOptions::default()
+    };
+    let input: Vec<u8> = (0..=255).rev().collect();
+    for seed in 0..200 {
+        let code = generate::program(&mut generate::Xorshift::new(seed), &gen_opts);
+        for cell_size in [CellSize::Bits(8), CellSize::Bits(16), CellSize::Modular(251)] {
+            let opts = with_cell_size(cell_size);
+            let insts = parser::parse(&code, &opts).unwrap();
+            let mut output = Vec::new();
+            let tape = interpret::run_to_tape(&insts, &opts, &mut &input[..], &mut output);
+            let mut opt_output = Vec::new();
+            let opt_tape = interpret::run_to_tape(&compile(&code, &opts), &opts, &mut &input[..], &mut opt_output);
+            assert!(output == opt_output && tape == opt_tape, "optimizing changes the behaviour of {}", code);
+        }
+    }
+}
+
+#[test]
+fn debug_instructions_agree() {
+    // the dumps go to stderr, this checks that every backend generates code
+    // for them around the usual output
+    let opts = Options { debug_instruction: true, ..Options::default() };
+    let code = "#++++++++[>++++++++<-]#>+.<#<+#[-]>>#.";
+    for cell_size in &[CellSize::Bits(8), CellSize::Bits(16), CellSize::Bits(32), CellSize::Bits(64), CellSize::Modular(251)] {
+        let opts = Options { cell_size: cell_size.clone(), ..opts.clone() };
+        check_program(&format!("debug program ({:?})", cell_size), code, b"", &opts, BACKENDS);
+    }
+}
+
+#[test]
+fn loops_without_code_agree() {
+    // moves around the whole tape generate no code in some backends, the
+    // loops still need a body
+    for &(code, memory_size) in &[("[>>>>]+++.", 4), ("+[-[>>>>]]++.", 4), ("[>]+.", 1), ("+[-[<]]+.", 1), ("+[-[]][<>]+.", 4)] {
+        let opts = Options { memory_size, ..Options::default() };
+        check_program(&format!("loop without code ({} cells)", memory_size), code, b"", &opts, BACKENDS);
+    }
+}
+
+#[test]
+fn minified_programs_agree() {
+    let gen_opts = generate::GenOptions {
+        reads: true,
+        ..generate::GenOptions::default()
+    };
+    let input: Vec<u8> = (0..=255).rev().collect();
+    for seed in 0..200 {
+        let code = generate::program(&mut generate::Xorshift::new(seed), &gen_opts);
+        for cell_size in [CellSize::Bits(8), CellSize::Bits(16), CellSize::Modular(251)] {
+            let opts = with_cell_size(cell_size);
+            let minified = trans::bf::transpile(&opts, &compile(&code, &opts));
+            assert!(minified.len() <= code.len(), "minifying makes {} longer: {}", code, minified);
+            let mut output = Vec::new();
+            let tape = interpret::run_to_tape(&parser::parse(&code, &opts).unwrap(), &opts, &mut &input[..], &mut output);
+            let mut min_output = Vec::new();
+            let min_tape = interpret::run_to_tape(&parser::parse(&minified, &opts).unwrap(), &opts, &mut &input[..], &mut min_output);
+            assert!(output == min_output && tape == min_tape, "minifying changes the behaviour of {}: {}", code, minified);
+        }
+    }
+}
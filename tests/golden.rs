@@ -0,0 +1,14 @@
+// Runs the programs in `examples/` against their expected output.
+
+use std::path::Path;
+
+use zombie::golden::{self, Backend};
+use zombie::options::Options;
+
+#[test]
+fn examples_match_golden_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let summary = golden::run(&dir, Backend::Jit, &Options::default()).unwrap();
+    assert!(summary.passed > 0, "no golden tests found");
+    assert_eq!(summary.failed, 0);
+}
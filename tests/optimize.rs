@@ -0,0 +1,195 @@
+// Runs the optimizer on the IR fixtures in `tests/ir/`, each of them is the
+// input and the expected output separated by a line `---`.
+
+use std::fs;
+use std::path::Path;
+
+use zombie::analysis::{Bounds, KnownCells, PointerRanges};
+use zombie::ir::{self, Invariant, MutVisitor};
+use zombie::optimize::{self, LinOptimizer};
+use zombie::options::{CellLayout, CellSize, Options};
+use zombie::parser;
+use zombie::trans::zombie_ir;
+
+#[test]
+fn optimizer_matches_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/ir");
+    let mut fixtures = 0;
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("zir".as_ref()) {
+            continue;
+        }
+        let text = fs::read_to_string(&path).unwrap();
+        let (input, expected) = text.split_once("\n---\n")
+            .unwrap_or_else(|| panic!("{}: no '---' line", path.display()));
+
+        let mut insts = zombie_ir::parse(input).unwrap();
+        let mut optimizer = LinOptimizer::new();
+        optimizer.visit_instructions(&mut insts);
+        let expected = zombie_ir::parse(expected).unwrap();
+        assert_eq!(zombie_ir::transpile(&optimizer.instructions), zombie_ir::transpile(&expected), "{}", path.display());
+        fixtures += 1;
+    }
+    assert!(fixtures > 0, "no fixtures found");
+}
+
+#[test]
+fn examples_round_trip() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    for name in &["hello_world.bf", "mandel.bf", "wc.bf", "factor.bf"] {
+        let code = fs::read_to_string(dir.join(name)).unwrap();
+        let mut insts = parser::parse(&code, &Options::default()).unwrap();
+        let mut optimizer = LinOptimizer::new();
+        optimizer.visit_instructions(&mut insts);
+
+        let text = zombie_ir::transpile(&optimizer.instructions);
+        assert_eq!(zombie_ir::parse(&text).unwrap(), optimizer.instructions, "{}", name);
+    }
+}
+
+#[test]
+fn errors_name_the_line() {
+    let error = zombie_ir::parse("@0 += 1\nLoop {\n    @1 += x\n}\n").unwrap_err();
+    assert_eq!(error.line, 3);
+    let error = zombie_ir::parse("Loop {\n    Write(@0)\n").unwrap_err();
+    assert_eq!(error.line, 1);
+    let error = zombie_ir::parse("LinearLoop(@0) {\n    @1 += 2 * @1\n}\n").unwrap_err();
+    assert_eq!(error.line, 2);
+    assert!(zombie_ir::parse("}").is_err());
+}
+
+#[test]
+fn verified_ir_names_the_line() {
+    let opts = Options::default();
+    let error = zombie_ir::parse_verified("@0 += 1\nLoop {\n    @1 += 200\n}\n", &opts).unwrap_err();
+    assert_eq!((error.line, error.message.as_str()), (3, "added value not normalized to the cell size in `@1 += 200`"));
+    let error = zombie_ir::parse_verified("Write(@0)\nLinearLoop(@0) {\n    @5000000000 += 1 * @0\n}\n", &opts).unwrap_err();
+    assert_eq!(error.line, 2);
+    let error = zombie_ir::parse_verified("Loop 0..2 {\n    Loop {\n    }\n}\n", &opts).unwrap_err();
+    assert_eq!(error.line, 2);
+    let error = zombie_ir::parse_verified("@0 += -56\nLoop 0..2 {\n    Loop 1..1 {\n    }\n}\n", &opts).unwrap_err();
+    assert_eq!(error.line, 3);
+    assert!(zombie_ir::parse_verified("@0 += -56\nLoop 0..2 {\n    Loop 1..1 {\n        ptr += 0\n    }\n}\n", &opts).is_ok());
+
+    // the offsets are checked against the tape
+    let small = Options { memory_size: 16, ..Options::default() };
+    let error = zombie_ir::parse_verified("Write(@3)\nptr += -17\n", &small).unwrap_err();
+    assert_eq!((error.line, error.message.as_str()), (2, "offset out of range in `ptr += -17`"));
+    for layout in &[CellLayout::Wrapping, CellLayout::Unbounded] {
+        let opts = Options { cell_layout: layout.clone(), ..small.clone() };
+        assert!(zombie_ir::parse_verified("Write(@3)\nptr += -17\n", &opts).is_ok());
+    }
+}
+
+#[test]
+fn instructions_display_as_ir() {
+    let insts = zombie_ir::parse("@-1 += 3\nWrite(@2)\nLinearLoop(@0) 4..9 {\n    @1 += 2 * @0\n    @3 += -1 * @0\n}\n").unwrap();
+    assert_eq!(insts[0].to_string(), "@-1 += 3");
+    assert_eq!(insts[1].to_string(), "Write(@2)");
+    assert_eq!(insts[2].to_string(), "LinearLoop(@0) 4..9 {\n    @1 += 2 * @0\n    @3 += -1 * @0\n}");
+}
+
+#[test]
+fn printed_ir_shows_known_cells() {
+    let opts = Options::default();
+    let mut insts = parser::parse("+++>++[->+++<]<.,>>.[-]", &opts).unwrap();
+    let mut optimizer = LinOptimizer::new();
+    optimizer.visit_instructions(&mut insts);
+
+    let known = KnownCells::analyze(&optimizer.instructions, &opts);
+    let text = ir::print(&optimizer.instructions, true, &known);
+    assert!(text.contains("LinearLoop(@1) 6..14 {  // @1 = 2, @2 = 0\n    @2 += 3 * @1\n}"), "{}", text);
+    assert!(text.contains("Write(@0)  // @0 = 3"), "{}", text);
+    assert!(text.contains("Write(@2)  // @2 = 6"), "{}", text);
+    assert_eq!(zombie_ir::parse(&text).unwrap(), optimizer.instructions);
+}
+
+#[test]
+fn optimized_examples_verify() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    for cell_size in &[CellSize::Bits(8), CellSize::Bits(1), CellSize::Modular(251), CellSize::Int] {
+        let opts = Options { cell_size: cell_size.clone(), ..Options::default() };
+        for name in &["hello_world.bf", "mandel.bf", "wc.bf", "factor.bf"] {
+            let code = fs::read_to_string(dir.join(name)).unwrap();
+            let insts = parser::parse(&code, &opts).unwrap();
+            assert_eq!(ir::verify(&insts, &opts), Ok(()), "{}", name);
+            let insts = optimize::lin_optimize(insts, &opts);
+            assert_eq!(ir::verify(&insts, &opts), Ok(()), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn parsed_adds_are_normalized() {
+    let opts = Options::default();
+    let code = format!("+-{}>{}", "+".repeat(300), "-".repeat(129));
+    assert_eq!(ir::print(&parser::parse(&code, &opts).unwrap(), false, &()), "@0 += 44\n@1 += 127\nptr += 1\n");
+    assert_eq!(ir::normalize(-1, &CellSize::Bits(8)), -1);
+    assert_eq!(ir::normalize(128, &CellSize::Bits(8)), -128);
+    assert_eq!(ir::normalize(-1, &CellSize::Bits(1)), -1);
+    assert_eq!(ir::normalize(7, &CellSize::Modular(5)), 2);
+    assert_eq!(ir::normalize(7, &CellSize::Modular(0)), 7);
+    assert_eq!(ir::normalize(-300, &CellSize::Int), -300);
+}
+
+#[test]
+fn verify_names_the_invariant() {
+    let opts = Options::default();
+    let broken = [
+        ("Nop", Invariant::NoNop, "Nop"),
+        ("@2 += 0", Invariant::NonZeroAdd, "@2 += 0"),
+        ("@2 += 200", Invariant::NormalizedAdd, "@2 += 200"),
+        ("LinearLoop(@1) {\n    @1 += 1 * @1\n}", Invariant::NoSelfFactor, "LinearLoop(@1)"),
+        ("Loop 0..4 {\n    Loop {\n    }\n}", Invariant::NonEmptyLoop, "Loop"),
+        ("Loop 0..2 {\n}", Invariant::NonEmptyLoop, "Loop 0..2"),
+        ("Write(@3000000000)", Invariant::OffsetInRange, "Write(@3000000000)"),
+        ("ptr += 65537", Invariant::OffsetInRange, "ptr += 65537"),
+    ];
+    for (text, invariant, instruction) in broken.iter() {
+        let violation = ir::verify(&zombie_ir::parse(text).unwrap(), &opts).unwrap_err();
+        assert_eq!(violation, ir::Violation{ invariant: *invariant, instruction: instruction.to_string() }, "{}", text);
+    }
+    // loops doing nothing keep a body
+    for code in &["+[]", "+[<>]"] {
+        let insts = optimize::lin_optimize(parser::parse(code, &opts).unwrap(), &opts);
+        assert_eq!(ir::print(&insts, false, &()), "@0 += 1\nLoop {\n    ptr += 0\n}\n", "{}", code);
+    }
+}
+
+#[test]
+#[should_panic(expected = "the linear loop pass broke an invariant: adds 0 in `@1 += 0`")]
+fn debug_builds_check_the_passes() {
+    optimize::lin_optimize(zombie_ir::parse("@1 += 0").unwrap(), &Options::default());
+}
+
+#[test]
+fn pointer_ranges_bound_the_cells() {
+    let opts = Options::default();
+    let insts = optimize::lin_optimize(parser::parse("++[>+++<-]<+>>.[>>[-]<<-]", &opts).unwrap(), &opts);
+    let ranges = PointerRanges::analyze(&insts);
+    assert_eq!(ranges.cells(), Bounds{ min: Some(-1), max: Some(3) });
+    assert_eq!(ranges.cells().to_string(), "-1..=3");
+    assert_eq!(ranges.memory_size(&CellLayout::Wrapping), Some(5));
+    assert_eq!(ranges.memory_size(&CellLayout::Trusting), Some(7));
+    assert_eq!(ranges.pointer(insts.last().unwrap()), Some(Bounds::at(1)));
+    assert!(!ranges.is_unbounded(insts.last().unwrap()));
+}
+
+#[test]
+fn moving_loops_are_unbounded() {
+    let opts = Options::default();
+    let insts = optimize::lin_optimize(parser::parse("+[>]<<+", &opts).unwrap(), &opts);
+    let ranges = PointerRanges::analyze(&insts);
+    assert!(ranges.is_unbounded(&insts[1]));
+    assert_eq!(ranges.pointer(&insts[2]), Some(Bounds{ min: Some(0), max: None }));
+    assert_eq!(ranges.cells().to_string(), "-2..");
+    assert_eq!(ranges.memory_size(&CellLayout::Wrapping), None);
+
+    // the inner loop can move the outer one anywhere
+    let insts = optimize::lin_optimize(parser::parse("+[[>]<]", &opts).unwrap(), &opts);
+    let ranges = PointerRanges::analyze(&insts);
+    assert!(ranges.is_unbounded(&insts[1]));
+    assert_eq!(ranges.cells(), Bounds{ min: None, max: None });
+    assert_eq!(ranges.cells().to_string(), "..");
+}